@@ -1,10 +1,28 @@
 use structopt::StructOpt;
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use std::fs::OpenOptions;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
 use tn3270s::tn3270;
-use tn3270s::tn3270::stream::WriteOrder::SetBufferAddress;
-use tn3270s::tn3270::screen::{Screen, Field, Address, FieldData};
+use tn3270s::tn3270::idle::{IdlePolicy, IdleWarning};
+use tn3270s::tn3270::keepalive::{Keepalive, KeepaliveProbe};
+use tn3270s::tn3270::listener::{Connection, Listener};
+use tn3270s::tn3270::lu::{LuPool, MemoryLuPool};
+use tn3270s::tn3270::negotiation::NegotiationPolicy;
+use tn3270s::tn3270::screen::{Screen, Field};
 use tn3270s::tn3270::stream::{ExtendedFieldAttribute, FieldAttribute};
+use tn3270s::tn3270::trace::TraceRecorder;
+use tn3270s::tn3270::transport::Transport;
+#[cfg(feature = "tls")]
+use tn3270s::tn3270::proxy::ProxyHeader;
+#[cfg(feature = "tls")]
+use tn3270s::tn3270::tls::{PeerCertificate, StartTlsStream, SubjectAltName, TlsAcceptor};
+#[cfg(feature = "tls")]
+use tn3270s::tn3270::transport::StartTlsPolicy;
 
 #[derive(StructOpt)]
 pub struct Cli {
@@ -12,7 +30,50 @@ pub struct Cli {
     host: String,
     #[structopt(short="p", long = "port", default_value="2101")]
     port: u16,
-
+    /// Listen on a Unix domain socket instead of TCP
+    #[cfg(unix)]
+    #[structopt(long = "unix", conflicts_with_all = &["systemd", "stdio"])]
+    unix: Option<std::path::PathBuf>,
+    /// Serve the sockets passed by systemd socket activation instead of listening
+    #[cfg(unix)]
+    #[structopt(long = "systemd", conflicts_with = "stdio")]
+    systemd: bool,
+    /// Serve the one connection on stdin and stdout, as under inetd
+    #[structopt(long = "stdio")]
+    stdio: bool,
+    /// Seconds a terminal can be quiet before it's checked on with TIMING-MARK
+    #[structopt(long = "keepalive", default_value="60")]
+    keepalive: u64,
+    /// Seconds without a keypress before a terminal is logged off, with a warning shortly
+    /// before
+    #[structopt(long = "idle-timeout")]
+    idle_timeout: Option<u64>,
+    /// File to append the log to, rather than stderr. Without it, `--stdio` logs nothing,
+    /// since inetd usually passes the connection as stderr as well.
+    #[structopt(long = "log-file")]
+    log_file: Option<std::path::PathBuf>,
+    /// Directory to write an x3270-style trace of each session to
+    #[structopt(long = "trace-dir")]
+    trace_dir: Option<std::path::PathBuf>,
+    /// Expect a PROXY protocol header from a load balancer on each connection
+    #[structopt(long = "proxy-protocol")]
+    proxy_protocol: bool,
+    /// PEM certificate chain; serve TLS instead of plain telnet
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls-cert", requires = "tls-key")]
+    tls_cert: Option<std::path::PathBuf>,
+    /// PEM private key for --tls-cert
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls-key", requires = "tls-cert")]
+    tls_key: Option<std::path::PathBuf>,
+    /// PEM CA certificates that client certificates are checked against
+    #[cfg(feature = "tls")]
+    #[structopt(long = "tls-client-ca", requires = "tls-cert")]
+    tls_client_ca: Option<std::path::PathBuf>,
+    /// Offer TLS through telnet START_TLS rather than starting with it
+    #[cfg(feature = "tls")]
+    #[structopt(long = "start-tls", requires = "tls-cert", possible_values = &["allow", "require"])]
+    start_tls: Option<String>,
 }
 
 //      _~^~^~_
@@ -21,16 +82,16 @@ pub struct Cli {
 //    / '-----' \
 //  1234567890123456
 
-static rust_logo: [&'static str; 4] = [
+static RUST_LOGO: [&str; 4] = [
   r#"     _~^~^~_     "#,
   r#" \) /  o o  \ (/ "#,
   r#"   '_   ¬   _'   "#,
   r#"   / '-----' \   "#,
 ];
 
-fn intro_screen(session: &mut tn3270::Session) -> anyhow::Result<()> {
+fn intro_screen<T: Transport>(session: &mut tn3270::Session<T>) -> anyhow::Result<()> {
     use tn3270::stream::*;
-    let bufsz = BufferAddressCalculator { width: 80, height: 24 };
+    let bufsz = session.terminal_model().default_size;
     let mut record = WriteCommand {
         command: WriteCommandCode::Write,
         wcc: WCC::RESET | WCC::KBD_RESTORE | WCC::RESET_MDT,
@@ -56,7 +117,7 @@ fn intro_screen(session: &mut tn3270::Session) -> anyhow::Result<()> {
         ],
     };
 
-    for (i, line) in rust_logo.iter().enumerate() {
+    for (i, line) in RUST_LOGO.iter().enumerate() {
         record.orders.push(WriteOrder::SetBufferAddress(bufsz.encode_address(3+i as u16, 31)));
         record.orders.push(WriteOrder::StartFieldExtended(vec![
             ExtendedFieldAttribute::FieldAttribute(FieldAttribute::PROTECTED),
@@ -64,6 +125,7 @@ fn intro_screen(session: &mut tn3270::Session) -> anyhow::Result<()> {
         ]));
         record.orders.push(WriteOrder::SendText((*line).into()));
     }
+    record.check_addresses(bufsz)?;
     session.send_record(&record)?;
     session.send_record(&WriteCommand{
         command: WriteCommandCode::Write,
@@ -73,17 +135,18 @@ fn intro_screen(session: &mut tn3270::Session) -> anyhow::Result<()> {
 
     let record = session.receive_record(None)?;
     if let Some(record) = record {
-        eprintln!("Incoming record: {:?}", hex::encode(&record));
-        eprintln!("Decoded: {:#?}", IncomingRecord::parse_record(record.as_slice()))
+        info!("Incoming record: {:?}", hex::encode(&record.data));
+        info!("Decoded: {:#?}", IncomingRecord::parse_record(record.data.as_slice()))
     } else {
-        eprintln!("No record");
+        info!("No record");
     }
     Ok(())
 }
 
 
-fn hlapi_demo(session: &mut tn3270::Session) -> anyhow::Result<()> {
-    let mut name = "        ".to_string();
+fn hlapi_demo<T: Transport>(session: &mut tn3270::Session<T>) -> anyhow::Result<()> {
+    // Emulators that support NEW-ENVIRON tell us who's signing on
+    let mut name = format!("{:8}", session.environment().user().unwrap_or(""));
     let mut passwd = "        ".to_string();
 
     let result = Screen {
@@ -114,36 +177,239 @@ fn hlapi_demo(session: &mut tn3270::Session) -> anyhow::Result<()> {
     Ok(())
 }
 
-fn run(mut session: tn3270::Session) -> anyhow::Result<()> {
-    intro_screen(&mut session);
-    hlapi_demo(&mut session);
+fn run<T: Transport>(mut session: tn3270::Session<T>) -> anyhow::Result<()> {
+    intro_screen(&mut session)?;
+    hlapi_demo(&mut session)?;
 
     // std::thread::sleep(Duration::from_secs(50));
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let options: Cli = Cli::from_args();
-    let server = std::net::TcpListener::bind((options.host.as_str(), options.port))?;
+/// Sign in whoever's email address is on the certificate
+#[cfg(feature = "tls")]
+fn certificate_user(chain: &[PeerCertificate]) -> Option<String> {
+    chain[0].subject_alt_names.iter()
+        .find_map(|name| match name {
+            SubjectAltName::Email(address) => Some(address.clone()),
+            _ => None,
+        })
+}
+
+fn serve<T: Transport>(client: T, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy, keepalive: Keepalive, idle: Option<IdlePolicy>, trace: Option<TraceRecorder>) {
+    let negotiated = match trace {
+        Some(trace) => tn3270::Session::with_trace(client, lu_pool, policy, trace),
+        None => tn3270::Session::with_policy(client, lu_pool, policy),
+    };
+    let mut session = match negotiated {
+        Ok(session) => session,
+        Err(err) => {
+            warn!("Error negotiating session: {}", err);
+            return;
+        }
+    };
+
+    session.set_keepalive(Some(keepalive));
+    session.set_idle_timeout(idle);
+
+    if let (Some(peer), Some(_)) = (session.peer_addr(), session.proxy_header()) {
+        info!("Proxied connection from {}", peer);
+    }
+
+    if let Some(term_type) = session.term_type() {
+        info!("Terminal type {} (offered: {})", term_type, session.offered_term_types().join(", "));
+    }
+
+    #[cfg(feature = "tls")]
+    for cert in session.peer_certificates() {
+        let names: Vec<String> = cert.subject_alt_names.iter().map(ToString::to_string).collect();
+        info!("Client certificate: {} ({})", cert.subject, names.join(", "));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(user) = session.peer_identity() {
+        info!("Signed in as {}", user);
+    }
+
+    if let Err(err) = run(session) {
+        warn!("Error in session: {}", err);
+    }
+}
+
+/// Everything a connection needs, whichever listener it came from
+#[derive(Clone)]
+struct Server {
+    lu_pool: Arc<dyn LuPool>,
+    policy: NegotiationPolicy,
+    keepalive: Keepalive,
+    idle: Option<IdlePolicy>,
+    trace_dir: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    #[cfg(feature = "tls")]
+    start_tls: Option<StartTlsPolicy>,
+}
+
+/// Names trace files for connections without an address, such as Unix sockets
+static UNNAMED_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+impl Server {
+    fn handle(&self, client: Connection) {
+        let Server { lu_pool, policy, keepalive, idle, trace_dir, .. } = self.clone();
+        let trace = match trace_dir {
+            Some(dir) => {
+                let name = match client.peer_addr() {
+                    Some(peer) => format!("{}-{}.trc", peer.ip(), peer.port()),
+                    None => format!("session-{}.trc", UNNAMED_CONNECTIONS.fetch_add(1, Ordering::Relaxed)),
+                };
+                match TraceRecorder::create(dir.join(name)) {
+                    Ok(trace) => Some(trace),
+                    Err(err) => {
+                        warn!("Error creating trace: {}", err);
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "tls")]
+        if let (Some(tls), Some(start_tls)) = (self.tls.clone(), self.start_tls) {
+            serve(StartTlsStream::new(client, tls, start_tls), lu_pool, policy, keepalive, idle, trace);
+            return;
+        }
+        #[cfg(feature = "tls")]
+        if let Some(ref tls) = self.tls {
+            // The PROXY header comes ahead of the TLS handshake, so it's read here and
+            // handed to the session
+            let mut client = client;
+            let mut policy = policy;
+            if policy.proxy_protocol {
+                match ProxyHeader::read_until(&mut client, std::time::Instant::now() + policy.deadline) {
+                    Ok(header) => policy.proxy_header = Some(header),
+                    Err(err) => {
+                        warn!("Error reading PROXY header: {}", err);
+                        return;
+                    }
+                }
+            }
+            match tls.accept(client) {
+                Ok(client) => serve(client, lu_pool, policy, keepalive, idle, trace),
+                Err(err) => warn!("Error accepting session: {}", err),
+            }
+            return;
+        }
+
+        serve(client, lu_pool, policy, keepalive, idle, trace);
+    }
 
-    for client in server.incoming() {
-        let client = client?;
-        std::thread::spawn(move || {
-            let session = match tn3270::Session::new(client) {
-                Ok(session) => session,
+    /// Serve every connection from `listener`, each on a thread of its own
+    fn listen(&self, mut listener: Listener) {
+        for client in listener.incoming() {
+            // Running out of descriptors, or a client hanging up before it was accepted,
+            // only costs that one connection
+            let client = match client {
+                Ok(client) => client,
                 Err(err) => {
-                    eprintln!("Error accepting session: {}", err);
-                    return;
+                    warn!("Error accepting connection: {}", err);
+                    // Give descriptors a moment to be freed rather than spinning
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
                 }
             };
+            let server = self.clone();
+            std::thread::spawn(move || server.handle(client));
+        }
+    }
+}
 
-            if let Err(err) = run(session) {
-                eprintln!("Error in session: {}", err);
-            }
+fn main() -> anyhow::Result<()> {
+    let options: Cli = Cli::from_args();
+    // Session logging goes to the log file if there is one, and otherwise to stderr unless
+    // that's the connection; set RUST_LOG=tn3270s=trace to see every record
+    let log = match (&options.log_file, options.stdio) {
+        (Some(path), _) => BoxMakeWriter::new(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        // Under inetd, stderr is the terminal's connection
+        (None, true) => BoxMakeWriter::new(std::io::sink),
+        (None, false) => BoxMakeWriter::new(std::io::stderr),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_ansi(options.log_file.is_none())
+        .with_writer(log)
+        .init();
+    let lu_pool: Arc<dyn LuPool> = Arc::new(MemoryLuPool::new()
+        .with_terminals("TCP00###")?
+        .with_printers("PRT00###")?);
+
+    #[cfg(feature = "tls")]
+    let tls = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => Some(match options.tls_client_ca {
+            Some(ref ca) => TlsAcceptor::from_pem_files_with_client_auth(cert, key, ca, false)?
+                .with_identity_mapper(certificate_user),
+            None => TlsAcceptor::from_pem_files(cert, key)?,
+        }),
+        _ => None,
+    };
+    #[cfg(feature = "tls")]
+    let start_tls = match options.start_tls.as_deref() {
+        Some("require") => Some(StartTlsPolicy::Require),
+        Some(_) => Some(StartTlsPolicy::Allow),
+        None => None,
+    };
+
+    let server = Server {
+        lu_pool,
+        // Plain telnet clients get the same screens, drawn with ANSI escapes
+        policy: NegotiationPolicy {
+            nvt_fallback: true,
+            proxy_protocol: options.proxy_protocol,
+            ..NegotiationPolicy::default()
+        },
+        keepalive: Keepalive::new(Duration::from_secs(options.keepalive), KeepaliveProbe::TimingMark),
+        idle: options.idle_timeout.map(|timeout| {
+            let timeout = Duration::from_secs(timeout);
+            let lead = (timeout / 4).min(Duration::from_secs(60));
+            IdlePolicy::new(timeout)
+                .with_warning(IdleWarning::new(lead, format!("No activity; you will be logged off in {} seconds", lead.as_secs())))
+        }),
+        trace_dir: options.trace_dir.clone(),
+        #[cfg(feature = "tls")]
+        tls,
+        #[cfg(feature = "tls")]
+        start_tls,
+    };
+
+    // inetd's connection is served here, and the process exits when it's done
+    if options.stdio {
+        if let Some(client) = Listener::stdio().accept() {
+            server.handle(client?);
+        }
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    let listeners = if options.systemd {
+        let listeners = Listener::systemd()?;
+        if listeners.is_empty() {
+            anyhow::bail!("No sockets were passed by systemd");
+        }
+        listeners
+    } else if let Some(ref path) = options.unix {
+        vec![Listener::bind_unix(path)?]
+    } else {
+        vec![Listener::bind_tcp((options.host.as_str(), options.port))?]
+    };
+    #[cfg(not(unix))]
+    let listeners = vec![Listener::bind_tcp((options.host.as_str(), options.port))?];
 
-        });
+    let threads: Vec<_> = listeners.into_iter()
+        .map(|listener| {
+            let server = server.clone();
+            std::thread::spawn(move || server.listen(listener))
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
     }
-    println!("Hello, world!");
 
     Ok(())
 }
@@ -0,0 +1,81 @@
+use structopt::StructOpt;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+use std::sync::Arc;
+
+use tn3270s::tn3270::async_session::AsyncSession;
+use tn3270s::tn3270::lu::{LuPool, MemoryLuPool};
+use tn3270s::tn3270::negotiation::NegotiationPolicy;
+use tn3270s::tn3270::screen::{Screen, Field};
+use tn3270s::tn3270::stream::{ExtendedFieldAttribute, FieldAttribute};
+
+#[derive(StructOpt)]
+pub struct Cli {
+    #[structopt(short="h", long = "host", default_value="::1")]
+    host: String,
+    #[structopt(short="p", long = "port", default_value="2101")]
+    port: u16,
+}
+
+async fn run(mut session: AsyncSession) -> anyhow::Result<()> {
+    let mut name = "        ".to_string();
+    let mut passwd = "        ".to_string();
+
+    let result = Screen {
+        fields: vec![
+            Field::at(1, 32).ro_text("Please enter your data"),
+            Field::at(3, 10).ro_text("Name: "),
+            Field::at(3, 20).rw_text(&mut name),
+            Field::at(4, 10).ro_text("Password: "),
+            Field::at(4, 20).rw_text(&mut passwd)
+                .with_attr(ExtendedFieldAttribute::FieldAttribute(FieldAttribute::NON_DISPLAY)),
+        ],
+    }.present_async(&mut session).await?;
+
+    let aid = format!("{:?}", result.aid);
+    Screen {
+        fields: vec![
+            Field::at(1, 32).ro_text("Your data"),
+            Field::at(3, 10).ro_text("Name: "),
+            Field::at(3, 20).ro_text(name.as_str()),
+            Field::at(4, 10).ro_text("Password: "),
+            Field::at(4, 20).ro_text(passwd.as_str()),
+            Field::at(5, 10).ro_text("You pressed: "),
+            Field::at(5, 25).ro_text(aid.as_str()),
+            Field::at(23, 32).ro_text("Press ENTER to exit"),
+        ],
+    }.present_async(&mut session).await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(std::io::stderr)
+        .init();
+    let options: Cli = Cli::from_args();
+    let server = tokio::net::TcpListener::bind((options.host.as_str(), options.port)).await?;
+    let lu_pool: Arc<dyn LuPool> = Arc::new(MemoryLuPool::new()
+        .with_terminals("TCP00###")?
+        .with_printers("PRT00###")?);
+
+    loop {
+        let (client, peer) = server.accept().await?;
+        let lu_pool = lu_pool.clone();
+        tokio::spawn(async move {
+            let session = match AsyncSession::with_peer_addr(client, peer, lu_pool, NegotiationPolicy::default()).await {
+                Ok(session) => session,
+                Err(err) => {
+                    warn!("Error accepting session: {}", err);
+                    return;
+                }
+            };
+
+            if let Err(err) = run(session).await {
+                warn!("Error in session: {}", err);
+            }
+        });
+    }
+}
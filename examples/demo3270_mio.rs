@@ -0,0 +1,222 @@
+//! Many terminals served from a single thread, using a session's non-blocking mode with
+//! mio. Negotiation still blocks, so it's done on a thread of its own before the session
+//! is handed over to the event loop.
+
+// mio only exposes raw file descriptors as event sources on Unix
+#[cfg(unix)]
+mod demo {
+    use structopt::StructOpt;
+    use tracing::warn;
+    use tracing_subscriber::EnvFilter;
+    use std::collections::HashMap;
+    use std::net::TcpStream;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::{Duration, Instant};
+
+    use mio::{Events, Interest, Poll, Token, Waker};
+    use mio::unix::SourceFd;
+
+    use tn3270s::tn3270::{self, Event, Session};
+    use tn3270s::tn3270::keepalive::{Keepalive, KeepaliveProbe};
+    use tn3270s::tn3270::lu::{LuPool, MemoryLuPool};
+    use tn3270s::tn3270::negotiation::NegotiationPolicy;
+    use tn3270s::tn3270::stream::*;
+
+    #[derive(StructOpt)]
+    pub struct Cli {
+        #[structopt(short="h", long = "host", default_value="::1")]
+        host: String,
+        #[structopt(short="p", long = "port", default_value="2101")]
+        port: u16,
+    }
+
+    /// Wakes the event loop when a newly negotiated session is ready
+    const NEW_SESSION: Token = Token(0);
+
+    struct Terminal {
+        session: Session<TcpStream>,
+        presses: u32,
+    }
+
+    impl Terminal {
+        fn draw(&mut self, others: usize) -> std::io::Result<()> {
+            let size = self.session.terminal_model().default_size;
+            let lines = [
+                format!("Served from one thread, along with {} other terminal(s)", others),
+                format!("Keys pressed so far: {}", self.presses),
+                "Press any key, or PF3 to leave".to_owned(),
+            ];
+            let mut orders = vec![];
+            for (row, line) in lines.iter().enumerate() {
+                orders.push(WriteOrder::SetBufferAddress(size.encode_address(2 + row as u16 * 2, 10)));
+                orders.push(WriteOrder::StartField(FieldAttribute::PROTECTED));
+                orders.push(WriteOrder::SendText(line.clone()));
+            }
+            self.session.send_record(&WriteCommand {
+                command: WriteCommandCode::EraseWrite,
+                wcc: WCC::RESET | WCC::KBD_RESTORE,
+                orders,
+            })
+        }
+
+        /// Deal with everything the terminal has sent. Returns false once it's done with.
+        fn handle_input(&mut self, others: usize) -> std::io::Result<bool> {
+            while let Some(event) = self.session.receive_event(None)? {
+                let record = match event {
+                    Event::Record(record) => record,
+                    Event::Signal(_) => continue,
+                };
+                match IncomingRecord::parse_record(record.data.as_slice()) {
+                    Ok(IncomingRecord { aid: AID::PF3, .. }) => return Ok(false),
+                    Ok(_) => {
+                        self.presses += 1;
+                        self.draw(others)?;
+                    }
+                    Err(err) => warn!("Bad record: {}", err),
+                }
+            }
+            Ok(true)
+        }
+
+        fn interest(&self) -> Interest {
+            if self.session.wants_write() {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            }
+        }
+    }
+
+    fn event_loop(mut poll: Poll, new_sessions: Receiver<Session<TcpStream>>) -> anyhow::Result<()> {
+        let mut terminals: HashMap<Token, Terminal> = HashMap::new();
+        let mut next_token = 1;
+        let mut events = Events::with_capacity(128);
+
+        loop {
+            let now = Instant::now();
+            let timeout = terminals.values()
+                .filter_map(|terminal| terminal.session.next_timer())
+                .min()
+                .map(|due| due.saturating_duration_since(now));
+            poll.poll(&mut events, timeout)?;
+
+            let mut ready = vec![];
+            for event in events.iter() {
+                if event.token() == NEW_SESSION {
+                    for mut session in new_sessions.try_iter() {
+                        session.set_nonblocking(true)?;
+                        let token = Token(next_token);
+                        next_token += 1;
+                        let mut terminal = Terminal { session, presses: 0 };
+                        if terminal.draw(terminals.len()).is_err() {
+                            continue;
+                        }
+                        let interest = terminal.interest();
+                        poll.registry().register(&mut SourceFd(&terminal.session.as_raw_fd()), token, interest)?;
+                        terminals.insert(token, terminal);
+                    }
+                } else {
+                    ready.push((event.token(), event.is_readable() || event.is_read_closed(), event.is_writable()));
+                }
+            }
+
+            let others = terminals.len().saturating_sub(1);
+            let mut finished = vec![];
+            for (token, readable, writable) in ready {
+                let terminal = match terminals.get_mut(&token) {
+                    Some(terminal) => terminal,
+                    None => continue,
+                };
+                let result = (|| {
+                    if writable {
+                        terminal.session.on_writable()?;
+                    }
+                    if readable {
+                        terminal.session.on_readable()?;
+                    }
+                    terminal.handle_input(others)
+                })();
+                match result {
+                    Ok(true) => {
+                        let interest = terminal.interest();
+                        poll.registry().reregister(&mut SourceFd(&terminal.session.as_raw_fd()), token, interest)?;
+                    }
+                    Ok(false) => finished.push(token),
+                    Err(err) => {
+                        warn!("Error in session: {}", err);
+                        finished.push(token);
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            for (&token, terminal) in terminals.iter_mut() {
+                if terminal.session.next_timer().is_some_and(|due| due <= now) {
+                    if let Err(err) = terminal.session.on_timer() {
+                        warn!("Error in session: {}", err);
+                        finished.push(token);
+                    }
+                }
+            }
+
+            for token in finished {
+                if let Some(terminal) = terminals.remove(&token) {
+                    poll.registry().deregister(&mut SourceFd(&terminal.session.as_raw_fd()))?;
+                }
+            }
+        }
+    }
+
+    pub fn main() -> anyhow::Result<()> {
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+            .with_writer(std::io::stderr)
+            .init();
+        let options: Cli = Cli::from_args();
+        let server = std::net::TcpListener::bind((options.host.as_str(), options.port))?;
+        let lu_pool: Arc<dyn LuPool> = Arc::new(MemoryLuPool::new().with_terminals("TCP00###")?);
+
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), NEW_SESSION)?);
+        let (sender, new_sessions) = channel();
+        std::thread::spawn(move || {
+            if let Err(err) = event_loop(poll, new_sessions) {
+                warn!("Event loop failed: {}", err);
+                std::process::exit(1);
+            }
+        });
+
+        let keepalive = Keepalive::new(Duration::from_secs(60), KeepaliveProbe::TimingMark);
+        for client in server.incoming() {
+            let client = client?;
+            let lu_pool = lu_pool.clone();
+            let sender = sender.clone();
+            let waker = waker.clone();
+            std::thread::spawn(move || {
+                let policy = NegotiationPolicy { nvt_fallback: true, ..NegotiationPolicy::default() };
+                match tn3270::Session::with_policy(client, lu_pool, policy) {
+                    Ok(mut session) => {
+                        session.set_keepalive(Some(keepalive));
+                        if sender.send(session).is_ok() {
+                            let _ = waker.wake();
+                        }
+                    }
+                    Err(err) => warn!("Error negotiating session: {}", err),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn main() -> anyhow::Result<()> {
+    demo::main()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("This demo polls raw file descriptors, so it only runs on Unix");
+}
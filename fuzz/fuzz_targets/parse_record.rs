@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use tn3270s::tn3270::stream::IncomingRecord;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = IncomingRecord::parse_record(data);
+});
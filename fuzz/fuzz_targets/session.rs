@@ -0,0 +1,66 @@
+//! Feeds arbitrary bytes to a session as if they came from a client, through negotiation
+//! and on to receiving records
+
+#![no_main]
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+
+use tn3270s::tn3270::Session;
+use tn3270s::tn3270::lu::AdHocLuPool;
+use tn3270s::tn3270::negotiation::NegotiationPolicy;
+use tn3270s::tn3270::transport::Transport;
+
+/// Hands out the input a few bytes at a time (the first byte says how many), so that
+/// commands get split across reads, then reports EOF
+struct Client<'a> {
+    input: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Client<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.chunk).min(self.input.len());
+        let (now, later) = self.input.split_at(len);
+        buf[..len].copy_from_slice(now);
+        self.input = later;
+        Ok(len)
+    }
+}
+
+impl Write for Client<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Client<'_> {
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, _nonblocking: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (chunk, input) = match data.split_first() {
+        Some((&chunk, input)) => (chunk.max(1) as usize, input),
+        None => return,
+    };
+    let client = Client { input, chunk };
+    let policy = NegotiationPolicy { nvt_fallback: true, ..NegotiationPolicy::default() };
+    let mut session = match Session::with_policy(client, Arc::new(AdHocLuPool), policy) {
+        Ok(session) => session,
+        Err(_) => return,
+    };
+    session.set_max_record_size(4096);
+    while let Ok(Some(_)) = session.receive_event(None) {}
+});
@@ -1,2 +1,5 @@
+// `Option::is_none_or` is newer than the toolchains this crate builds with
+#![allow(clippy::unnecessary_map_or)]
+
 pub mod tn3270;
 mod encoding;
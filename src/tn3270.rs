@@ -1,184 +1,823 @@
-use libtelnet_rs::{
-    Parser,
-    events::*,
-    telnet::{
-        op_option as tn_opt,
-        op_command as tn_cmd,
-    }
-};
-use std::net::TcpStream;
-use std::io::{Write, Read};
-use std::time::Duration;
-use std::collections::VecDeque;
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+
+use libtelnet_rs::telnet::op_command as tn_cmd;
+use tracing::{debug, field, info, info_span, warn, Span};
+
+use crate::tn3270::tn3270e::{DataType, DeviceResponse, Functions, Header};
+use crate::tn3270::lu::{AdHocLuPool, LuPool};
+use crate::tn3270::environ::Environment;
+use crate::tn3270::protocol::Protocol;
+use crate::tn3270::keepalive::{Keepalive, KeepaliveError, KeepaliveProbe};
+use crate::tn3270::idle::{IdleError, IdlePolicy};
+use crate::tn3270::negotiation::{NegotiationError, NegotiationPolicy};
+use crate::tn3270::nvt::ESCAPE_TIMEOUT;
+use crate::tn3270::proxy::{ProxyError, ProxyHeader};
+use crate::tn3270::terminal::TerminalModel;
+use crate::tn3270::stream::BufferAddressCalculator;
+use crate::tn3270::trace::{Direction, TraceRecorder};
+use crate::tn3270::transport::Transport;
 
 pub mod stream;
 pub mod screen;
+pub mod tn3270e;
+pub mod lu;
+pub mod environ;
+pub mod terminal;
+pub mod negotiation;
+pub mod proxy;
+pub mod listener;
+pub mod keepalive;
+pub mod idle;
+mod nvt;
+pub mod telnet;
+pub mod trace;
+pub mod transport;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(test, feature = "testing"))]
+pub mod replay;
+#[cfg(feature = "async")]
+pub mod async_session;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod protocol;
+
+/// The longest inbound record (or telnet subnegotiation) a session accepts unless told
+/// otherwise. Plenty for a full screen of input, or a terminal's query reply.
+pub const DEFAULT_MAX_RECORD_SIZE: usize = 32 * 1024;
+
+/// A single record received from the client. Outside of TN3270E mode, the header is
+/// synthesized and always describes 3270 data.
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub header: Header,
+    pub data: Vec<u8>,
+}
+
+/// Out-of-band requests from the terminal that aren't carried in a record
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Signal {
+    /// The ATTN key, sent as telnet IP or BREAK
+    Attention,
+    /// The SYSREQ key, sent as telnet AO when the TN3270E SYSREQ function is active
+    SysReq,
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Record(Record),
+    Signal(Signal),
+}
 
-pub struct Session {
+pub struct Session<T: Transport = TcpStream> {
+    protocol: Protocol,
+    stream: T,
 
-    parser: Parser,
+    keepalive: Option<Keepalive>,
+    last_heard: Instant,
+    last_probe: Option<Instant>,
+    /// TIMING-MARK probes sent since the client was last heard from
+    missed: u32,
 
-    stream: std::net::TcpStream,
+    idle: Option<IdlePolicy>,
+    last_aid: Instant,
+    warned: bool,
 
-    term_type: Option<Vec<u8>>,
-    is_eor: bool,
-    is_bin: bool,
+    /// When an NVT client's ESC started being held in case an escape sequence follows
+    escape_since: Option<Instant>,
 
-    incoming_records: VecDeque<Vec<u8>>,
-    cur_record: Vec<u8>,
+    closed: bool,
+
+    /// Reused for every read from the client
+    read_buf: Vec<u8>,
+    /// The read timeout the transport was last given, if it's known
+    read_timeout: Option<Option<Duration>>,
+    nonblocking: bool,
+    /// Output the transport wasn't ready for; only used in non-blocking mode
+    unsent: Vec<u8>,
+
+    span: Span,
+    trace: Option<TraceRecorder>,
+    proxy: Option<ProxyHeader>,
 }
 
+/// How much is read from the client at a time
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
 type Error = std::io::Error;
 
-impl Session {
-    pub fn new(stream: TcpStream) -> Result<Self, Error> {
+/// What the receive methods return once the client has hung up and everything it sent
+/// has been picked up
+pub(crate) fn disconnected() -> Error {
+    Error::new(std::io::ErrorKind::UnexpectedEof, "The client disconnected")
+}
+
+impl<T: Transport> Session<T> {
+    /// Create a session with the default [`NegotiationPolicy`], which doesn't read a PROXY
+    /// header; behind a load balancer, use [`Session::with_policy`] with
+    /// [`NegotiationPolicy::proxy_protocol`] set
+    pub fn new(stream: T) -> Result<Self, NegotiationError> {
+        Self::with_lu_pool(stream, Arc::new(AdHocLuPool))
+    }
+
+    /// Create a session that takes TN3270E device names from `lu_pool`. Like
+    /// [`Session::new`], this never reads a PROXY header.
+    pub fn with_lu_pool(stream: T, lu_pool: Arc<dyn LuPool>) -> Result<Self, NegotiationError> {
+        Self::with_policy(stream, lu_pool, NegotiationPolicy::default())
+    }
+
+    /// Create a session that negotiates according to `policy`
+    pub fn with_policy(stream: T, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy) -> Result<Self, NegotiationError> {
+        Self::start(stream, lu_pool, policy, None)
+    }
+
+    /// Like [`Session::with_policy`], recording everything sent and received, negotiation
+    /// included, to `trace`
+    pub fn with_trace(stream: T, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy, trace: TraceRecorder) -> Result<Self, NegotiationError> {
+        Self::start(stream, lu_pool, policy, Some(trace))
+    }
+
+    fn start(mut stream: T, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy, trace: Option<TraceRecorder>) -> Result<Self, NegotiationError> {
+        let span = info_span!("session", peer = field::Empty, term_type = field::Empty, lu = field::Empty);
+        let _entered = span.clone().entered();
+        // The PROXY header and negotiation share the deadline
+        let deadline = Instant::now() + policy.deadline;
+        let proxy = if let Some(header) = policy.proxy_header {
+            Some(header)
+        } else if policy.proxy_protocol {
+            match read_proxy_header(&mut stream, deadline) {
+                Ok(header) => Some(header),
+                Err(err) => {
+                    info!(peer = ?stream.peer_addr(), error = %err, "negotiation failed");
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(peer) = proxy.and_then(|proxy| proxy.source).or_else(|| stream.peer_addr()) {
+            span.record("peer", field::display(peer));
+        }
         let mut session = Session {
-            parser: Parser::new(),
-            incoming_records: VecDeque::new(),
+            protocol: Protocol::new(lu_pool, policy, stream.start_tls_policy()),
             stream,
-            term_type: None,
-            is_bin: false,
-            is_eor: false,
-            cur_record: Vec::new(),
+            keepalive: None,
+            last_heard: Instant::now(),
+            last_probe: None,
+            missed: 0,
+            idle: None,
+            last_aid: Instant::now(),
+            warned: false,
+            escape_since: None,
+            closed: false,
+            read_buf: vec![0; READ_BUFFER_SIZE],
+            read_timeout: None,
+            nonblocking: false,
+            unsent: Vec::new(),
+            span,
+            trace,
+            proxy,
         };
 
-        session.parser.options.support(tn_opt::EOR);
-        session.parser.options.support_remote(tn_opt::TTYPE);
-        session.parser.options.support(tn_opt::TTYPE);
-        session.parser.options.support(tn_opt::BINARY);
-
-        // eprintln!("Negotiating...");
-        session.negotiate()?;
-        // eprintln!("Negotiation complete.");
+        if let Err(err) = session.negotiate(deadline) {
+            info!(error = %err, "negotiation failed");
+            return Err(err);
+        }
+        if let Some(term_type) = session.term_type() {
+            session.span.record("term_type", term_type);
+        }
+        if let Some(lu) = session.lu_name() {
+            session.span.record("lu", lu);
+        }
+        info!(
+            tn3270e = session.is_tn3270e(),
+            nvt = session.is_nvt(),
+            start_tls = session.is_start_tls(),
+            "negotiation complete",
+        );
         Ok(session)
     }
 
-    fn option_state(&self, opt: u8) -> bool {
-        let opt = self.parser.options.get_option(opt);
-        opt.local_state && opt.remote_state
-    }
-
-    fn process_events(&mut self, mut events: Vec<TelnetEvents>) -> Result<(), Error> {
-        let mut extra_events = Vec::new();
-        let mut sendbuf = Vec::new();
-        while !events.is_empty() || !extra_events.is_empty() {
-            events.append(&mut extra_events);
-            extra_events.truncate(0);
-            for mut event in events.drain(..) {
-                match event {
-                    TelnetEvents::DataSend(ref mut data) => sendbuf.append(data),
-                    TelnetEvents::DataReceive(ref mut data) => self.cur_record.append(data),
-                    TelnetEvents::IAC(TelnetIAC { command: tn_cmd::EOR }) =>
-                        self.incoming_records.push_back(std::mem::replace(&mut self.cur_record, Vec::new())),
-                    TelnetEvents::IAC(iac) => eprintln!("Unknown IAC {}", iac.command),
-                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL, option: tn_opt::TTYPE }) => {
-                        // eprintln!("WILL ttype");
-                        let sub = self.parser.subnegotiation(tn_opt::TTYPE, vec![1]);
-                        if let Some(event) = sub {
-                            // eprintln!("Sending subnegotiation");
-                            extra_events.push(event);
-                        } else {
-                            // eprintln!("Didn't do subnegotiation");
-                        }
+    /// The span that everything logged about this session is recorded under, carrying the
+    /// client's address, terminal type and LU name. Enter it to log application events
+    /// alongside the session's own.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
 
-                    }
-                    TelnetEvents::Negotiation(TelnetNegotiation { command, option }) => {
-                        // eprintln!("Negotiate: {}/{}", command, option);
-                        self.is_eor = self.option_state(tn_opt::EOR);
-                        self.is_bin = self.option_state(tn_opt::BINARY);
-                    }
-                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: tn_opt::TTYPE, buffer }) => {
-                        if buffer[0] == 0 {
-                            self.term_type = Some(buffer[1..].to_vec());
-
-                            // If the terminal type is correct, we also need to negotiate EOR and BINARY
-                            extra_events.extend(
-                                [
-                                    self.parser._will(tn_opt::EOR),
-                                    self.parser._do(tn_opt::EOR),
-                                    self.parser._will(tn_opt::BINARY),
-                                    self.parser._do(tn_opt::BINARY),
-                                ].iter_mut()
-                                    .flat_map(Option::take)
-                            )
-                        }
-                    }
-                    TelnetEvents::Subnegotiation(_) => {},
-                    TelnetEvents::DecompressImmediate(_) => unimplemented!("We don't support MCCP"),
-                }
+    /// Whether TN3270E has been fully negotiated for this session
+    pub fn is_tn3270e(&self) -> bool {
+        self.protocol.is_tn3270e()
+    }
+
+    /// The TN3270E functions agreed on with the client. Always empty for plain TN3270.
+    pub fn functions(&self) -> Functions {
+        self.protocol.functions()
+    }
+
+    /// The LU name assigned during TN3270E negotiation
+    pub fn lu_name(&self) -> Option<&str> {
+        self.protocol.lu_name()
+    }
+
+    /// The terminal type chosen for the session, as the client names it
+    pub fn term_type(&self) -> Option<&str> {
+        self.protocol.term_type()
+    }
+
+    /// Every terminal type the client offered, in the order it listed them
+    pub fn offered_term_types(&self) -> &[String] {
+        self.protocol.term_types()
+    }
+
+    /// What the chosen terminal type says about the terminal. Types that aren't
+    /// recognized are taken to be a 3278 model 2.
+    pub fn terminal_model(&self) -> TerminalModel {
+        self.protocol.terminal_model()
+    }
+
+    /// The size of the terminal's screen: its alternate size after the application last
+    /// sent an Erase/Write Alternate, and its default size otherwise
+    pub fn screen_size(&self) -> BufferAddressCalculator {
+        self.protocol.screen_size()
+    }
+
+    /// The variables the client sent with NEW-ENVIRON. Empty if it doesn't support the
+    /// option.
+    pub fn environment(&self) -> &Environment {
+        self.protocol.environment()
+    }
+
+    /// Whether the client is a plain NVT terminal rather than a 3270. Records sent to it
+    /// are drawn as a full-screen form, and its keystrokes come back as 3270 input
+    /// records; only write commands can be sent.
+    pub fn is_nvt(&self) -> bool {
+        self.protocol.is_nvt()
+    }
+
+    /// Where the client connected from, as reported by the proxy in front of the server if
+    /// [`NegotiationPolicy::proxy_protocol`] or [`NegotiationPolicy::proxy_header`] is set,
+    /// or by the transport if not
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.proxy.and_then(|proxy| proxy.source).or_else(|| self.stream.peer_addr())
+    }
+
+    /// The address the client connected to; like [`Session::peer_addr`], this is the
+    /// proxy's idea of it when there is one
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.proxy.and_then(|proxy| proxy.destination).or_else(|| self.stream.local_addr())
+    }
+
+    /// The PROXY protocol header the session started with, whether it read it or was given
+    /// it in [`NegotiationPolicy::proxy_header`]
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy.as_ref()
+    }
+
+    /// Whether the connection was switched to TLS with START_TLS
+    pub fn is_start_tls(&self) -> bool {
+        self.protocol.is_tls()
+    }
+
+    /// The client's verified TLS certificate chain, starting with its own certificate.
+    /// Empty if the session isn't encrypted or the client didn't present a certificate.
+    #[cfg(feature = "tls")]
+    pub fn peer_certificates(&self) -> &[tls::PeerCertificate] {
+        self.stream.peer_certificates()
+    }
+
+    /// The application user that the acceptor's
+    /// [`IdentityMapper`](tls::IdentityMapper) assigned to the client's certificate. This
+    /// is settled by the time the session has been negotiated, so it can be used to skip
+    /// a sign-on screen.
+    #[cfg(feature = "tls")]
+    pub fn peer_identity(&self) -> Option<&str> {
+        self.stream.peer_identity()
+    }
+
+    /// Probe the client whenever it has been quiet for a while, so that one that has gone
+    /// away is reported as a [`KeepaliveError`] rather than leaving the session blocked
+    /// forever. `None` turns probing off.
+    pub fn set_keepalive(&mut self, keepalive: Option<Keepalive>) {
+        self.keepalive = keepalive;
+        self.last_probe = None;
+        self.missed = 0;
+    }
+
+    /// Switch the session to non-blocking mode, for serving it from an event loop (with
+    /// mio or epoll, say) instead of a thread of its own. Register the transport (see
+    /// [`Session::get_ref`]) for readability, and:
+    ///
+    /// * call [`Session::on_readable`] when it's readable, then take whatever records and
+    ///   signals have arrived with the receive methods, which return immediately;
+    /// * while [`Session::wants_write`] is true, also wait for it to become writable, and
+    ///   call [`Session::on_writable`] when it is;
+    /// * wake up at [`Session::next_timer`] and call [`Session::on_timer`], if a keepalive
+    ///   or idle timeout is set.
+    ///
+    /// Negotiation always blocks, so this can only be turned on once the session has been
+    /// created.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        if nonblocking == self.nonblocking {
+            return Ok(());
+        }
+        if !nonblocking {
+            // Anything still waiting has to go out before writes can block again
+            self.stream.set_nonblocking(false)?;
+            self.stream.write_all(self.unsent.as_slice())?;
+            self.unsent.clear();
+        } else {
+            self.stream.set_nonblocking(true)?;
+        }
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    /// The transport, e.g. to register it with an event loop
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    /// The transport. Reading from or writing to it directly will confuse the session.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    /// Read and process everything the client has sent, until the transport has nothing
+    /// more to give. Returns whether anything arrived. Only a single read is made in
+    /// blocking mode, so it should only be called once the transport is known to be
+    /// readable.
+    pub fn on_readable(&mut self) -> std::io::Result<bool> {
+        let mut received = false;
+        while !self.closed {
+            match self.read_once() {
+                Ok(true) => received = true,
+                Ok(false) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+            if !self.nonblocking {
+                break;
             }
         }
+        Ok(received)
+    }
+
+    /// Whether there's output waiting for the transport to become writable
+    pub fn wants_write(&self) -> bool {
+        !self.unsent.is_empty()
+    }
 
-        // eprintln!("Sending: {:?}", &sendbuf);
-        self.stream.write_all(sendbuf.as_slice())?;
+    /// Send as much of the waiting output as the transport will take
+    pub fn on_writable(&mut self) -> std::io::Result<()> {
+        while !self.unsent.is_empty() {
+            match self.stream.write(self.unsent.as_slice()) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.unsent.drain(..len);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
         Ok(())
     }
 
-    fn is_ready(&self) -> bool {
-        self.term_type.is_some() && self.is_bin && self.is_eor
+    /// When [`Session::on_timer`] next needs to be called, if a keepalive or idle timeout
+    /// is set or an NVT client's ESC key is waiting to be told apart from an escape
+    /// sequence
+    pub fn next_timer(&self) -> Option<Instant> {
+        [self.next_probe(), self.next_idle_check(), self.next_escape_flush()]
+            .iter()
+            .flatten()
+            .min()
+            .copied()
+    }
+
+    /// Send keepalive probes and idle warnings that are due, and press an NVT client's
+    /// ESC key once nothing has followed it. Fails as the receive methods do once the
+    /// client has stopped responding or been idle for too long.
+    pub fn on_timer(&mut self) -> std::io::Result<()> {
+        self.flush_escape_if_due()?;
+        self.probe_if_due()?;
+        self.check_idle()
+    }
+
+    /// Whether [`Screen::present`](screen::Screen::present) switches terminals with a larger
+    /// alternate size (such as a model 4) to it with an Erase/Write Alternate. On by
+    /// default; turn it off to keep every screen at the terminal's default 24x80.
+    pub fn set_use_alternate_size(&mut self, enabled: bool) {
+        self.protocol.set_use_alternate_size(enabled);
+    }
+
+    pub fn uses_alternate_size(&self) -> bool {
+        self.protocol.uses_alternate_size()
     }
 
-    fn negotiate(&mut self) -> Result<bool, std::io::Error> {
-        let mut initial_negotiation = vec![];
-        initial_negotiation.extend(self.parser._do(tn_opt::TTYPE));
-        initial_negotiation.extend(self.parser._will(tn_opt::TTYPE));
+    /// Limit how long a record from the client can be, rather than buffering whatever it
+    /// sends until it finishes one. A client that goes over is treated as broken, and the
+    /// receive method fails with [`InvalidData`](std::io::ErrorKind::InvalidData).
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.protocol.set_max_record_size(max_record_size);
+    }
 
-        self.process_events(initial_negotiation)?;
+    /// Whether the client has hung up. Records and signals it sent beforehand can still be
+    /// received.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
 
-        // Large enough for a TCP packet
-        let mut idata = Vec::with_capacity(2000);
-        idata.resize(idata.capacity(), 0);
+    /// Give up on the terminal once nobody has pressed an AID key (or ATTN) for as long
+    /// as `idle` allows, which is reported as an [`IdleError`]. `None` turns this off.
+    pub fn set_idle_timeout(&mut self, idle: Option<IdlePolicy>) {
+        self.idle = idle;
+        self.last_aid = Instant::now();
+        self.warned = false;
+    }
 
-        // Make sure that negotiation completes quickly
-        self.stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    /// Start (or, with `None`, stop) recording everything sent and received to a trace
+    /// file. Use [`Session::with_trace`] to include negotiation.
+    pub fn set_trace(&mut self, trace: Option<TraceRecorder>) {
+        self.trace = trace;
+    }
 
-        while !self.is_ready() {
-            let len = self.stream.read(&mut idata[..])?;
-            if len == 0 {
-                return Ok(false)
+    /// A trace that can't be written is given up on, rather than taking the session
+    /// down with it
+    fn record_trace(&mut self, direction: Direction, data: &[u8]) {
+        if let Some(ref mut trace) = self.trace {
+            if let Err(err) = trace.record(direction, data) {
+                warn!(error = %err, "couldn't write to the trace; no longer tracing");
+                self.trace = None;
             }
-            let events = self.parser.receive(&idata[..len]);
-            // eprintln!("Received events: {:#?}", &events);
-            self.process_events(events)?;
         }
+    }
 
-        self.stream.set_read_timeout(None)?;
-        Ok(true)
+    /// When the terminal last sent a record or signal
+    pub fn last_aid(&self) -> Instant {
+        self.last_aid
+    }
+
+    /// When the idle warning or timeout is next due
+    fn next_idle_check(&self) -> Option<Instant> {
+        let idle = self.idle.as_ref()?;
+        let timeout = self.last_aid + idle.timeout;
+        match idle.warning {
+            Some(ref warning) if !self.warned => Some(timeout.checked_sub(warning.lead).unwrap_or(self.last_aid)),
+            _ => Some(timeout),
+        }
+    }
+
+    /// Warn the user or end the session if the terminal has been idle for long enough
+    fn check_idle(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        if self.next_idle_check().map_or(true, |due| due > now) {
+            return Ok(());
+        }
+        let _entered = self.span.clone().entered();
+        let idle_for = now - self.last_aid;
+        let idle = match self.idle {
+            Some(ref idle) => idle,
+            None => return Ok(()),
+        };
+        if idle_for >= idle.timeout {
+            info!(?idle_for, "terminal idle for too long");
+            return Err(IdleError::TimedOut { idle_for }.into());
+        }
+        if let Some(ref warning) = idle.warning {
+            debug!(?idle_for, "warning the user about the idle timeout");
+            let command = warning.write_command(self.screen_size());
+            self.warned = true;
+            self.send_record(&command)?;
+        }
+        Ok(())
+    }
+
+    fn next_escape_flush(&self) -> Option<Instant> {
+        self.escape_since.map(|since| since + ESCAPE_TIMEOUT)
+    }
+
+    /// Take an NVT client's held ESC to be the Esc key if nothing has followed it in time
+    fn flush_escape_if_due(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        if self.next_escape_flush().map_or(true, |due| due > now) {
+            return Ok(());
+        }
+        let _entered = self.span.clone().entered();
+        self.escape_since = None;
+        let pending = self.protocol.pending_events();
+        self.protocol.flush_escape();
+        self.flush_output()?;
+        if self.protocol.pending_events() > pending {
+            self.last_aid = now;
+            self.warned = false;
+        }
+        Ok(())
+    }
+
+    fn next_probe(&self) -> Option<Instant> {
+        self.keepalive.map(|keepalive| self.last_probe.unwrap_or(self.last_heard) + keepalive.interval)
+    }
+
+    /// Send a keepalive probe if one is due, or give up on the client if it has ignored
+    /// too many of them
+    fn probe_if_due(&mut self) -> Result<(), Error> {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        if self.next_probe().is_some_and(|due| due > now) {
+            return Ok(());
+        }
+        let _entered = self.span.clone().entered();
+
+        let dead = KeepaliveError::DeadPeer { silent_for: now - self.last_heard };
+        if keepalive.probe == KeepaliveProbe::TimingMark && self.missed >= keepalive.max_missed {
+            warn!(missed = self.missed, "client stopped answering keepalive probes");
+            return Err(dead.into());
+        }
+        let sent = match keepalive.probe {
+            KeepaliveProbe::Nop => self.write_out(&[tn_cmd::IAC, tn_cmd::NOP]),
+            KeepaliveProbe::TimingMark => self.protocol.send_timing_mark().and_then(|()| self.flush_output()),
+        };
+        if let Err(err) = sent {
+            warn!(error = %err, "couldn't send keepalive probe");
+            return Err(dead.into());
+        }
+        debug!(probe = ?keepalive.probe, "sent keepalive probe");
+        if keepalive.probe == KeepaliveProbe::TimingMark {
+            self.missed += 1;
+        }
+        self.last_probe = Some(now);
+        Ok(())
+    }
+
+    /// Write out anything the protocol has queued for the client
+    fn flush_output(&mut self) -> Result<(), Error> {
+        let output = self.protocol.take_output();
+        if !output.is_empty() {
+            self.write_out(output.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Send `data`, or in non-blocking mode, as much of it as the transport will take
+    /// without waiting, keeping the rest for [`Session::on_writable`]
+    fn write_out(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.record_trace(Direction::Outbound, data);
+        if !self.nonblocking {
+            return self.stream.write_all(data);
+        }
+        self.unsent.extend_from_slice(data);
+        self.on_writable()
+    }
+
+    /// Feed raw bytes from the client through the telnet parser
+    fn receive_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        // Anything at all from the client shows that it's still there, but only an answer
+        // to a TIMING-MARK shows that it's still answering them
+        self.last_heard = Instant::now();
+        self.last_probe = None;
+
+        let pending = self.protocol.pending_events();
+        self.protocol.receive_bytes(data)?;
+        if self.protocol.take_timing_mark_answer() {
+            self.missed = 0;
+        }
+        self.flush_output()?;
+        if self.protocol.pending_events() > pending {
+            self.last_aid = Instant::now();
+            self.warned = false;
+        }
+        self.escape_since = if self.protocol.has_pending_escape() {
+            self.escape_since.or_else(|| Some(Instant::now()))
+        } else {
+            None
+        };
 
+        if let Some(handshake) = self.protocol.take_tls_handshake() {
+            // The handshake sets its own timeouts
+            self.read_timeout = None;
+            self.stream.start_tls(handshake.as_slice())?;
+            self.protocol.restart_after_tls()?;
+            self.flush_output()?;
+        }
+        Ok(())
     }
 
+    fn negotiate(&mut self, deadline: Instant) -> Result<(), NegotiationError> {
+        // Negotiation always blocks, even if the transport was handed over non-blocking
+        self.stream.set_nonblocking(false)?;
+        self.protocol.start_negotiation()?;
+        self.flush_output()?;
+
+        while !self.protocol.is_ready() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(NegotiationError::TimedOut);
+            }
+            self.set_read_timeout(Some(remaining))?;
+            match self.read_once() {
+                Ok(true) => {}
+                Ok(false) => return Err(NegotiationError::PeerClosed),
+                Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    return Err(NegotiationError::TimedOut);
+                }
+                Err(err) => {
+                    // Make sure the client sees any explanation before it's dropped
+                    let _ = self.flush_output();
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a 3270 data stream record
     pub fn send_record(&mut self, record: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        self.send_typed_record(DataType::Data3270, record)
+    }
 
-        let mut send_data = Parser::escape_iac(record.into());
-        send_data.extend_from_slice(&[libtelnet_rs::telnet::op_command::IAC, libtelnet_rs::telnet::op_command::EOR]);
-        self.stream.write_all(send_data.as_slice())
+    /// Send a record of an arbitrary TN3270E data type. Outside of TN3270E mode, only
+    /// 3270 data can be sent.
+    pub fn send_typed_record(&mut self, data_type: DataType, record: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        let _entered = self.span.clone().entered();
+        self.protocol.queue_record(data_type, record.into())?;
+        self.flush_output()
     }
 
+    /// Send a 3270 data stream record and wait up to `timeout` for the terminal to report
+    /// whether it was processed. Returns `None` if the timeout expires first (or, in
+    /// non-blocking mode, if the response isn't already in). Requires the RESPONSES
+    /// function to have been negotiated.
+    pub fn send_record_with_response(&mut self, record: impl Into<Vec<u8>>, timeout: Option<Duration>) -> std::io::Result<Option<DeviceResponse>> {
+        let _entered = self.span.clone().entered();
+        let seq_number = self.protocol.queue_record_with_response(record.into())?;
+        self.flush_output()?;
 
-    pub fn receive_record(&mut self, timeout: Option<Duration>) -> std::io::Result<Option<Vec<u8>>> {
-        if !self.incoming_records.is_empty() {
-            return Ok(self.incoming_records.pop_front());
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while !self.protocol.has_response(seq_number) {
+            if !self.read_available(deadline)? {
+                break;
+            }
+        }
+        match self.protocol.take_response(seq_number) {
+            None if self.closed => Err(disconnected()),
+            response => Ok(response),
         }
+    }
 
-        self.stream.set_read_timeout(timeout)?;
-        let mut buf = vec![0; 1024];
-        let mut len = self.stream.read(buf.as_mut_slice())?;
-        if len != 0 {
-            self.stream.set_nonblocking(true)?;
-            while len != 0 {
-                let events = self.parser.receive(&buf[..len]);
-                self.process_events(events)?;
-                len = match self.stream.read(buf.as_mut_slice()) {
-                    Ok(len) => len,
-                    Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => 0,
-                    Err(err) => return Err(err),
-                };
+    /// Wait up to `timeout` for the next record or signal from the terminal. Signals are
+    /// returned ahead of any records that are already queued. Fails with
+    /// [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) once the client has hung up.
+    pub fn receive_event(&mut self, timeout: Option<Duration>) -> std::io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while !self.protocol.has_event() {
+            if !self.read_available(deadline)? {
+                break;
             }
-            self.stream.set_nonblocking(false)?;
         }
+        match self.protocol.next_event() {
+            None if self.closed => Err(disconnected()),
+            event => Ok(event),
+        }
+    }
+
+    /// Check, without waiting more than a moment, whether the terminal has sent a signal.
+    /// This is meant to be called periodically during long-running work so that ATTN can
+    /// interrupt it.
+    pub fn poll_signal(&mut self) -> std::io::Result<Option<Signal>> {
+        if !self.protocol.has_signal() && !self.closed {
+            // A read timeout can't be zero, so a blocking session waits the shortest one it
+            // can rather than switching the transport's mode
+            self.read_available(Some(Instant::now() + Duration::from_millis(1)))?;
+            self.on_timer()?;
+        }
+        match self.protocol.next_signal() {
+            None if self.closed => Err(disconnected()),
+            signal => Ok(signal),
+        }
+    }
 
-        self.stream.set_read_timeout(None)?;
-        Ok(self.incoming_records.pop_front())
+    /// Wait up to `timeout` for the next record. Signals are left queued for
+    /// [`Session::receive_event`] and [`Session::poll_signal`]. Fails with
+    /// [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) once the client has hung up.
+    pub fn receive_record(&mut self, timeout: Option<Duration>) -> std::io::Result<Option<Record>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while !self.protocol.has_record() {
+            if !self.read_available(deadline)? {
+                break;
+            }
+        }
+        match self.protocol.next_record() {
+            None if self.closed => Err(disconnected()),
+            record => Ok(record),
+        }
     }
+
+    /// Process what the client sends next, waiting until `deadline` for it to send
+    /// anything at all. In non-blocking mode, this processes whatever is ready without
+    /// waiting. Returns false if nothing arrived or the client has hung up.
+    fn read_available(&mut self, deadline: Option<Instant>) -> std::io::Result<bool> {
+        if self.nonblocking {
+            return self.on_readable();
+        }
+        while !self.closed {
+            self.check_idle()?;
+            // Wake up for whichever comes first: the caller's deadline, the next probe, or
+            // the idle warning or timeout
+            let wake = [deadline, self.next_timer()]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+            let wait = wake.map(|wake| wake.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+            self.set_read_timeout(wait)?;
+            match self.read_once() {
+                Ok(received) => return Ok(received),
+                Err(err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    // A held ESC that turns out to be the Esc key counts as input
+                    let pending = self.protocol.pending_events();
+                    self.flush_escape_if_due()?;
+                    if self.protocol.pending_events() > pending {
+                        return Ok(true);
+                    }
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        return Ok(false);
+                    }
+                    self.on_timer()?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Make a single read from the transport and process it. Returns false if the client
+    /// has hung up.
+    fn read_once(&mut self) -> std::io::Result<bool> {
+        let _entered = self.span.clone().entered();
+        let mut buf = std::mem::take(&mut self.read_buf);
+        let result = self.stream.read(buf.as_mut_slice());
+        let result = match result {
+            Ok(0) => {
+                debug!("client disconnected");
+                self.closed = true;
+                Ok(false)
+            }
+            Ok(len) => {
+                self.record_trace(Direction::Inbound, &buf[..len]);
+                self.receive_bytes(&buf[..len]).map(|()| true)
+            }
+            Err(err) => Err(self.read_failed(err)),
+        };
+        self.read_buf = buf;
+        result
+    }
+
+    /// Only touch the transport's read timeout when it actually changes
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        if self.read_timeout != Some(timeout) {
+            self.read_timeout = None;
+            self.stream.set_read_timeout(timeout)?;
+            self.read_timeout = Some(timeout);
+        }
+        Ok(())
+    }
+
+    /// Notice a connection that was reset rather than closed cleanly
+    fn read_failed(&mut self, err: Error) -> Error {
+        use std::io::ErrorKind;
+        if matches!(err.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted) {
+            debug!(error = %err, "client connection reset");
+            self.closed = true;
+        }
+        err
+    }
+}
+
+/// Read the PROXY protocol header, which has to arrive by `deadline`
+fn read_proxy_header<T: Transport>(stream: &mut T, deadline: Instant) -> Result<ProxyHeader, NegotiationError> {
+    ProxyHeader::read_until(stream, deadline).map_err(proxy_failed)
 }
 
+/// Why negotiation failed, given why the PROXY header couldn't be read
+pub(crate) fn proxy_failed(err: ProxyError) -> NegotiationError {
+    use std::io::ErrorKind;
+    match err {
+        ProxyError::Io { source } if matches!(source.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => NegotiationError::TimedOut,
+        ProxyError::Io { source } if source.kind() == ErrorKind::UnexpectedEof => NegotiationError::PeerClosed,
+        source => NegotiationError::Proxy { source },
+    }
+}
+
+#[cfg(unix)]
+impl<T: Transport + std::os::unix::io::AsRawFd> std::os::unix::io::AsRawFd for Session<T> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.stream.as_raw_fd()
+    }
+}
@@ -0,0 +1,495 @@
+//! A [`Session`](crate::tn3270::Session) for tokio, so that many idle terminals can be
+//! served without a thread apiece
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tracing::{debug, field, info, info_span, Instrument, Span};
+
+use crate::tn3270::{disconnected, proxy_failed, Event, Record};
+use crate::tn3270::environ::Environment;
+use crate::tn3270::lu::{AdHocLuPool, LuPool};
+use crate::tn3270::protocol::Protocol;
+use crate::tn3270::negotiation::{NegotiationError, NegotiationPolicy};
+use crate::tn3270::nvt::ESCAPE_TIMEOUT;
+use crate::tn3270::proxy::ProxyHeader;
+use crate::tn3270::stream::BufferAddressCalculator;
+use crate::tn3270::terminal::TerminalModel;
+use crate::tn3270::tn3270e::{DataType, DeviceResponse, Functions};
+use crate::tn3270::transport::StartTlsPolicy;
+
+type Error = std::io::Error;
+
+/// Like [`Session`](crate::tn3270::Session), but over a tokio stream. Only the core of a
+/// session is supported so far; unlike `Session`, an `AsyncSession`:
+///
+/// * doesn't send keepalive probes or enforce an idle timeout, so wrap the receive methods
+///   in [`tokio::time::timeout`] to give up on a terminal;
+/// * can't record a trace;
+/// * never offers START_TLS, since tokio streams can't be switched to TLS in place;
+/// * only knows the client's address if it's given one with
+///   [`AsyncSession::with_peer_addr`] or in a PROXY header.
+///
+/// Like `Session`, it reads a PROXY protocol header ahead of negotiation when
+/// [`NegotiationPolicy::proxy_protocol`] is set, unless one was already read and passed in
+/// [`NegotiationPolicy::proxy_header`].
+///
+/// The sending and receiving methods are cancel-safe: if one of their futures is dropped
+/// part-way (say, by `tokio::select!` or a timeout), output that hadn't been written yet is
+/// sent by the next call, and anything already received stays queued for the next receive.
+pub struct AsyncSession<S = TcpStream> {
+    protocol: Protocol,
+    stream: S,
+    /// Output taken from the protocol that hasn't been written to the stream yet
+    pending: Vec<u8>,
+    read_buf: Vec<u8>,
+    closed: bool,
+    /// When an NVT client's ESC started being held in case an escape sequence follows
+    escape_since: Option<Instant>,
+    span: Span,
+    peer: Option<SocketAddr>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncSession<S> {
+    pub async fn new(stream: S) -> Result<Self, NegotiationError> {
+        Self::with_lu_pool(stream, Arc::new(AdHocLuPool)).await
+    }
+
+    /// Create a session that takes TN3270E device names from `lu_pool`
+    pub async fn with_lu_pool(stream: S, lu_pool: Arc<dyn LuPool>) -> Result<Self, NegotiationError> {
+        Self::with_policy(stream, lu_pool, NegotiationPolicy::default()).await
+    }
+
+    /// Create a session that negotiates according to `policy`
+    pub async fn with_policy(stream: S, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy) -> Result<Self, NegotiationError> {
+        Self::start(stream, None, lu_pool, policy).await
+    }
+
+    /// Like [`AsyncSession::with_policy`], for a client connecting from `peer`, which is
+    /// recorded on the session's span. tokio streams can't be asked for it in general.
+    pub async fn with_peer_addr(stream: S, peer: SocketAddr, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy) -> Result<Self, NegotiationError> {
+        Self::start(stream, Some(peer), lu_pool, policy).await
+    }
+
+    async fn start(mut stream: S, peer: Option<SocketAddr>, lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy) -> Result<Self, NegotiationError> {
+        let span = info_span!("session", peer = field::Empty, term_type = field::Empty, lu = field::Empty);
+        // The PROXY header and negotiation share the deadline
+        let deadline = Instant::now() + policy.deadline;
+        let proxy = if let Some(header) = policy.proxy_header {
+            Some(header)
+        } else if policy.proxy_protocol {
+            match read_proxy_header(&mut stream, deadline).await {
+                Ok(header) => Some(header),
+                Err(err) => {
+                    span.in_scope(|| info!(peer = ?peer, error = %err, "negotiation failed"));
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+        let peer = proxy.and_then(|header| header.source).or(peer);
+        if let Some(peer) = peer {
+            span.record("peer", field::display(peer));
+        }
+        let mut session = AsyncSession {
+            protocol: Protocol::new(lu_pool, policy, StartTlsPolicy::Refuse),
+            stream,
+            pending: Vec::new(),
+            // Large enough for a TCP packet
+            read_buf: vec![0; 2000],
+            closed: false,
+            escape_since: None,
+            span: span.clone(),
+            peer,
+        };
+        if let Err(err) = session.negotiate(deadline).instrument(span.clone()).await {
+            span.in_scope(|| info!(error = %err, "negotiation failed"));
+            return Err(err);
+        }
+        if let Some(term_type) = session.term_type() {
+            span.record("term_type", term_type);
+        }
+        if let Some(lu) = session.lu_name() {
+            span.record("lu", lu);
+        }
+        span.in_scope(|| info!(tn3270e = session.is_tn3270e(), nvt = session.is_nvt(), "negotiation complete"));
+        Ok(session)
+    }
+
+    /// The span that everything logged about this session is recorded under; see
+    /// [`Session::span`](crate::tn3270::Session::span)
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Where the client connected from, if the session was given it, preferring the
+    /// address in the PROXY header when there is one
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    /// Whether TN3270E has been fully negotiated for this session
+    pub fn is_tn3270e(&self) -> bool {
+        self.protocol.is_tn3270e()
+    }
+
+    /// The TN3270E functions agreed on with the client. Always empty for plain TN3270.
+    pub fn functions(&self) -> Functions {
+        self.protocol.functions()
+    }
+
+    /// The LU name assigned during TN3270E negotiation
+    pub fn lu_name(&self) -> Option<&str> {
+        self.protocol.lu_name()
+    }
+
+    /// The terminal type chosen for the session, as the client names it
+    pub fn term_type(&self) -> Option<&str> {
+        self.protocol.term_type()
+    }
+
+    /// Every terminal type the client offered, in the order it listed them
+    pub fn offered_term_types(&self) -> &[String] {
+        self.protocol.term_types()
+    }
+
+    /// What the chosen terminal type says about the terminal. Types that aren't
+    /// recognized are taken to be a 3278 model 2.
+    pub fn terminal_model(&self) -> TerminalModel {
+        self.protocol.terminal_model()
+    }
+
+    /// The size of the terminal's screen; see
+    /// [`Session::screen_size`](crate::tn3270::Session::screen_size)
+    pub fn screen_size(&self) -> BufferAddressCalculator {
+        self.protocol.screen_size()
+    }
+
+    /// Whether the client is a plain NVT terminal rather than a 3270. See
+    /// [`Session::is_nvt`](crate::tn3270::Session::is_nvt).
+    pub fn is_nvt(&self) -> bool {
+        self.protocol.is_nvt()
+    }
+
+    /// The variables the client sent with NEW-ENVIRON. Empty if it doesn't support the
+    /// option.
+    pub fn environment(&self) -> &Environment {
+        self.protocol.environment()
+    }
+
+    /// Whether [`Screen::present_async`](crate::tn3270::screen::Screen::present_async) uses
+    /// the alternate size; see
+    /// [`Session::set_use_alternate_size`](crate::tn3270::Session::set_use_alternate_size)
+    pub fn set_use_alternate_size(&mut self, enabled: bool) {
+        self.protocol.set_use_alternate_size(enabled);
+    }
+
+    pub fn uses_alternate_size(&self) -> bool {
+        self.protocol.uses_alternate_size()
+    }
+
+    /// Limit how long a record from the client can be; see
+    /// [`Session::set_max_record_size`](crate::tn3270::Session::set_max_record_size)
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.protocol.set_max_record_size(max_record_size);
+    }
+
+    /// Whether the client has hung up. Records and signals it sent beforehand can still be
+    /// received.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    async fn flush_output(&mut self) -> Result<(), Error> {
+        self.pending.append(&mut self.protocol.take_output());
+        // Only drop what has been written, so that nothing is lost if this future is
+        // cancelled
+        while !self.pending.is_empty() {
+            let len = self.stream.write(self.pending.as_slice()).await?;
+            if len == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            self.pending.drain(..len);
+        }
+        Ok(())
+    }
+
+    /// Read from the client until `done` is satisfied, the client hangs up, or `deadline`
+    /// passes. Returns false if the deadline passed first.
+    async fn read_until(&mut self, deadline: Option<Instant>, done: impl Fn(&Protocol) -> bool) -> Result<bool, Error> {
+        while !done(&self.protocol) && !self.closed {
+            let escape_due = self.escape_since.map(|since| since + ESCAPE_TIMEOUT);
+            let wake = [deadline, escape_due].iter().flatten().min().copied();
+            let read = self.stream.read(self.read_buf.as_mut_slice());
+            let len = match wake {
+                Some(wake) => match tokio::time::timeout_at(wake, read).await {
+                    Ok(result) => result?,
+                    Err(_) if escape_due == Some(wake) => {
+                        // Nothing followed the ESC, so it was the Esc key
+                        self.escape_since = None;
+                        let protocol = &mut self.protocol;
+                        self.span.in_scope(|| protocol.flush_escape());
+                        self.flush_output().await?;
+                        continue;
+                    }
+                    Err(_) => return Ok(false),
+                },
+                None => read.await?,
+            };
+            let entered = self.span.enter();
+            if len == 0 {
+                debug!("client disconnected");
+                self.closed = true;
+                break;
+            }
+            self.protocol.receive_bytes(&self.read_buf[..len])?;
+            self.escape_since = if self.protocol.has_pending_escape() {
+                self.escape_since.or_else(|| Some(Instant::now()))
+            } else {
+                None
+            };
+            // The span mustn't stay entered across an await
+            drop(entered);
+            self.flush_output().await?;
+        }
+        Ok(true)
+    }
+
+    async fn negotiate(&mut self, deadline: Instant) -> Result<(), NegotiationError> {
+        self.protocol.start_negotiation()?;
+        self.flush_output().await?;
+
+        let finished = self.read_until(Some(deadline), Protocol::is_ready).await;
+        if finished.is_err() {
+            // Make sure the client sees any explanation before it's dropped
+            let _ = self.flush_output().await;
+        }
+        if !finished? {
+            Err(NegotiationError::TimedOut)
+        } else if !self.protocol.is_ready() {
+            Err(NegotiationError::PeerClosed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Send a 3270 data stream record
+    pub async fn send_record(&mut self, record: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        self.send_typed_record(DataType::Data3270, record).await
+    }
+
+    /// Send a record of an arbitrary TN3270E data type. Outside of TN3270E mode, only
+    /// 3270 data can be sent.
+    pub async fn send_typed_record(&mut self, data_type: DataType, record: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        let protocol = &mut self.protocol;
+        self.span.in_scope(|| protocol.queue_record(data_type, record.into()))?;
+        self.flush_output().await
+    }
+
+    /// Send a 3270 data stream record and wait up to `timeout` for the terminal to report
+    /// whether it was processed. Returns `None` if the timeout expires first. Requires the
+    /// RESPONSES function to have been negotiated.
+    pub async fn send_record_with_response(&mut self, record: impl Into<Vec<u8>>, timeout: Option<Duration>) -> std::io::Result<Option<DeviceResponse>> {
+        let protocol = &mut self.protocol;
+        let seq_number = self.span.in_scope(|| protocol.queue_record_with_response(record.into()))?;
+        self.flush_output().await?;
+
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.read_until(deadline, |protocol| protocol.has_response(seq_number)).await?;
+        match self.protocol.take_response(seq_number) {
+            None if self.closed => Err(disconnected()),
+            response => Ok(response),
+        }
+    }
+
+    /// Wait up to `timeout` for the next record or signal from the terminal. Signals are
+    /// returned ahead of any records that are already queued. Fails with
+    /// [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) once the client has hung up.
+    pub async fn receive_event(&mut self, timeout: Option<Duration>) -> std::io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.read_until(deadline, Protocol::has_event).await?;
+        match self.protocol.next_event() {
+            None if self.closed => Err(disconnected()),
+            event => Ok(event),
+        }
+    }
+
+    /// Wait up to `timeout` for the next record. Signals are left queued for
+    /// [`AsyncSession::receive_event`]. Fails with
+    /// [`UnexpectedEof`](std::io::ErrorKind::UnexpectedEof) once the client has hung up.
+    pub async fn receive_record(&mut self, timeout: Option<Duration>) -> std::io::Result<Option<Record>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.read_until(deadline, Protocol::has_record).await?;
+        match self.protocol.next_record() {
+            None if self.closed => Err(disconnected()),
+            record => Ok(record),
+        }
+    }
+}
+
+/// Read the PROXY protocol header, which has to arrive by `deadline`
+async fn read_proxy_header<S: AsyncRead + Unpin>(stream: &mut S, deadline: Instant) -> Result<ProxyHeader, NegotiationError> {
+    match tokio::time::timeout_at(deadline, ProxyHeader::read_async(stream)).await {
+        Ok(result) => result.map_err(proxy_failed),
+        Err(_) => Err(NegotiationError::TimedOut),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::io::{Read, Write};
+    use std::task::Poll;
+    use tokio::io::DuplexStream;
+    use crate::tn3270::Signal;
+    use crate::tn3270::memory::{self, MemoryStream};
+    use crate::tn3270::screen::{Field, Screen, ScreenError};
+    use crate::tn3270::stream::{AID, WriteCommandCode};
+    use crate::tn3270::testing::{FakeClient, TIMEOUT};
+
+    // The tests wait on the FakeClient synchronously, so the bridge needs worker threads of
+    // its own to make progress meanwhile
+
+    /// Carry bytes between a tokio duplex stream and the in-memory stream a [`FakeClient`]
+    /// talks over, until either side hangs up
+    fn bridge(tokio_end: DuplexStream, memory_end: MemoryStream) {
+        let (mut tokio_read, mut tokio_write) = tokio::io::split(tokio_end);
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+
+        let mut reader = memory_end.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; 1024];
+            while let Ok(len @ 1..) = reader.read(buf.as_mut_slice()) {
+                if sender.send(buf[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(data) = receiver.recv().await {
+                if tokio_write.write_all(data.as_slice()).await.is_err() {
+                    break;
+                }
+            }
+            let _ = tokio_write.shutdown().await;
+        });
+        tokio::spawn(async move {
+            let mut writer = memory_end;
+            let mut buf = vec![0; 1024];
+            while let Ok(len @ 1..) = tokio_read.read(buf.as_mut_slice()).await {
+                if writer.write_all(&buf[..len]).is_err() {
+                    break;
+                }
+            }
+            writer.shutdown();
+        });
+    }
+
+    async fn connect(term_type: &str) -> (AsyncSession<DuplexStream>, FakeClient) {
+        let (server, tokio_end) = tokio::io::duplex(4096);
+        let (memory_end, client) = memory::duplex();
+        bridge(tokio_end, memory_end);
+        let client = FakeClient::start(client, term_type);
+        let session = AsyncSession::new(server).await.expect("negotiation failed");
+        (session, client)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn negotiates() {
+        let (session, _client) = connect("IBM-3278-4").await;
+        assert!(!session.is_tn3270e());
+        assert!(!session.is_nvt());
+        assert_eq!(session.term_type(), Some("IBM-3278-4"));
+        assert_eq!(session.screen_size().height, 24);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_proxy_header() {
+        let (server, mut tokio_end) = tokio::io::duplex(4096);
+        tokio_end.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 23\r\n").await.unwrap();
+        let (memory_end, client) = memory::duplex();
+        bridge(tokio_end, memory_end);
+        let _client = FakeClient::start(client, "IBM-3278-2");
+        let policy = NegotiationPolicy { proxy_protocol: true, ..NegotiationPolicy::default() };
+        let session = AsyncSession::with_policy(server, Arc::new(AdHocLuPool), policy).await.expect("negotiation failed");
+        assert_eq!(session.peer_addr(), Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(session.term_type(), Some("IBM-3278-2"));
+    }
+
+    #[tokio::test]
+    async fn proxy_header_shares_the_deadline() {
+        let (server, mut client) = tokio::io::duplex(4096);
+        // Started, but never finished
+        client.write_all(b"PROXY TCP4 ").await.unwrap();
+        let policy = NegotiationPolicy { proxy_protocol: true, deadline: Duration::from_millis(100), ..NegotiationPolicy::default() };
+        let result = AsyncSession::with_policy(server, Arc::new(AdHocLuPool), policy).await;
+        assert!(matches!(result, Err(NegotiationError::TimedOut)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn record_round_trip() {
+        let (mut session, mut client) = connect("IBM-3278-2").await;
+        session.send_record(vec![0xF1, 0xC2, 0x11, 0x40, 0x40]).await.unwrap();
+        assert_eq!(client.next_record(TIMEOUT), Some(vec![0xF1, 0xC2, 0x11, 0x40, 0x40]));
+
+        client.send_aid(AID::Enter, 86, &[]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).await.unwrap().expect("no record");
+        assert_eq!(record.data, [0x7D, 0x00, 0x56]);
+
+        client.send_attention().unwrap();
+        assert!(matches!(session.receive_event(Some(TIMEOUT)).await, Ok(Some(Event::Signal(Signal::Attention)))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn present_screen() {
+        let (mut session, mut client) = connect("IBM-3278-2").await;
+        client.send_aid(AID::Enter, 262, &[(261, "BOB")]).unwrap();
+
+        let mut name = "        ".to_owned();
+        let response = Screen {
+            fields: vec![
+                Field::at(1, 32).ro_text("Sign on"),
+                Field::at(3, 20).rw_text(&mut name),
+            ],
+        }.present_async(&mut session).await.unwrap();
+        assert_eq!(response.aid, AID::Enter);
+        assert_eq!(name, "BOB");
+
+        let screen = client.next_write(TIMEOUT).unwrap().expect("no screen");
+        assert_eq!(screen.command, WriteCommandCode::EraseWrite);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnect() {
+        let (mut session, client) = connect("IBM-3278-2").await;
+        client.disconnect();
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Bye")] }.present_async(&mut session).await;
+        assert!(matches!(result, Err(ScreenError::Disconnected)));
+        let err = session.receive_record(Some(TIMEOUT)).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    // Single-threaded, so the bridge can't drain the stream while the send is being cancelled
+    #[tokio::test]
+    async fn cancelled_sends_keep_their_output() {
+        let (mut session, mut client) = connect("IBM-3278-2").await;
+        // Bigger than the duplex buffer, so the write can't finish in one go
+        let mut send = Box::pin(session.send_record(vec![0x40; 20000]));
+        let pending = std::future::poll_fn(|cx| Poll::Ready(send.as_mut().poll(cx).is_pending())).await;
+        assert!(pending);
+        drop(send);
+        session.send_record(vec![0xF1, 0xC2]).await.unwrap();
+
+        let records = tokio::task::spawn_blocking(move || [client.next_record(TIMEOUT), client.next_record(TIMEOUT)])
+            .await
+            .unwrap();
+        assert_eq!(records[0].as_ref().map(Vec::len), Some(20000));
+        assert_eq!(records[1], Some(vec![0xF1, 0xC2]));
+    }
+}
@@ -0,0 +1,156 @@
+//! The NEW-ENVIRON telnet option (RFC 1572), which emulators use to pass along the user
+//! name, workstation ID, and other settings
+
+use std::collections::HashMap;
+
+use crate::tn3270::telnet::unescape_iac;
+
+pub const OPT_NEW_ENVIRON: u8 = 39;
+
+/// How many variables a client can set, counting well-known and user variables together
+pub const MAX_VARIABLES: usize = 64;
+/// The longest name or value a variable can have
+pub const MAX_VARIABLE_SIZE: usize = 1024;
+
+pub mod cmd {
+    pub const IS: u8 = 0;
+    pub const SEND: u8 = 1;
+    pub const INFO: u8 = 2;
+}
+
+pub mod kind {
+    pub const VAR: u8 = 0;
+    pub const VALUE: u8 = 1;
+    pub const ESC: u8 = 2;
+    pub const USERVAR: u8 = 3;
+}
+
+/// The variables a client has sent. Well-known variables (RFC 1572 VARs, such as `USER`)
+/// are kept apart from user variables, which is where emulators put things like
+/// `DEVNAME` and `IBMRSEED`. Variables the client reported as undefined are left out, as
+/// are any beyond [`MAX_VARIABLES`] and any with a name or value over
+/// [`MAX_VARIABLE_SIZE`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Environment {
+    pub vars: HashMap<String, Vec<u8>>,
+    pub user_vars: HashMap<String, Vec<u8>>,
+}
+
+impl Environment {
+    /// The `USER` variable, if it's text
+    pub fn user(&self) -> Option<&str> {
+        self.vars.get("USER").and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// The `DEVNAME` user variable, which names the workstation, if it's text
+    pub fn devname(&self) -> Option<&str> {
+        self.user_vars.get("DEVNAME").and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Apply the body of an IS or INFO subnegotiation (everything after the command byte)
+    pub fn update(&mut self, body: &[u8]) {
+        let mut current: Option<(u8, Vec<u8>)> = None;
+        let mut value: Option<Vec<u8>> = None;
+
+        let mut bytes = unescape_iac(body).into_iter();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                kind::VAR | kind::USERVAR => {
+                    if let Some((kind, name)) = current.take() {
+                        self.set(kind, name, value.take());
+                    }
+                    current = Some((byte, Vec::new()));
+                    value = None;
+                }
+                kind::VALUE => value = Some(Vec::new()),
+                _ => {
+                    let byte = if byte == kind::ESC {
+                        match bytes.next() {
+                            Some(byte) => byte,
+                            None => break,
+                        }
+                    } else {
+                        byte
+                    };
+                    match (&mut current, &mut value) {
+                        (Some(_), Some(value)) => value.push(byte),
+                        (Some((_, name)), None) => name.push(byte),
+                        // Junk before the first variable
+                        (None, _) => {}
+                    }
+                }
+            }
+        }
+        if let Some((kind, name)) = current {
+            self.set(kind, name, value);
+        }
+    }
+
+    fn set(&mut self, kind: u8, name: Vec<u8>, value: Option<Vec<u8>>) {
+        let count = self.vars.len() + self.user_vars.len();
+        let map = if kind == kind::VAR { &mut self.vars } else { &mut self.user_vars };
+        let name = String::from_utf8_lossy(&name).into_owned();
+        match value {
+            Some(value) => {
+                let too_big = name.len() > MAX_VARIABLE_SIZE || value.len() > MAX_VARIABLE_SIZE;
+                let too_many = count >= MAX_VARIABLES && !map.contains_key(&name);
+                if !too_big && !too_many {
+                    map.insert(name, value);
+                }
+            }
+            None => { map.remove(&name); }
+        }
+    }
+}
+
+/// Ask for every well-known and user variable
+pub fn send_all() -> Vec<u8> {
+    vec![cmd::SEND, kind::VAR, kind::USERVAR]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_var(name: &str, value: &[u8]) -> Vec<u8> {
+        let mut body = vec![kind::USERVAR];
+        body.extend_from_slice(name.as_bytes());
+        body.push(kind::VALUE);
+        body.extend_from_slice(value);
+        body
+    }
+
+    #[test]
+    fn parses_variables() {
+        let mut env = Environment::default();
+        let mut body = vec![kind::VAR];
+        body.extend_from_slice(b"USER");
+        body.push(kind::VALUE);
+        body.extend_from_slice(b"ALICE");
+        body.extend(user_var("DEVNAME", b"TERM0001"));
+        env.update(body.as_slice());
+        assert_eq!(env.user(), Some("ALICE"));
+        assert_eq!(env.devname(), Some("TERM0001"));
+    }
+
+    #[test]
+    fn caps_variable_count() {
+        let mut env = Environment::default();
+        for n in 0..MAX_VARIABLES * 2 {
+            env.update(user_var(&format!("VAR{}", n), b"x").as_slice());
+        }
+        assert_eq!(env.user_vars.len(), MAX_VARIABLES);
+
+        // Variables already set can still be changed
+        env.update(user_var("VAR0", b"y").as_slice());
+        assert_eq!(env.user_vars["VAR0"], b"y");
+    }
+
+    #[test]
+    fn drops_oversized_variables() {
+        let mut env = Environment::default();
+        env.update(user_var("BIG", &[b'x'; MAX_VARIABLE_SIZE + 1]).as_slice());
+        env.update(user_var(&"N".repeat(MAX_VARIABLE_SIZE + 1), b"x").as_slice());
+        assert!(env.user_vars.is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+//! Logging off terminals that nobody is using
+
+use std::time::Duration;
+
+use snafu::Snafu;
+
+use crate::tn3270::screen::Address;
+use crate::tn3270::stream::{BufferAddressCalculator, FieldAttribute, WCC, WriteCommand, WriteCommandCode, WriteOrder};
+
+/// How long a terminal can go without the user pressing an AID key
+#[derive(Clone, Debug)]
+pub struct IdlePolicy {
+    pub timeout: Duration,
+    pub warning: Option<IdleWarning>,
+}
+
+impl IdlePolicy {
+    pub fn new(timeout: Duration) -> Self {
+        IdlePolicy { timeout, warning: None }
+    }
+
+    pub fn with_warning(mut self, warning: IdleWarning) -> Self {
+        self.warning = Some(warning);
+        self
+    }
+}
+
+/// A message written over whatever the terminal is showing shortly before it's logged off
+#[derive(Clone, Debug)]
+pub struct IdleWarning {
+    /// How long before the timeout the warning is shown
+    pub lead: Duration,
+    pub message: String,
+    /// Where the message goes. `None` puts it at the start of the bottom line.
+    pub position: Option<Address>,
+}
+
+impl IdleWarning {
+    pub fn new(lead: Duration, message: impl Into<String>) -> Self {
+        IdleWarning { lead, message: message.into(), position: None }
+    }
+
+    pub fn at(mut self, row: u16, col: u16) -> Self {
+        self.position = Some(Address { row, col });
+        self
+    }
+
+    /// A Write (rather than an Erase/Write), so the rest of the screen and anything the
+    /// user has typed are left alone
+    pub(crate) fn write_command(&self, size: BufferAddressCalculator) -> WriteCommand {
+        let Address { row, col } = self.position
+            .unwrap_or(Address { row: size.height.saturating_sub(1), col: 0 });
+        WriteCommand {
+            command: WriteCommandCode::Write,
+            wcc: WCC::SOUND_ALARM,
+            orders: vec![
+                WriteOrder::SetBufferAddress(size.encode_address(row, col)),
+                WriteOrder::StartField(FieldAttribute::PROTECTED | FieldAttribute::INTENSE_SELECTOR_PEN_DETECTABLE),
+                WriteOrder::SendText(self.message.clone()),
+            ],
+        }
+    }
+}
+
+/// Returned (inside a `std::io::Error`) by a session's receive methods once the terminal
+/// has been idle for longer than its [`IdlePolicy`] allows. Use
+/// [`IdleError::from_io_error`] to recognize it.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum IdleError {
+    #[snafu(display("The terminal was idle for {:?}", idle_for))]
+    TimedOut {
+        /// How long it had been since the last AID
+        idle_for: Duration,
+    },
+}
+
+impl IdleError {
+    pub fn from_io_error(err: &std::io::Error) -> Option<&IdleError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<IdleError>())
+    }
+}
+
+impl From<IdleError> for std::io::Error {
+    fn from(err: IdleError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, err)
+    }
+}
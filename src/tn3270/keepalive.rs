@@ -0,0 +1,58 @@
+//! Probing idle clients, so that ones that have silently disappeared (e.g., behind a NAT
+//! that dropped the connection) are noticed
+
+use std::time::Duration;
+
+use snafu::Snafu;
+
+/// What to send to an idle client
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeepaliveProbe {
+    /// Telnet NOP. Clients don't answer it, so a dead client is only noticed once the
+    /// connection reports an error.
+    Nop,
+    /// DO TIMING-MARK, which clients answer with WILL or WONT
+    TimingMark,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Keepalive {
+    /// How long the client can be quiet before it's probed
+    pub interval: Duration,
+    pub probe: KeepaliveProbe,
+    /// How many TIMING-MARK probes can go unanswered before the client is given up on
+    pub max_missed: u32,
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, probe: KeepaliveProbe) -> Self {
+        Keepalive { interval, probe, max_missed: 3 }
+    }
+
+    pub fn with_max_missed(mut self, max_missed: u32) -> Self {
+        self.max_missed = max_missed;
+        self
+    }
+}
+
+/// Returned (inside a `std::io::Error`) by a session's receive methods once the client
+/// stops responding. Use [`KeepaliveError::from_io_error`] to
+/// recognize it.
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum KeepaliveError {
+    #[snafu(display("The client stopped responding (last heard from {:?} ago)", silent_for))]
+    DeadPeer { silent_for: Duration },
+}
+
+impl KeepaliveError {
+    pub fn from_io_error(err: &std::io::Error) -> Option<&KeepaliveError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<KeepaliveError>())
+    }
+}
+
+impl From<KeepaliveError> for std::io::Error {
+    fn from(err: KeepaliveError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, err)
+    }
+}
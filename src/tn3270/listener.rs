@@ -0,0 +1,423 @@
+//! Ready-made ways for a server to get its connections: listening on a TCP or Unix domain
+//! socket, taking the sockets systemd passes with socket activation, or serving the single
+//! connection inetd passes on stdin and stdout. Whichever is used, the connections come
+//! out as [`Connection`]s, which any `Session` can run over.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use crate::tn3270::transport::Transport;
+
+/// Where connections come from
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    /// The one connection on stdin and stdout, until it has been accepted
+    Stdio { accepted: bool },
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    /// Listen on a Unix domain socket at `path`, replacing the socket left behind by a
+    /// previous run, if there is one. Fails with
+    /// [`AddrInUse`](std::io::ErrorKind::AddrInUse) if something is still listening on it.
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        use std::io::ErrorKind;
+        use std::os::unix::fs::FileTypeExt;
+        let path = path.as_ref();
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            // Only a socket nobody is listening on is left over
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(std::io::Error::new(ErrorKind::AddrInUse, "another server is listening on the socket")),
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path)?,
+                Err(err) => return Err(err),
+            }
+        }
+        UnixListener::bind(path).map(Listener::Unix)
+    }
+
+    /// The listening sockets passed by systemd socket activation (`LISTEN_FDS`), in the
+    /// order they're configured. Empty if the process wasn't socket-activated, or if an
+    /// earlier call already took them. Fails with
+    /// [`InvalidInput`](std::io::ErrorKind::InvalidInput), without taking any of them, if
+    /// one of them isn't a listening stream socket or `LISTEN_FDS` is out of range.
+    ///
+    /// The variables are left set, since changing the environment isn't safe once other
+    /// threads are running. A server that starts child processes should clear
+    /// `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` itself, while it's still
+    /// single-threaded, so that the children don't pick the sockets up as well.
+    ///
+    /// This is for `Accept=no` sockets; with `Accept=yes`, systemd starts a process per
+    /// connection, which should use [`Listener::stdio`] with `StandardInput=socket`.
+    #[cfg(unix)]
+    pub fn systemd() -> std::io::Result<Vec<Self>> {
+        use std::os::unix::io::{BorrowedFd, FromRawFd, OwnedFd, RawFd};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        /// The first descriptor systemd passes
+        const LISTEN_FDS_START: RawFd = 3;
+        /// Set by the first call, since the environment still names the descriptors
+        /// afterwards and each may only be owned once
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+
+        let for_us = std::env::var("LISTEN_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+        let count = std::env::var("LISTEN_FDS").ok()
+            .and_then(|count| count.parse::<RawFd>().ok())
+            .filter(|_| for_us)
+            .unwrap_or(0);
+        if count <= 0 {
+            return Ok(Vec::new());
+        }
+        let end = LISTEN_FDS_START.checked_add(count).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("LISTEN_FDS of {} names more descriptors than there can be", count),
+        ))?;
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(Vec::new());
+        }
+        let fds = LISTEN_FDS_START..end;
+
+        // Check them all before owning any, so that a bad one doesn't close the rest
+        for fd in fds.clone() {
+            // SAFETY: systemd hands these descriptors to this process (LISTEN_PID was
+            // checked above), and they stay open while they're checked
+            let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+            let checked = is_listening_stream(borrowed).and_then(|listening| if listening {
+                Ok(())
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("descriptor {} from systemd isn't a listening stream socket", fd),
+                ))
+            });
+            if let Err(error) = checked {
+                // Nothing was taken, so a later call may try again
+                TAKEN.store(false, Ordering::SeqCst);
+                return Err(error);
+            }
+        }
+
+        fds.map(|fd| {
+            // SAFETY: as above, and TAKEN ensures nothing else in this process takes
+            // ownership of them
+            let tcp = TcpListener::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            if tcp.local_addr().is_ok() {
+                return Ok(Listener::Tcp(tcp));
+            }
+            let unix = UnixListener::from(OwnedFd::from(tcp));
+            unix.local_addr()?;
+            Ok(Listener::Unix(unix))
+        })
+        .collect()
+    }
+
+    /// The single connection on stdin and stdout, as passed by inetd
+    pub fn stdio() -> Self {
+        Listener::Stdio { accepted: false }
+    }
+
+    /// Wait for the next connection. Returns `None` once a stdio listener has handed over
+    /// its connection, since there won't be another.
+    pub fn accept(&mut self) -> Option<std::io::Result<Connection>> {
+        match self {
+            Listener::Tcp(listener) => Some(listener.accept().map(|(stream, _)| Connection::Tcp(stream))),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Some(listener.accept().map(|(stream, _)| Connection::Unix(stream))),
+            Listener::Stdio { accepted: true } => None,
+            Listener::Stdio { accepted } => {
+                *accepted = true;
+                Some(Connection::stdio())
+            }
+        }
+    }
+
+    /// Every connection, as they arrive
+    pub fn incoming(&mut self) -> impl Iterator<Item = std::io::Result<Connection>> + '_ {
+        std::iter::from_fn(move || self.accept())
+    }
+}
+
+/// Whether `fd` is a stream socket that's listening for connections, and so something
+/// that can be accepted from
+#[cfg(unix)]
+fn is_listening_stream(fd: std::os::unix::io::BorrowedFd<'_>) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let option = |name: libc::c_int| -> std::io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are an int and its size, which is what these options take
+        let result = unsafe {
+            libc::getsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+        };
+        if result == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(value)
+    };
+    Ok(option(libc::SO_TYPE)? == libc::SOCK_STREAM && option(libc::SO_ACCEPTCONN)? != 0)
+}
+
+/// A connection from any kind of [`Listener`]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Stdio(StdioStream),
+}
+
+impl Connection {
+    /// inetd passes the client's socket itself as stdin and stdout, which is used directly
+    /// so that timeouts and addresses work as usual. Anything else (a pipe, say) is read
+    /// and written through [`StdioStream`].
+    fn stdio() -> std::io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            use std::os::unix::io::{AsFd, OwnedFd};
+
+            let fd = std::io::stdin().as_fd().try_clone_to_owned()?;
+            let file = std::fs::File::from(fd);
+            if file.metadata()?.file_type().is_socket() {
+                let tcp = TcpStream::from(OwnedFd::from(file));
+                if tcp.local_addr().is_ok() {
+                    return Ok(Connection::Tcp(tcp));
+                }
+                return Ok(Connection::Unix(UnixStream::from(OwnedFd::from(tcp))));
+            }
+        }
+        Ok(Connection::Stdio(StdioStream::new()))
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Stdio(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Stdio(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Stdio(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Transport for Connection {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => Transport::set_read_timeout(stream, timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Transport::set_read_timeout(stream, timeout),
+            Connection::Stdio(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => Transport::set_nonblocking(stream, nonblocking),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Transport::set_nonblocking(stream, nonblocking),
+            Connection::Stdio(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => Transport::peer_addr(stream),
+            _ => None,
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => Transport::local_addr(stream),
+            _ => None,
+        }
+    }
+}
+
+/// stdin and stdout as a transport. Pipes don't support read timeouts, so stdin is read
+/// on a thread of its own, and the session waits on that instead.
+pub struct StdioStream {
+    input: Receiver<std::io::Result<Vec<u8>>>,
+    pending: VecDeque<u8>,
+    output: std::io::Stdout,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+impl StdioStream {
+    fn new() -> Self {
+        let (sender, input) = channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin();
+            loop {
+                let mut buf = vec![0; 4096];
+                let result = stdin.read(buf.as_mut_slice()).map(|len| {
+                    buf.truncate(len);
+                    buf
+                });
+                // Stop at the end of input or an error, or once the stream is dropped
+                let last = !matches!(result, Ok(ref data) if !data.is_empty());
+                if sender.send(result).is_err() || last {
+                    return;
+                }
+            }
+        });
+        StdioStream {
+            input,
+            pending: VecDeque::new(),
+            output: std::io::stdout(),
+            read_timeout: None,
+            nonblocking: false,
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
+
+impl Read for StdioStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            // Once the reader thread has gone, the input has ended
+            let received = if self.nonblocking {
+                match self.input.try_recv() {
+                    Ok(received) => received,
+                    Err(TryRecvError::Empty) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                    Err(TryRecvError::Disconnected) => return Ok(0),
+                }
+            } else if let Some(timeout) = self.read_timeout {
+                match self.input.recv_timeout(timeout) {
+                    Ok(received) => received,
+                    Err(RecvTimeoutError::Timeout) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                }
+            } else {
+                match self.input.recv() {
+                    Ok(received) => received,
+                    Err(_) => return Ok(0),
+                }
+            };
+            self.pending.extend(received?);
+        }
+
+        let len = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for StdioStream {
+    /// stdout is line buffered, and sessions don't flush, so every write goes straight out
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.output.write(buf)?;
+        self.output.flush()?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tn3270s-{}-{}.sock", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn listening_stream_sockets() {
+        use std::os::unix::io::AsFd;
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(is_listening_stream(tcp.as_fd()).unwrap());
+
+        let connected = TcpStream::connect(tcp.local_addr().unwrap()).unwrap();
+        assert!(!is_listening_stream(connected.as_fd()).unwrap());
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(!is_listening_stream(udp.as_fd()).unwrap());
+    }
+
+    #[test]
+    fn bind_unix_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind_unix(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_unix_leaves_live_socket() {
+        let path = socket_path("live");
+        let live = UnixListener::bind(&path).unwrap();
+
+        let err = Listener::bind_unix(&path).err().expect("replaced a live socket");
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        // The original server can still be reached
+        assert!(UnixStream::connect(&path).is_ok());
+        assert!(live.accept().is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn systemd_rejects_impossible_descriptor_count() {
+        // No other test reads these
+        std::env::set_var("LISTEN_PID", std::process::id().to_string());
+        std::env::set_var("LISTEN_FDS", i32::MAX.to_string());
+        let result = Listener::systemd();
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_PID");
+        assert_eq!(result.err().map(|err| err.kind()), Some(std::io::ErrorKind::InvalidInput));
+    }
+}
@@ -0,0 +1,374 @@
+//! Assignment of LU (device) names to TN3270E sessions
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use snafu::{ensure, Snafu};
+
+use crate::tn3270::tn3270e::ReasonCode;
+
+/// A source of LU names for TN3270E sessions. A pool is usually shared between every
+/// session accepted by a listener.
+pub trait LuPool: Send + Sync {
+    /// Assign an LU to a device of type `device_type`. `requested` is the name the client
+    /// asked for with `CONNECT`, if any.
+    fn assign(&self, device_type: &str, requested: Option<&str>) -> Result<String, ReasonCode>;
+
+    /// Assign the printer LU that is paired with the terminal LU `terminal`, in response to
+    /// an `ASSOCIATE` request.
+    fn associate(&self, device_type: &str, terminal: &str) -> Result<String, ReasonCode>;
+
+    /// Return a name to the pool once its session ends
+    fn release(&self, name: &str);
+}
+
+/// An LU name that is returned to its pool when dropped
+pub struct LuLease {
+    pool: Arc<dyn LuPool>,
+    name: String,
+}
+
+impl LuLease {
+    pub fn new(pool: Arc<dyn LuPool>, name: String) -> Self {
+        LuLease { pool, name }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+}
+
+impl Drop for LuLease {
+    fn drop(&mut self) {
+        self.pool.release(self.name.as_str());
+    }
+}
+
+/// Used when no pool has been configured: any valid LU name the client asks for is accepted
+/// as long as no other session in the process holds it, generic requests get a made-up name,
+/// and printer association is refused. Every `AdHocLuPool` shares the same set of names in use,
+/// since `Session::new` creates one per session.
+pub struct AdHocLuPool;
+
+static NEXT_DEVICE: AtomicUsize = AtomicUsize::new(1);
+/// Names held by sessions using an [`AdHocLuPool`]
+static AD_HOC_IN_USE: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// The longest LU name SNA allows
+pub const MAX_LU_NAME_LEN: usize = 8;
+
+/// Whether `name` is a syntactically valid LU name: up to eight letters, digits, `@`, `#`
+/// or `$`
+pub fn is_valid_lu_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_LU_NAME_LEN
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"@#$".contains(&b))
+}
+
+impl LuPool for AdHocLuPool {
+    fn assign(&self, _device_type: &str, requested: Option<&str>) -> Result<String, ReasonCode> {
+        let mut in_use = AD_HOC_IN_USE.lock().unwrap();
+        let name = match requested {
+            Some(requested) if !is_valid_lu_name(requested) => return Err(ReasonCode::InvName),
+            Some(requested) if in_use.contains(requested) => return Err(ReasonCode::DeviceInUse),
+            Some(requested) => requested.to_owned(),
+            // Wrap around so generated names stay valid too
+            None => (0..10000)
+                .map(|_| format!("TERM{:04}", NEXT_DEVICE.fetch_add(1, Ordering::Relaxed) % 10000))
+                .find(|name| !in_use.contains(name))
+                .ok_or(ReasonCode::DeviceInUse)?,
+        };
+        in_use.insert(name.clone());
+        Ok(name)
+    }
+
+    fn associate(&self, _device_type: &str, _terminal: &str) -> Result<String, ReasonCode> {
+        Err(ReasonCode::UnsupportedReq)
+    }
+
+    fn release(&self, name: &str) {
+        AD_HOC_IN_USE.lock().unwrap().remove(name);
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum PatternError {
+    #[snafu(display("{:?} doesn't produce valid LU names", pattern))]
+    InvalidPattern { pattern: String },
+    #[snafu(display("Printer pattern {:?} doesn't have as many digits as its terminal pattern {:?}", printer, terminal))]
+    MismatchedPrinter { terminal: String, printer: String },
+}
+
+/// A pattern such as `TCP00###`, where each `#` stands for one decimal digit
+#[derive(Clone, Debug)]
+pub struct NamePattern {
+    pattern: String,
+    digits: u32,
+}
+
+impl NamePattern {
+    /// Fails unless every name the pattern produces is a valid LU name
+    pub fn new(pattern: &str) -> Result<Self, PatternError> {
+        ensure!(is_valid_lu_name(pattern.replace('#', "0").as_str()), InvalidPattern { pattern });
+        Ok(NamePattern {
+            pattern: pattern.to_ascii_uppercase(),
+            digits: pattern.chars().filter(|&ch| ch == '#').count() as u32,
+        })
+    }
+
+    /// The number of names this pattern can produce
+    pub fn capacity(&self) -> usize {
+        // No more than eight digits, so this can't overflow
+        10usize.pow(self.digits)
+    }
+
+    /// The name with number `index`, or `None` if the pattern doesn't have enough digits
+    /// for it
+    pub fn name(&self, index: usize) -> Option<String> {
+        if index >= self.capacity() {
+            return None;
+        }
+        let mut digits = format!("{:0width$}", index, width = self.digits as usize).into_bytes().into_iter();
+        Some(self.pattern.chars()
+            .map(|ch| if ch == '#' { digits.next().map(char::from).unwrap_or('0') } else { ch })
+            .collect())
+    }
+
+    /// If `name` was produced by this pattern, return its index
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        if name.len() != self.pattern.len() {
+            return None;
+        }
+        let mut index: usize = 0;
+        for (pch, nch) in self.pattern.chars().zip(name.chars()) {
+            if pch == '#' {
+                index = index.checked_mul(10)?.checked_add(nch.to_digit(10)? as usize)?;
+            } else if pch != nch.to_ascii_uppercase() {
+                return None;
+            }
+        }
+        Some(index)
+    }
+}
+
+/// An in-memory pool built from name patterns. Terminals are assigned names from the
+/// terminal patterns and printers (3287s) from the printer patterns; a printer pattern is
+/// paired with the terminal pattern at the same position, and `ASSOCIATE` maps a terminal
+/// name onto the printer name with the same number.
+#[derive(Default)]
+pub struct MemoryLuPool {
+    terminals: Vec<NamePattern>,
+    printers: Vec<NamePattern>,
+    state: Mutex<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    in_use: HashSet<String>,
+    /// Where the search for a free name resumes, for each terminal pattern
+    terminal_cursors: Vec<usize>,
+    /// The same for each printer pattern
+    printer_cursors: Vec<usize>,
+}
+
+fn is_printer(device_type: &str) -> bool {
+    device_type.starts_with("IBM-3287")
+}
+
+impl MemoryLuPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_terminals(mut self, pattern: &str) -> Result<Self, PatternError> {
+        self.terminals.push(NamePattern::new(pattern)?);
+        self.state.get_mut().unwrap().terminal_cursors.push(0);
+        self.check_pair(self.terminals.len() - 1)?;
+        Ok(self)
+    }
+
+    /// Add a printer pattern, paired with the terminal pattern at the same position. Fails
+    /// if the two don't have the same number of digits, since `ASSOCIATE` couldn't map
+    /// every terminal name onto its own printer.
+    pub fn with_printers(mut self, pattern: &str) -> Result<Self, PatternError> {
+        self.printers.push(NamePattern::new(pattern)?);
+        self.state.get_mut().unwrap().printer_cursors.push(0);
+        self.check_pair(self.printers.len() - 1)?;
+        Ok(self)
+    }
+
+    fn check_pair(&self, position: usize) -> Result<(), PatternError> {
+        match (self.terminals.get(position), self.printers.get(position)) {
+            (Some(terminal), Some(printer)) if terminal.digits != printer.digits => MismatchedPrinter {
+                terminal: terminal.pattern.as_str(),
+                printer: printer.pattern.as_str(),
+            }.fail(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Names currently assigned to a session
+    pub fn in_use(&self) -> Vec<String> {
+        self.state.lock().unwrap().in_use.iter().cloned().collect()
+    }
+}
+
+impl PoolState {
+    /// Find a free name from `patterns`, picking up each one's search where the last left
+    /// off. Only as many names are tried per pattern as are in use, plus one, since at
+    /// least one of those has to be free if the pattern isn't full.
+    fn next_free(&mut self, patterns: &[NamePattern], printer: bool) -> Option<String> {
+        let cursors = if printer { &mut self.printer_cursors } else { &mut self.terminal_cursors };
+        let tries = self.in_use.len().saturating_add(1);
+        for (pattern, cursor) in patterns.iter().zip(cursors.iter_mut()) {
+            let capacity = pattern.capacity();
+            for offset in 0..tries.min(capacity) {
+                let index = (*cursor + offset) % capacity;
+                let name = pattern.name(index)?;
+                if !self.in_use.contains(&name) {
+                    *cursor = (index + 1) % capacity;
+                    return Some(name);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl LuPool for MemoryLuPool {
+    fn assign(&self, device_type: &str, requested: Option<&str>) -> Result<String, ReasonCode> {
+        let printer = is_printer(device_type);
+        let patterns = if printer { &self.printers } else { &self.terminals };
+        let mut state = self.state.lock().unwrap();
+        let name = if let Some(requested) = requested {
+            let requested = requested.to_ascii_uppercase();
+            if !patterns.iter().any(|pattern| pattern.index_of(&requested).is_some()) {
+                let other = if printer { &self.terminals } else { &self.printers };
+                return Err(if other.iter().any(|pattern| pattern.index_of(&requested).is_some()) {
+                    ReasonCode::TypeNameError
+                } else {
+                    ReasonCode::InvName
+                });
+            }
+            if state.in_use.contains(&requested) {
+                return Err(ReasonCode::DeviceInUse);
+            }
+            requested
+        } else {
+            state.next_free(patterns, printer).ok_or(ReasonCode::DeviceInUse)?
+        };
+        state.in_use.insert(name.clone());
+        Ok(name)
+    }
+
+    fn associate(&self, device_type: &str, terminal: &str) -> Result<String, ReasonCode> {
+        if !is_printer(device_type) {
+            return Err(ReasonCode::InvDeviceType);
+        }
+        let terminal = terminal.to_ascii_uppercase();
+        let mut state = self.state.lock().unwrap();
+        if !state.in_use.contains(&terminal) {
+            return Err(ReasonCode::InvAssociate);
+        }
+        let name = self.terminals.iter()
+            .zip(self.printers.iter())
+            .find_map(|(tpat, ppat)| tpat.index_of(&terminal).and_then(|index| ppat.name(index)))
+            .ok_or(ReasonCode::ConnPartner)?;
+        if state.in_use.contains(&name) {
+            return Err(ReasonCode::DeviceInUse);
+        }
+        state.in_use.insert(name.clone());
+        Ok(name)
+    }
+
+    fn release(&self, name: &str) {
+        self.state.lock().unwrap().in_use.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_make_valid_names() {
+        assert_eq!(NamePattern::new("TCP00###").unwrap().capacity(), 1000);
+        for pattern in &["", "TCP00####", "TCP 0##", "TCP-0##"] {
+            assert!(matches!(NamePattern::new(pattern), Err(PatternError::InvalidPattern { .. })), "{}", pattern);
+        }
+        let pattern = NamePattern::new("TCP##").unwrap();
+        assert_eq!(pattern.name(99), Some("TCP99".to_owned()));
+        assert_eq!(pattern.name(100), None);
+    }
+
+    #[test]
+    fn printer_patterns_match_their_terminals() {
+        let result = MemoryLuPool::new().with_terminals("TCP####").unwrap().with_printers("PRT##");
+        assert!(matches!(result, Err(PatternError::MismatchedPrinter { .. })));
+        let result = MemoryLuPool::new().with_printers("PRT##").unwrap().with_terminals("TCP####");
+        assert!(matches!(result, Err(PatternError::MismatchedPrinter { .. })));
+        assert!(MemoryLuPool::new().with_terminals("TCP####").unwrap().with_printers("P####").is_ok());
+    }
+
+    #[test]
+    fn memory_pool_assigns_and_releases() {
+        let pool = MemoryLuPool::new().with_terminals("TCP00###").unwrap().with_printers("PRT00###").unwrap();
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Ok("TCP00000".to_owned()));
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Ok("TCP00001".to_owned()));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("tcp00001")), Err(ReasonCode::DeviceInUse));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("PRT00002")), Err(ReasonCode::TypeNameError));
+        assert_eq!(pool.associate("IBM-3287-1", "TCP00001"), Ok("PRT00001".to_owned()));
+        pool.release("TCP00001");
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("TCP00001")), Ok("TCP00001".to_owned()));
+    }
+
+    #[test]
+    fn memory_pool_resumes_where_it_left_off() {
+        let pool = MemoryLuPool::new().with_terminals("TCP#").unwrap();
+        for n in 0..3 {
+            assert_eq!(pool.assign("IBM-3278-2-E", None), Ok(format!("TCP{}", n)));
+        }
+        pool.release("TCP1");
+        // Carries on past the last name handed out, then wraps around to the free one
+        for n in 3..10 {
+            assert_eq!(pool.assign("IBM-3278-2-E", None), Ok(format!("TCP{}", n)));
+        }
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Ok("TCP1".to_owned()));
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Err(ReasonCode::DeviceInUse));
+    }
+
+    #[test]
+    fn memory_pool_hands_out_literal_names() {
+        let pool = MemoryLuPool::new().with_terminals("CONSOLE").unwrap().with_terminals("TCP0#").unwrap();
+        assert_eq!(NamePattern::new("CONSOLE").unwrap().capacity(), 1);
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Ok("CONSOLE".to_owned()));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("CONSOLE")), Err(ReasonCode::DeviceInUse));
+        assert_eq!(pool.assign("IBM-3278-2-E", None), Ok("TCP00".to_owned()));
+        pool.release("CONSOLE");
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("console")), Ok("CONSOLE".to_owned()));
+    }
+
+    #[test]
+    fn ad_hoc_pool_refuses_names_in_use() {
+        let first = AdHocLuPool;
+        let second = AdHocLuPool;
+        assert_eq!(first.assign("IBM-3278-2-E", Some("ADHOCLU1")), Ok("ADHOCLU1".to_owned()));
+        assert_eq!(second.assign("IBM-3278-2-E", Some("ADHOCLU1")), Err(ReasonCode::DeviceInUse));
+        first.release("ADHOCLU1");
+        assert_eq!(second.assign("IBM-3278-2-E", Some("ADHOCLU1")), Ok("ADHOCLU1".to_owned()));
+        second.release("ADHOCLU1");
+    }
+
+    #[test]
+    fn ad_hoc_pool_refuses_invalid_names() {
+        let pool = AdHocLuPool;
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("")), Err(ReasonCode::InvName));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("TOOLONGLU")), Err(ReasonCode::InvName));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("LU\x1b[2J")), Err(ReasonCode::InvName));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("LU 1")), Err(ReasonCode::InvName));
+        assert_eq!(pool.assign("IBM-3278-2-E", Some("ADHOC$#@")), Ok("ADHOC$#@".to_owned()));
+        pool.release("ADHOC$#@");
+    }
+}
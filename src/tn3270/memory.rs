@@ -0,0 +1,140 @@
+//! An in-memory transport, mostly useful for testing applications without sockets
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::tn3270::transport::Transport;
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of a duplex stream
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+struct Endpoint {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// One end of an in-memory duplex stream created by [`duplex`]. Clones share the same end;
+/// the stream is closed once every clone of either end is dropped, or [`shutdown`] is
+/// called.
+///
+/// [`shutdown`]: MemoryStream::shutdown
+#[derive(Clone)]
+pub struct MemoryStream {
+    endpoint: Arc<Endpoint>,
+    read_timeout: Option<Duration>,
+    nonblocking: bool,
+}
+
+/// Create a connected pair of in-memory streams
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    let a_to_b = Arc::new(Pipe::default());
+    let b_to_a = Arc::new(Pipe::default());
+    let a = MemoryStream::new(Endpoint { incoming: b_to_a.clone(), outgoing: a_to_b.clone() });
+    let b = MemoryStream::new(Endpoint { incoming: a_to_b, outgoing: b_to_a });
+    (a, b)
+}
+
+impl MemoryStream {
+    fn new(endpoint: Endpoint) -> Self {
+        MemoryStream {
+            endpoint: Arc::new(endpoint),
+            read_timeout: None,
+            nonblocking: false,
+        }
+    }
+
+    /// Close both directions of the stream. Pending data can still be read by either end.
+    pub fn shutdown(&self) {
+        self.endpoint.incoming.close();
+        self.endpoint.outgoing.close();
+    }
+
+    /// Whether reads fail with `WouldBlock` rather than waiting for data
+    pub fn is_nonblocking(&self) -> bool {
+        self.nonblocking
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pipe = &self.endpoint.incoming;
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = pipe.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed {
+            if self.nonblocking {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            state = match deadline {
+                None => pipe.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(std::io::ErrorKind::WouldBlock.into());
+                    }
+                    pipe.readable.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+
+        let len = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let pipe = &self.endpoint.outgoing;
+        let mut state = pipe.state.lock().unwrap();
+        if state.closed {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        pipe.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+}
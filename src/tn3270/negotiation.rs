@@ -0,0 +1,152 @@
+//! What a session insists on while negotiating with a client, and how negotiation can fail
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use snafu::Snafu;
+
+use crate::tn3270::proxy::{ProxyError, ProxyHeader};
+use crate::tn3270::terminal::{MostCapable, TerminalPreference};
+
+/// The telnet options negotiated when a session starts
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TelnetOption {
+    Tn3270e,
+    TerminalType,
+    Binary,
+    EndOfRecord,
+    NewEnviron,
+    /// Offered according to the transport's
+    /// [`StartTlsPolicy`](crate::tn3270::transport::StartTlsPolicy) rather than the
+    /// negotiation policy, so this only turns up in errors
+    StartTls,
+}
+
+impl fmt::Display for TelnetOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TelnetOption::Tn3270e => "TN3270E",
+            TelnetOption::TerminalType => "TERMINAL-TYPE",
+            TelnetOption::Binary => "BINARY",
+            TelnetOption::EndOfRecord => "END-OF-RECORD",
+            TelnetOption::NewEnviron => "NEW-ENVIRON",
+            TelnetOption::StartTls => "START-TLS",
+        })
+    }
+}
+
+/// What to do when the client refuses a required option or has no acceptable terminal
+/// type
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RefusalAction {
+    /// Hang up without saying anything
+    Disconnect,
+    /// Tell the client why in plain text, then hang up
+    Explain,
+}
+
+#[derive(Clone)]
+pub struct NegotiationPolicy {
+    /// How long the client has to finish negotiating
+    pub deadline: Duration,
+    /// Options the client must agree to. TERMINAL-TYPE, BINARY and END-OF-RECORD are only
+    /// negotiated for plain TN3270, since TN3270E takes their place.
+    pub required: Vec<TelnetOption>,
+    /// Options that are offered but can be refused. Anything in neither list isn't offered
+    /// at all.
+    pub optional: Vec<TelnetOption>,
+    /// Which terminal types are accepted, and which of them are preferred
+    pub terminal_types: Arc<dyn TerminalPreference>,
+    pub on_refusal: RefusalAction,
+    /// Serve clients whose terminal type isn't a 3270, or that won't say, as NVT
+    /// (VT100-style) terminals instead of failing negotiation. They are presented as a
+    /// 24x80 model 2 whatever their window size; a smaller window only shows the top left
+    /// of the screen.
+    pub nvt_fallback: bool,
+    /// Expect a PROXY protocol header (see [`proxy`](crate::tn3270::proxy)) ahead of the
+    /// telnet stream, as sent by a load balancer, and fail negotiation without one
+    pub proxy_protocol: bool,
+    /// A PROXY header that was already read off the connection, such as one that came
+    /// ahead of a TLS handshake. Sessions take the client's address from it, and don't
+    /// read another even if `proxy_protocol` is set.
+    pub proxy_header: Option<ProxyHeader>,
+}
+
+impl NegotiationPolicy {
+    pub fn requires(&self, option: TelnetOption) -> bool {
+        self.required.contains(&option)
+    }
+
+    pub fn offers(&self, option: TelnetOption) -> bool {
+        self.requires(option) || self.optional.contains(&option)
+    }
+}
+
+impl Default for NegotiationPolicy {
+    /// TN3270E if the client supports it and plain TN3270 if not, with five seconds to
+    /// settle on either
+    fn default() -> Self {
+        NegotiationPolicy {
+            deadline: Duration::from_secs(5),
+            required: vec![TelnetOption::TerminalType, TelnetOption::Binary, TelnetOption::EndOfRecord],
+            optional: vec![TelnetOption::Tn3270e, TelnetOption::NewEnviron],
+            terminal_types: Arc::new(MostCapable),
+            on_refusal: RefusalAction::Disconnect,
+            nvt_fallback: false,
+            proxy_protocol: false,
+            proxy_header: None,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum NegotiationError {
+    #[snafu(display("The client refused {}", option))]
+    RefusedOption { option: TelnetOption },
+    #[snafu(display("None of the client's terminal types are accepted ({})", offered.join(", ")))]
+    UnsupportedTerminalType { offered: Vec<String> },
+    #[snafu(display("Negotiation timed out"))]
+    TimedOut,
+    #[snafu(display("The client hung up during negotiation"))]
+    PeerClosed,
+    #[snafu(display("Invalid PROXY protocol header: {}", source))]
+    Proxy { source: ProxyError },
+    /// The client asked for START_TLS, then failed the handshake
+    #[cfg(feature = "tls")]
+    #[snafu(display("START_TLS failed: {}", source))]
+    Tls { source: crate::tn3270::tls::TlsError },
+    #[snafu(display("I/O error during negotiation: {}", source))]
+    Io { source: std::io::Error },
+}
+
+impl From<std::io::Error> for NegotiationError {
+    /// Negotiation failures that had to pass through an I/O error are unwrapped again
+    fn from(err: std::io::Error) -> Self {
+        if !err.get_ref().is_some_and(|inner| inner.is::<NegotiationError>()) {
+            return NegotiationError::Io { source: err };
+        }
+        match err.into_inner().map(|inner| inner.downcast::<NegotiationError>()) {
+            Some(Ok(inner)) => *inner,
+            _ => unreachable!("the error was just checked to be a NegotiationError"),
+        }
+    }
+}
+
+impl From<NegotiationError> for std::io::Error {
+    fn from(err: NegotiationError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match err {
+            NegotiationError::Io { source } => return source,
+            NegotiationError::RefusedOption { .. } => ErrorKind::PermissionDenied,
+            NegotiationError::UnsupportedTerminalType { .. } => ErrorKind::Unsupported,
+            NegotiationError::TimedOut => ErrorKind::TimedOut,
+            NegotiationError::PeerClosed => ErrorKind::UnexpectedEof,
+            NegotiationError::Proxy { .. } => ErrorKind::InvalidData,
+            #[cfg(feature = "tls")]
+            NegotiationError::Tls { .. } => ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,782 @@
+//! A stand-in 3270 display for clients that are plain NVT (VT100-style) terminals.
+//!
+//! Write commands are applied to a virtual screen buffer, which is drawn with ANSI escape
+//! sequences. Keystrokes edit the unprotected fields locally, and AID keys produce the
+//! same inbound record a 3270 would have sent, so the application can't tell the
+//! difference. Enter is Enter, F1-F24 are PF1-PF24 (F13-F24 can also be typed as
+//! shift-F1-F12), Esc is Clear, and ^L redraws the screen.
+//!
+//! The virtual screen is always 24x80, since that's the size applications see. The
+//! window size the client reports with NAWS only limits how much of it is drawn.
+
+use std::time::Duration;
+
+use crate::tn3270::stream::{
+    AID, BufferAddressCalculator, Color, ExtendedFieldAttribute, FieldAttribute, Highlighting,
+    WCC, WriteCommand, WriteCommandCode, WriteOrder,
+};
+
+const ESC: u8 = 0x1B;
+const BEL: u8 = 0x07;
+/// The longest escape sequence a key is expected to send
+const MAX_ESCAPE_LEN: usize = 16;
+/// How long an ESC waits for the rest of an escape sequence before it's taken to be the
+/// Esc key on its own
+pub(crate) const ESCAPE_TIMEOUT: Duration = Duration::from_millis(250);
+
+const PF_KEYS: [AID; 24] = [
+    AID::PF1, AID::PF2, AID::PF3, AID::PF4, AID::PF5, AID::PF6,
+    AID::PF7, AID::PF8, AID::PF9, AID::PF10, AID::PF11, AID::PF12,
+    AID::PF13, AID::PF14, AID::PF15, AID::PF16, AID::PF17, AID::PF18,
+    AID::PF19, AID::PF20, AID::PF21, AID::PF22, AID::PF23, AID::PF24,
+];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Tab,
+    BackTab,
+    Backspace,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    Esc,
+    /// F1-F24
+    Function(u8),
+    Redraw,
+    Ignored,
+}
+
+/// How a field looks, from its field attribute and any extended attributes
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct FieldStyle {
+    attr: FieldAttribute,
+    color: Color,
+    highlighting: Highlighting,
+}
+
+impl FieldStyle {
+    fn new(attr: FieldAttribute) -> Self {
+        FieldStyle { attr, color: Color::Default, highlighting: Highlighting::Default }
+    }
+
+    fn apply(&mut self, attrs: &[ExtendedFieldAttribute]) {
+        for attr in attrs {
+            match *attr {
+                ExtendedFieldAttribute::FieldAttribute(attr) => self.attr = attr,
+                ExtendedFieldAttribute::ForegroundColor(color) => self.color = color,
+                ExtendedFieldAttribute::ExtendedHighlighting(highlighting) => self.highlighting = highlighting,
+                ExtendedFieldAttribute::AllAttributes => {
+                    self.color = Color::Default;
+                    self.highlighting = Highlighting::Default;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn is_protected(&self) -> bool {
+        self.attr.contains(FieldAttribute::PROTECTED)
+    }
+
+    fn is_hidden(&self) -> bool {
+        self.attr.contains(FieldAttribute::NON_DISPLAY)
+    }
+
+    /// The SGR sequence that draws the field
+    fn sgr(&self) -> String {
+        let mut params = vec!["0"];
+        if self.attr & FieldAttribute::NON_DISPLAY == FieldAttribute::INTENSE_SELECTOR_PEN_DETECTABLE {
+            params.push("1");
+        }
+        match self.highlighting {
+            Highlighting::Blink => params.push("5"),
+            Highlighting::Reverse => params.push("7"),
+            Highlighting::Underscore => params.push("4"),
+            // Show where the input fields are
+            Highlighting::Default | Highlighting::Normal if !self.is_protected() => params.push("4"),
+            Highlighting::Default | Highlighting::Normal => {}
+        }
+        params.push(match self.color {
+            Color::Blue | Color::DeepBlue => "34",
+            Color::Red => "31",
+            Color::Pink | Color::Purple => "35",
+            Color::Green | Color::PaleGreen => "32",
+            Color::Turquoise | Color::PaleTurquoise => "36",
+            Color::Yellow | Color::Orange => "33",
+            Color::NeutralFG | Color::White | Color::Grey => "37",
+            Color::Black => "30",
+            Color::Default | Color::NeutralBG => "39",
+        });
+        format!("\x1B[{}m", params.join(";"))
+    }
+}
+
+pub(crate) struct NvtTerminal {
+    size: BufferAddressCalculator,
+    /// The client's window as (width, height), if it told us with NAWS
+    window: Option<(u16, u16)>,
+    /// `'\0'` where nothing has been written
+    chars: Vec<char>,
+    /// The field attribute stored at each position, if any
+    fields: Vec<Option<FieldStyle>>,
+    cursor: u16,
+    /// Set by an AID key until the application restores the keyboard
+    locked: bool,
+    /// The start of an escape sequence that was split across reads
+    pending: Vec<u8>,
+    /// Telnet sends Return as CR LF or CR NUL, which is a single key
+    after_cr: bool,
+}
+
+impl NvtTerminal {
+    pub fn new(size: BufferAddressCalculator) -> Self {
+        let len = size.last_address() as usize + 1;
+        NvtTerminal {
+            size,
+            window: None,
+            chars: vec!['\0'; len],
+            fields: vec![None; len],
+            cursor: 0,
+            locked: false,
+            pending: Vec::new(),
+            after_cr: false,
+        }
+    }
+
+    fn len(&self) -> u16 {
+        self.size.last_address() + 1
+    }
+
+    fn next(&self, addr: u16) -> u16 {
+        (addr + 1) % self.len()
+    }
+
+    fn prev(&self, addr: u16) -> u16 {
+        (addr + self.len() - 1) % self.len()
+    }
+
+    /// The position of the attribute of the field that `addr` is in. `None` if the screen
+    /// has no fields.
+    fn field_start(&self, addr: u16) -> Option<u16> {
+        let mut pos = addr;
+        for _ in 0..self.len() {
+            if self.fields[pos as usize].is_some() {
+                return Some(pos);
+            }
+            pos = self.prev(pos);
+        }
+        None
+    }
+
+    fn style_at(&self, addr: u16) -> Option<FieldStyle> {
+        self.field_start(addr).and_then(|start| self.fields[start as usize])
+    }
+
+    /// Whether the user can type at `addr`
+    fn is_input(&self, addr: u16) -> bool {
+        self.fields[addr as usize].is_none()
+            && self.style_at(addr).is_some_and(|style| !style.is_protected())
+    }
+
+    /// Whether `addr` is the first position of an unprotected field
+    fn is_input_start(&self, addr: u16) -> bool {
+        self.fields[addr as usize].is_none()
+            && self.fields[self.prev(addr) as usize].is_some_and(|style| !style.is_protected())
+    }
+
+    /// The first position of the next unprotected field after `addr`
+    fn next_input(&self, addr: u16) -> Option<u16> {
+        let mut pos = addr;
+        for _ in 0..self.len() {
+            pos = self.next(pos);
+            if self.is_input_start(pos) {
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    /// The first position of the unprotected field before `addr`, which is the start of
+    /// the current field if `addr` isn't already there
+    fn prev_input(&self, addr: u16) -> Option<u16> {
+        let mut pos = addr;
+        for _ in 0..self.len() {
+            pos = self.prev(pos);
+            if self.is_input_start(pos) {
+                return Some(pos);
+            }
+        }
+        None
+    }
+
+    fn clear(&mut self) {
+        self.chars.iter_mut().for_each(|ch| *ch = '\0');
+        self.fields.iter_mut().for_each(|field| *field = None);
+        self.cursor = 0;
+    }
+
+    pub fn set_window(&mut self, width: u16, height: u16, output: &mut Vec<u8>) {
+        self.window = Some((width, height));
+        self.redraw(output);
+    }
+
+    /// Apply a write command from the application and redraw the screen
+    pub fn write(&mut self, command: &WriteCommand, output: &mut Vec<u8>) {
+        let mut addr = self.cursor;
+        match command.command {
+            WriteCommandCode::EraseWrite | WriteCommandCode::EraseWriteAlternate => {
+                self.clear();
+                addr = 0;
+            }
+            WriteCommandCode::EraseAllUnprotected => {
+                for pos in 0..self.len() {
+                    if self.is_input(pos) {
+                        self.chars[pos as usize] = '\0';
+                    }
+                }
+                self.reset_mdt();
+                self.cursor = self.next_input(self.len() - 1).unwrap_or(0);
+                self.locked = false;
+                self.redraw(output);
+                return;
+            }
+            WriteCommandCode::Write | WriteCommandCode::WriteStructuredField => {}
+        }
+        if command.wcc.contains(WCC::RESET_MDT) {
+            self.reset_mdt();
+        }
+
+        let mut cursor = None;
+        for order in command.orders.iter() {
+            match *order {
+                WriteOrder::SetBufferAddress(to) => addr = to % self.len(),
+                WriteOrder::StartField(attr) => {
+                    self.fields[addr as usize] = Some(FieldStyle::new(attr));
+                    self.chars[addr as usize] = '\0';
+                    addr = self.next(addr);
+                }
+                WriteOrder::StartFieldExtended(ref attrs) => {
+                    let mut style = FieldStyle::new(FieldAttribute::NONE);
+                    style.apply(attrs);
+                    self.fields[addr as usize] = Some(style);
+                    self.chars[addr as usize] = '\0';
+                    addr = self.next(addr);
+                }
+                WriteOrder::ModifyField(ref attrs) => {
+                    if let Some(style) = self.fields[addr as usize].as_mut() {
+                        style.apply(attrs);
+                    }
+                    addr = self.next(addr);
+                }
+                WriteOrder::InsertCursor(to) => cursor = Some(to % self.len()),
+                WriteOrder::ProgramTab => addr = self.next_input(addr).unwrap_or(0),
+                WriteOrder::RepeatToAddress(stop, ch) => {
+                    let stop = stop % self.len();
+                    loop {
+                        self.put(addr, ch);
+                        addr = self.next(addr);
+                        if addr == stop {
+                            break;
+                        }
+                    }
+                }
+                WriteOrder::EraseUnprotectedToAddress(stop) => {
+                    let stop = stop % self.len();
+                    loop {
+                        if self.is_input(addr) {
+                            self.chars[addr as usize] = '\0';
+                        }
+                        addr = self.next(addr);
+                        if addr == stop {
+                            break;
+                        }
+                    }
+                }
+                WriteOrder::GraphicEscape(_) => {
+                    self.put(addr, '?');
+                    addr = self.next(addr);
+                }
+                WriteOrder::SendText(ref text) => {
+                    for ch in text.chars() {
+                        self.put(addr, ch);
+                        addr = self.next(addr);
+                    }
+                }
+                WriteOrder::SetAttribute(_) => {}
+            }
+        }
+
+        if let Some(cursor) = cursor {
+            self.cursor = cursor;
+        }
+        // A 3270 user would tab to the first field; save an NVT user the trouble
+        if !self.is_input(self.cursor) {
+            self.cursor = self.next_input(self.cursor).unwrap_or(self.cursor);
+        }
+        if command.wcc.contains(WCC::KBD_RESTORE) {
+            self.locked = false;
+        }
+        if command.wcc.contains(WCC::SOUND_ALARM) {
+            output.push(BEL);
+        }
+        self.redraw(output);
+    }
+
+    fn put(&mut self, addr: u16, ch: char) {
+        self.fields[addr as usize] = None;
+        self.chars[addr as usize] = ch;
+    }
+
+    fn reset_mdt(&mut self) {
+        for style in self.fields.iter_mut().flatten() {
+            style.attr.remove(FieldAttribute::MODIFIED);
+        }
+    }
+
+    fn visible(&self) -> (u16, u16) {
+        let (width, height) = self.window.unwrap_or((self.size.width, self.size.height));
+        (width.min(self.size.width), height.min(self.size.height))
+    }
+
+    fn move_to(&self, addr: u16, output: &mut Vec<u8>) {
+        let (row, col) = self.size.decode_address(addr);
+        output.extend_from_slice(format!("\x1B[{};{}H", row + 1, col + 1).as_bytes());
+    }
+
+    /// The character to show at `addr` in a field drawn with `style`
+    fn glyph(&self, addr: u16, style: Option<FieldStyle>) -> char {
+        match (self.chars[addr as usize], style) {
+            _ if self.fields[addr as usize].is_some() => ' ',
+            (_, Some(style)) if style.is_hidden() => ' ',
+            ('\0', _) => ' ',
+            (ch, _) if ch.is_control() => ' ',
+            (ch, _) => ch,
+        }
+    }
+
+    fn redraw(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(b"\x1B[0m\x1B[H\x1B[2J");
+        let (width, height) = self.visible();
+        for row in 0..height {
+            self.move_to(self.size.encode_address(row, 0), output);
+            let mut style = self.style_at(self.size.encode_address(row, 0));
+            output.extend_from_slice(style.map_or_else(|| "\x1B[0m".to_owned(), |style| style.sgr()).as_bytes());
+            for col in 0..width {
+                let addr = self.size.encode_address(row, col);
+                if let Some(field) = self.fields[addr as usize] {
+                    // The attribute itself is drawn as a blank in the previous field's style
+                    output.extend_from_slice(b"\x1B[0m ");
+                    output.extend_from_slice(field.sgr().as_bytes());
+                    style = Some(field);
+                    continue;
+                }
+                let mut buf = [0; 4];
+                output.extend_from_slice(self.glyph(addr, style).encode_utf8(&mut buf).as_bytes());
+            }
+        }
+        output.extend_from_slice(b"\x1B[0m");
+        self.place_cursor(output);
+    }
+
+    /// Redraw a single position after it's been typed over
+    fn draw_at(&self, addr: u16, output: &mut Vec<u8>) {
+        let (width, height) = self.visible();
+        let (row, col) = self.size.decode_address(addr);
+        if row >= height || col >= width {
+            return;
+        }
+        let style = self.style_at(addr);
+        self.move_to(addr, output);
+        output.extend_from_slice(style.map_or_else(|| "\x1B[0m".to_owned(), |style| style.sgr()).as_bytes());
+        let mut buf = [0; 4];
+        output.extend_from_slice(self.glyph(addr, style).encode_utf8(&mut buf).as_bytes());
+        output.extend_from_slice(b"\x1B[0m");
+    }
+
+    fn place_cursor(&self, output: &mut Vec<u8>) {
+        self.move_to(self.cursor, output);
+    }
+
+    /// Handle keystrokes from the client, returning the inbound records for any AID keys
+    pub fn receive(&mut self, data: &[u8], output: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(data);
+
+        let mut records = Vec::new();
+        let mut rest = input.as_slice();
+        while !rest.is_empty() {
+            let (key, used) = match decode_key(rest) {
+                Some(decoded) => decoded,
+                None => {
+                    self.pending = rest.to_vec();
+                    break;
+                }
+            };
+            let after_cr = std::mem::replace(&mut self.after_cr, rest[0] == b'\r');
+            rest = &rest[used..];
+            if after_cr && matches!(key, Key::Char('\n') | Key::Char('\0')) {
+                continue;
+            }
+            records.extend(self.press(key, output));
+        }
+        records
+    }
+
+    /// Whether an ESC is being held in case the rest of an escape sequence follows
+    pub fn has_pending_escape(&self) -> bool {
+        self.pending.first() == Some(&ESC)
+    }
+
+    /// Give up waiting for the rest of an escape sequence, so that the held ESC is
+    /// pressed as the Esc key and anything after it is typed as is
+    pub fn flush_escape(&mut self, output: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        if !self.has_pending_escape() {
+            return Vec::new();
+        }
+        let pending = std::mem::take(&mut self.pending);
+        self.after_cr = false;
+        let mut records: Vec<_> = self.press(Key::Esc, output).into_iter().collect();
+        records.extend(self.receive(&pending[1..], output));
+        records
+    }
+
+    fn press(&mut self, key: Key, output: &mut Vec<u8>) -> Option<Vec<u8>> {
+        if key == Key::Redraw {
+            self.redraw(output);
+            return None;
+        }
+        if self.locked {
+            output.push(BEL);
+            return None;
+        }
+        let width = self.size.width;
+        match key {
+            Key::Char('\n') => return Some(self.aid(AID::Enter, output)),
+            Key::Char(ch) if ch.is_control() => {}
+            Key::Char(ch) => {
+                if !self.is_input(self.cursor) {
+                    output.push(BEL);
+                    return None;
+                }
+                self.chars[self.cursor as usize] = ch;
+                self.set_mdt(self.cursor);
+                self.draw_at(self.cursor, output);
+                let next = self.next(self.cursor);
+                self.cursor = if self.is_input(next) {
+                    next
+                } else {
+                    self.next_input(self.cursor).unwrap_or(next)
+                };
+            }
+            Key::Backspace => {
+                let prev = self.prev(self.cursor);
+                if self.is_input(prev) && self.field_start(prev) == self.field_start(self.cursor) {
+                    self.cursor = prev;
+                    self.chars[prev as usize] = '\0';
+                    self.set_mdt(prev);
+                    self.draw_at(prev, output);
+                }
+            }
+            Key::Tab => self.cursor = self.next_input(self.cursor).unwrap_or(self.cursor),
+            Key::BackTab => self.cursor = self.prev_input(self.cursor).unwrap_or(self.cursor),
+            Key::Home => self.cursor = self.next_input(self.len() - 1).unwrap_or(0),
+            Key::Left => self.cursor = self.prev(self.cursor),
+            Key::Right => self.cursor = self.next(self.cursor),
+            Key::Up => self.cursor = (self.cursor + self.len() - width) % self.len(),
+            Key::Down => self.cursor = (self.cursor + width) % self.len(),
+            Key::Enter => return Some(self.aid(AID::Enter, output)),
+            Key::Esc => return Some(self.aid(AID::Clear, output)),
+            Key::Function(n) => return Some(self.aid(PF_KEYS[n as usize - 1], output)),
+            Key::Redraw | Key::Ignored => {}
+        }
+        self.place_cursor(output);
+        None
+    }
+
+    fn set_mdt(&mut self, addr: u16) {
+        if let Some(start) = self.field_start(addr) {
+            if let Some(style) = self.fields[start as usize].as_mut() {
+                style.attr.insert(FieldAttribute::MODIFIED);
+            }
+        }
+    }
+
+    /// Lock the keyboard and build the record a 3270 sends for `aid`: the cursor address,
+    /// then the address and contents of every modified field. Clear also clears the
+    /// screen, and sends only the cursor address.
+    fn aid(&mut self, aid: AID, output: &mut Vec<u8>) -> Vec<u8> {
+        self.locked = true;
+        let mut record = vec![aid.into(), (self.cursor >> 8) as u8, (self.cursor & 0xff) as u8];
+        if aid == AID::Clear {
+            self.clear();
+            self.redraw(output);
+            return record;
+        }
+
+        for start in 0..self.len() {
+            let modified = self.fields[start as usize]
+                .is_some_and(|style| style.attr.contains(FieldAttribute::MODIFIED));
+            if !modified {
+                continue;
+            }
+            let first = self.next(start);
+            record.extend_from_slice(&[0x11, (first >> 8) as u8, (first & 0xff) as u8]);
+            let mut pos = first;
+            let mut text = String::new();
+            while self.fields[pos as usize].is_none() && pos != start {
+                // Nulls aren't sent
+                if self.chars[pos as usize] != '\0' {
+                    text.push(self.chars[pos as usize]);
+                }
+                pos = self.next(pos);
+            }
+            record.extend(crate::encoding::to_cp037(text.chars()));
+        }
+        record
+    }
+}
+
+/// Decode the key at the start of `input`, returning it along with the number of bytes it
+/// took up. Returns `None` if `input` ends partway through a key.
+fn decode_key(input: &[u8]) -> Option<(Key, usize)> {
+    let key = match input[0] {
+        b'\r' => Key::Enter,
+        b'\t' => Key::Tab,
+        0x08 | 0x7F => Key::Backspace,
+        0x0C => Key::Redraw,
+        ESC => return decode_escape(input),
+        0x00..=0x7F => Key::Char(input[0] as char),
+        lead => {
+            let len = match lead {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => return Some((Key::Ignored, 1)),
+            };
+            if input.len() < len {
+                return None;
+            }
+            return Some(match std::str::from_utf8(&input[..len]).ok().and_then(|text| text.chars().next()) {
+                Some(ch) => (Key::Char(ch), len),
+                None => (Key::Ignored, 1),
+            });
+        }
+    };
+    Some((key, 1))
+}
+
+/// Decode a VT100/xterm escape sequence. An ESC followed by anything that can't start
+/// one is the Esc key; one at the end of the input is held until more arrives.
+fn decode_escape(input: &[u8]) -> Option<(Key, usize)> {
+    match input.get(1) {
+        None => return None,
+        Some(b'[') | Some(b'O') => {}
+        Some(_) => return Some((Key::Esc, 1)),
+    }
+
+    // Parameter and intermediate bytes, then a final byte. Real keys are only a few bytes
+    // long, so only that far is looked at, and anything past it is decoded as typed.
+    let window = &input[..input.len().min(MAX_ESCAPE_LEN)];
+    let end = match window[2..].iter().position(|byte| !(0x20..=0x3F).contains(byte)) {
+        Some(pos) if (0x40..=0x7E).contains(&window[pos + 2]) => pos + 2,
+        // Something that can't be part of a sequence, such as a control character, which
+        // is decoded in its own right
+        Some(pos) => return Some((Key::Ignored, pos + 2)),
+        None if input.len() >= MAX_ESCAPE_LEN => return Some((Key::Ignored, MAX_ESCAPE_LEN)),
+        None => return None,
+    };
+    let params: Vec<u16> = std::str::from_utf8(&input[2..end]).unwrap_or("")
+        .split(';')
+        .map(|param| param.parse().unwrap_or(0))
+        .collect();
+    let shifted = params.get(1) == Some(&2);
+    let shift = |n: u8| if shifted { n + 12 } else { n };
+
+    let key = match input[end] {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'Z' => Key::BackTab,
+        b'P' => Key::Function(shift(1)),
+        b'Q' => Key::Function(shift(2)),
+        b'R' => Key::Function(shift(3)),
+        b'S' => Key::Function(shift(4)),
+        b'~' => match params[0] {
+            1 => Key::Home,
+            11..=15 => Key::Function(shift(params[0] as u8 - 10)),
+            17..=21 => Key::Function(shift(params[0] as u8 - 11)),
+            23 | 24 => Key::Function(shift(params[0] as u8 - 12)),
+            // The VT220's F13-F20
+            25 | 26 => Key::Function(params[0] as u8 - 12),
+            28 | 29 => Key::Function(params[0] as u8 - 13),
+            31..=34 => Key::Function(params[0] as u8 - 14),
+            _ => Key::Ignored,
+        },
+        _ => Key::Ignored,
+    };
+    Some((key, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terminal() -> NvtTerminal {
+        NvtTerminal::new(BufferAddressCalculator { width: 80, height: 24 })
+    }
+
+    /// A sign-on form: a name field at 7-15 and a hidden password field at 87-95
+    fn sign_on() -> WriteCommand {
+        WriteCommand {
+            command: WriteCommandCode::EraseWrite,
+            wcc: WCC::RESET_MDT | WCC::KBD_RESTORE,
+            orders: vec![
+                WriteOrder::StartField(FieldAttribute::PROTECTED),
+                WriteOrder::SendText("Name:".into()),
+                WriteOrder::StartField(FieldAttribute::NONE),
+                WriteOrder::SetBufferAddress(16),
+                WriteOrder::StartField(FieldAttribute::PROTECTED),
+                WriteOrder::SetBufferAddress(80),
+                WriteOrder::StartField(FieldAttribute::PROTECTED),
+                WriteOrder::SendText("Pass:".into()),
+                WriteOrder::StartField(FieldAttribute::NON_DISPLAY),
+                WriteOrder::SetBufferAddress(96),
+                WriteOrder::StartField(FieldAttribute::PROTECTED),
+            ],
+        }
+    }
+
+    fn signed_on() -> NvtTerminal {
+        let mut nvt = terminal();
+        nvt.write(&sign_on(), &mut Vec::new());
+        nvt
+    }
+
+    fn contains(output: &[u8], expected: &str) -> bool {
+        output.windows(expected.len()).any(|window| window == expected.as_bytes())
+    }
+
+    #[test]
+    fn renders_screen() {
+        let mut nvt = terminal();
+        let mut output = Vec::new();
+        nvt.write(&sign_on(), &mut output);
+
+        assert!(output.starts_with(b"\x1B[0m\x1B[H\x1B[2J"));
+        assert!(contains(&output, "Name:"));
+        assert!(contains(&output, "\x1B[0m \x1B[0;39mPass:"));
+        // Input fields are underlined
+        assert!(contains(&output, "Name:\x1B[0m \x1B[0;4;39m"));
+        // The cursor starts in the first input field
+        assert!(output.ends_with(b"\x1B[1;8H"));
+        assert!(!output.contains(&BEL));
+    }
+
+    #[test]
+    fn rendering_fits_the_window() {
+        let mut nvt = signed_on();
+        let mut output = Vec::new();
+        nvt.set_window(40, 10, &mut output);
+        assert!(contains(&output, "\x1B[10;1H"));
+        assert!(!contains(&output, "\x1B[11;1H"));
+    }
+
+    #[test]
+    fn edits_fields() {
+        let mut nvt = signed_on();
+        let mut output = Vec::new();
+        assert!(nvt.receive(b"ab\x7fc", &mut output).is_empty());
+        assert_eq!(&nvt.chars[7..10], ['a', 'c', '\0']);
+        assert_eq!(nvt.cursor, 9);
+
+        // Protected positions can't be typed over
+        output.clear();
+        nvt.receive(b"\x1b[H\x1b[D", &mut output);
+        assert_eq!(nvt.cursor, 6);
+        output.clear();
+        nvt.receive(b"x", &mut output);
+        assert_eq!(output, [BEL]);
+        assert_eq!(nvt.chars[6], '\0');
+
+        // Tab goes to the next input field, and on to the hidden one, which doesn't show
+        // what's typed
+        nvt.receive(b"\t", &mut output);
+        assert_eq!(nvt.cursor, 7);
+        nvt.receive(b"\t", &mut output);
+        assert_eq!(nvt.cursor, 87);
+        output.clear();
+        nvt.receive(b"pw", &mut output);
+        assert_eq!(&nvt.chars[87..89], ['p', 'w']);
+        assert!(!output.contains(&b'p') && !output.contains(&b'w'));
+    }
+
+    #[test]
+    fn aid_builds_inbound_record() {
+        let mut nvt = signed_on();
+        let mut output = Vec::new();
+        assert!(nvt.receive(b"ac\tpw", &mut output).is_empty());
+        // CR LF is a single Enter
+        let records = nvt.receive(b"\r\n", &mut output);
+
+        let mut expected = vec![u8::from(AID::Enter), 0x00, 89, 0x11, 0x00, 7];
+        expected.extend(crate::encoding::to_cp037("ac".chars()));
+        expected.extend_from_slice(&[0x11, 0x00, 87]);
+        expected.extend(crate::encoding::to_cp037("pw".chars()));
+        assert_eq!(records, [expected]);
+
+        // The keyboard stays locked until the application restores it
+        output.clear();
+        assert!(nvt.receive(b"z\r", &mut output).is_empty());
+        assert_eq!(output, [BEL, BEL]);
+
+        let restore = WriteCommand { command: WriteCommandCode::Write, wcc: WCC::KBD_RESTORE | WCC::RESET_MDT, orders: vec![] };
+        nvt.write(&restore, &mut output);
+        let records = nvt.receive(b"\x1bOR", &mut output);
+        assert_eq!(records, [vec![u8::from(AID::PF3), 0x00, 89]]);
+    }
+
+    #[test]
+    fn escape_sequence_split_across_reads() {
+        let mut nvt = terminal();
+        let mut output = Vec::new();
+        assert!(nvt.receive(&[ESC], &mut output).is_empty());
+        assert!(nvt.has_pending_escape());
+
+        let records = nvt.receive(b"OP", &mut output);
+        assert!(!nvt.has_pending_escape());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0][0], u8::from(AID::PF1));
+    }
+
+    #[test]
+    fn junk_escape_keeps_following_keys() {
+        for junk in [&b"\x1b[1;2\x7f"[..], b"\x1b[;;;;;;;;;;;;;;"] {
+            let mut nvt = signed_on();
+            let mut input = junk.to_vec();
+            input.extend_from_slice(b"abc\r");
+            let records = nvt.receive(input.as_slice(), &mut Vec::new());
+            assert_eq!(&nvt.chars[7..10], ['a', 'b', 'c'], "{:?}", junk);
+            assert_eq!(records.len(), 1);
+            assert_eq!(records[0][0], u8::from(AID::Enter));
+        }
+    }
+
+    #[test]
+    fn lone_escape_is_clear_once_flushed() {
+        let mut nvt = terminal();
+        let mut output = Vec::new();
+        assert!(nvt.receive(&[ESC], &mut output).is_empty());
+
+        let records = nvt.flush_escape(&mut output);
+        assert!(!nvt.has_pending_escape());
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0][0], u8::from(AID::Clear));
+        assert!(nvt.flush_escape(&mut output).is_empty());
+    }
+}
@@ -0,0 +1,1161 @@
+//! The transport-independent half of a session: telnet parsing, option negotiation, and
+//! record framing. Nothing in here does any I/O; bytes received from the client are fed in
+//! with [`Protocol::receive_bytes`], and anything that needs to be sent back accumulates
+//! until the session collects it with [`Protocol::take_output`].
+
+use libtelnet_rs::{
+    Parser,
+    events::*,
+    telnet::{
+        op_option as tn_opt,
+        op_command as tn_cmd,
+    }
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use tracing::{debug, trace};
+
+use crate::tn3270::{Event, Record, Signal, DEFAULT_MAX_RECORD_SIZE};
+use crate::tn3270::tn3270e::{self, DataType, DeviceResponse, Functions, Header, Subnegotiation, OPT_TN3270E, response_flag};
+use crate::tn3270::lu::{LuLease, LuPool};
+use crate::tn3270::environ::{self, Environment, OPT_NEW_ENVIRON};
+use crate::tn3270::negotiation::{NegotiationError, NegotiationPolicy, RefusalAction, TelnetOption};
+use crate::tn3270::nvt::NvtTerminal;
+use crate::tn3270::stream::{BufferAddressCalculator, IncomingRecord, StreamFormatError, WriteCommand, WriteCommandCode};
+use crate::tn3270::terminal::TerminalModel;
+use crate::tn3270::tn3270e::ReasonCode;
+use crate::tn3270::telnet::{self, CommandExtractor, CommandName, OptionName, Piece, OPT_START_TLS, START_TLS_FOLLOWS, unescape_iac};
+use crate::tn3270::transport::StartTlsPolicy;
+
+/// How many terminal types a client can list before we stop asking for more
+const MAX_TERM_TYPES: usize = 32;
+
+/// Where we are in negotiating TN3270E with the client
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Tn3270eState {
+    /// We've sent DO TN3270E and are waiting to hear back
+    Offered,
+    /// The client refused TN3270E; we're running plain TN3270
+    Refused,
+    /// The client agreed; device type and functions are still being negotiated
+    Negotiating,
+    /// Device type and functions have been agreed on
+    Active,
+}
+
+/// Where we are in going through the client's terminal types (RFC 1091)
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum TtypeState {
+    /// Collecting the client's list
+    Cycling,
+    /// The list is complete, and we're asking until the client switches to the type we
+    /// chose
+    Settling { chosen: String, attempts: usize },
+    Done,
+}
+
+/// Where we are in collecting the client's environment variables
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum EnvironState {
+    /// 3270 negotiation hasn't started yet
+    NotOffered,
+    /// We've sent DO NEW-ENVIRON and are waiting to hear back
+    Offered,
+    /// We've asked for the variables and are waiting for them
+    Requested,
+    /// We have the variables, or the client refused to send any
+    Done,
+}
+
+/// Where we are in switching to TLS with START_TLS
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum StartTlsState {
+    /// Not offered, or refused by the client
+    Off,
+    /// We've sent DO START_TLS and are waiting to hear back
+    Offered,
+    /// We've sent FOLLOWS; the handshake starts right after the client's FOLLOWS
+    Follows,
+    /// The client has sent FOLLOWS, and the session needs to run the handshake
+    Handshake,
+    /// The session is running over TLS
+    Active,
+}
+
+/// What the client sends right before the TLS handshake
+const CLIENT_FOLLOWS: [u8; 6] = [tn_cmd::IAC, tn_cmd::SB, OPT_START_TLS, START_TLS_FOLLOWS, tn_cmd::IAC, tn_cmd::SE];
+
+type Error = std::io::Error;
+
+fn log_inbound_record(header: &Header, data: &[u8]) {
+    debug!(data_type = ?header.data_type, seq = header.seq_number, len = data.len(), "received record");
+    if header.data_type == DataType::Data3270 {
+        trace!(payload = %hex::encode(data), decoded = ?IncomingRecord::parse_record(data), "received record contents");
+    } else {
+        trace!(payload = %hex::encode(data), "received record contents");
+    }
+}
+
+fn log_outbound_record(header: &Header, data: &[u8]) {
+    debug!(data_type = ?header.data_type, seq = header.seq_number, len = data.len(), "sending record");
+    if header.data_type == DataType::Data3270 {
+        trace!(payload = %hex::encode(data), decoded = ?WriteCommand::parse(data), "sending record contents");
+    } else {
+        trace!(payload = %hex::encode(data), "sending record contents");
+    }
+}
+
+/// Log what the telnet parser wants to send. TN3270E subnegotiations are also logged
+/// decoded, as they're built.
+fn log_telnet_output(data: &[u8]) {
+    match *data {
+        [tn_cmd::IAC, command @ tn_cmd::WILL..=tn_cmd::DONT, option] => {
+            debug!(command = %CommandName(command), option = %OptionName(option), "sent negotiation");
+        }
+        [tn_cmd::IAC, tn_cmd::SB, option, ..] => {
+            let body = data.get(3..data.len() - 2).unwrap_or_default();
+            debug!(option = %OptionName(option), payload = %hex::encode(body), "sent subnegotiation");
+        }
+        _ => trace!(payload = %hex::encode(data), "sent telnet data"),
+    }
+}
+
+pub(crate) struct Protocol {
+    parser: Parser,
+    commands: CommandExtractor,
+
+    term_type: Option<String>,
+    term_types: Vec<String>,
+    ttype: TtypeState,
+    is_eor: bool,
+    is_bin: bool,
+    /// Set once the client turns out to be an NVT terminal
+    nvt: Option<NvtTerminal>,
+    /// Whether the client has sent its window size, or refused to
+    naws_settled: bool,
+    /// Whether the last erase sent to the terminal switched it to its alternate size
+    alternate_screen: bool,
+    /// Whether screens should switch to the alternate size when it's larger
+    use_alternate_size: bool,
+
+    tn3270e: Tn3270eState,
+    lu_pool: Arc<dyn LuPool>,
+    lu: Option<LuLease>,
+    functions: Functions,
+    supported_functions: Functions,
+    send_seq: u16,
+
+    start_tls_policy: StartTlsPolicy,
+    start_tls: StartTlsState,
+    /// Bytes that might be the start of the client's FOLLOWS
+    held: Vec<u8>,
+    environ: EnvironState,
+    environment: Environment,
+    /// Bytes following the client's FOLLOWS, which belong to the TLS handshake
+    handshake: Vec<u8>,
+
+    incoming_records: VecDeque<Record>,
+    responses: HashMap<u16, DeviceResponse>,
+    /// Sequence numbers of records whose response someone is still waiting for
+    awaiting_responses: HashSet<u16>,
+    signals: VecDeque<Signal>,
+    /// Whether a DO TIMING-MARK is waiting for the client's answer
+    timing_mark_sent: bool,
+    /// Whether the client has answered a DO TIMING-MARK that nobody has taken note of yet
+    timing_mark_answered: bool,
+    cur_record: Vec<u8>,
+    max_record_size: usize,
+
+    policy: NegotiationPolicy,
+    /// Optional options the client turned down
+    refused: Vec<TelnetOption>,
+
+    output: Vec<u8>,
+}
+
+impl Protocol {
+    pub fn new(lu_pool: Arc<dyn LuPool>, policy: NegotiationPolicy, start_tls_policy: StartTlsPolicy) -> Self {
+        let mut protocol = Protocol {
+            parser: Parser::new(),
+            commands: CommandExtractor::new(),
+            incoming_records: VecDeque::new(),
+            responses: HashMap::new(),
+            awaiting_responses: HashSet::new(),
+            signals: VecDeque::new(),
+            timing_mark_sent: false,
+            timing_mark_answered: false,
+            term_type: None,
+            term_types: Vec::new(),
+            ttype: TtypeState::Cycling,
+            is_bin: false,
+            is_eor: false,
+            nvt: None,
+            naws_settled: false,
+            alternate_screen: false,
+            use_alternate_size: true,
+            tn3270e: Tn3270eState::Offered,
+            lu_pool,
+            lu: None,
+            functions: Functions::empty(),
+            supported_functions: Functions::RESPONSES | Functions::SYSREQ,
+            send_seq: 0,
+            start_tls_policy,
+            start_tls: StartTlsState::Off,
+            held: Vec::new(),
+            handshake: Vec::new(),
+            environ: EnvironState::NotOffered,
+            environment: Environment::default(),
+            cur_record: Vec::new(),
+            max_record_size: DEFAULT_MAX_RECORD_SIZE,
+            policy,
+            refused: Vec::new(),
+            output: Vec::new(),
+        };
+
+        if protocol.policy.offers(TelnetOption::EndOfRecord) {
+            protocol.parser.options.support(tn_opt::EOR);
+        }
+        if protocol.policy.offers(TelnetOption::TerminalType) {
+            protocol.parser.options.support_remote(tn_opt::TTYPE);
+        }
+        if protocol.policy.offers(TelnetOption::Binary) {
+            protocol.parser.options.support(tn_opt::BINARY);
+        }
+        if protocol.policy.offers(TelnetOption::Tn3270e) {
+            protocol.parser.options.support_remote(OPT_TN3270E);
+        }
+        if protocol.policy.offers(TelnetOption::NewEnviron) {
+            protocol.parser.options.support_remote(OPT_NEW_ENVIRON);
+        }
+        if protocol.policy.nvt_fallback {
+            protocol.parser.options.support_local(tn_opt::ECHO);
+            protocol.parser.options.support(tn_opt::SGA);
+            protocol.parser.options.support_remote(tn_opt::NAWS);
+        }
+        if start_tls_policy != StartTlsPolicy::Refuse {
+            protocol.parser.options.support_remote(OPT_START_TLS);
+        }
+        protocol
+    }
+
+    /// Queue the opening negotiation. Everything after this is driven by the client.
+    pub fn start_negotiation(&mut self) -> Result<(), Error> {
+        let initial_negotiation = if self.start_tls_policy == StartTlsPolicy::Refuse {
+            self.start_3270_negotiation()
+        } else {
+            // 3270 negotiation waits until we know whether the rest will be encrypted
+            self.start_tls = StartTlsState::Offered;
+            self.parser._do(OPT_START_TLS).into_iter().collect()
+        };
+        self.process_events(initial_negotiation)
+    }
+
+    fn start_3270_negotiation(&mut self) -> Vec<TelnetEvents> {
+        let mut events = Vec::new();
+        if self.policy.offers(TelnetOption::NewEnviron) {
+            self.environ = EnvironState::Offered;
+            events.extend(self.parser._do(OPT_NEW_ENVIRON));
+        }
+        if self.policy.offers(TelnetOption::Tn3270e) {
+            events.extend(self.parser._do(OPT_TN3270E));
+        } else {
+            self.tn3270e = Tn3270eState::Refused;
+            events.extend(self.start_classic_negotiation());
+        }
+        events
+    }
+
+    /// If the client has agreed to START_TLS, returns the part of the handshake that has
+    /// already been received. The session must then run the handshake and call
+    /// [`Protocol::restart_after_tls`].
+    pub fn take_tls_handshake(&mut self) -> Option<Vec<u8>> {
+        if self.start_tls == StartTlsState::Handshake {
+            Some(std::mem::take(&mut self.handshake))
+        } else {
+            None
+        }
+    }
+
+    /// Start negotiation over from scratch once the connection is encrypted. Nothing
+    /// negotiated in plain text carries over.
+    pub fn restart_after_tls(&mut self) -> Result<(), Error> {
+        *self = Protocol::new(self.lu_pool.clone(), self.policy.clone(), StartTlsPolicy::Refuse);
+        self.start_tls = StartTlsState::Active;
+        self.start_negotiation()
+    }
+
+    /// Whether the client agreed to START_TLS
+    pub fn is_tls(&self) -> bool {
+        self.start_tls == StartTlsState::Active
+    }
+
+    /// Give up on negotiation, explaining why to the client if the policy says to
+    fn fail(&mut self, err: NegotiationError) -> Error {
+        debug!(error = %err, "giving up on negotiation");
+        if self.policy.on_refusal == RefusalAction::Explain {
+            self.output.extend_from_slice(format!("\r\n{}\r\n", err).as_bytes());
+        }
+        err.into()
+    }
+
+    /// Note that the client turned down an option, which is fatal if the policy requires it
+    fn refuse(&mut self, option: TelnetOption) -> Result<(), Error> {
+        debug!(option = %option, "client refused option");
+        if self.policy.requires(option) {
+            return Err(self.fail(NegotiationError::RefusedOption { option }));
+        }
+        if !self.refused.contains(&option) {
+            self.refused.push(option);
+        }
+        Ok(())
+    }
+
+    /// Whether an option has been dealt with, one way or another
+    fn is_settled(&self, option: TelnetOption, agreed: bool) -> bool {
+        agreed || self.refused.contains(&option) || !self.policy.offers(option)
+    }
+
+    /// Take everything that is waiting to be sent to the client
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn option_state(&self, opt: u8) -> bool {
+        let opt = self.parser.options.get_option(opt);
+        opt.local_state && opt.remote_state
+    }
+
+    /// libtelnet-rs only passes subnegotiations through (in either direction) for options
+    /// that are enabled on our side, so options that only the client performs need to be
+    /// marked as such once the client agrees to them.
+    fn enable_subnegotiation(&mut self, option: u8) {
+        let mut entry = self.parser.options.get_option(option);
+        entry.local = true;
+        entry.local_state = true;
+        self.parser.options.set_option(option, entry);
+    }
+
+    pub fn is_nvt(&self) -> bool {
+        self.nvt.is_some()
+    }
+
+    pub fn is_tn3270e(&self) -> bool {
+        self.tn3270e == Tn3270eState::Active
+    }
+
+    pub fn functions(&self) -> Functions {
+        self.functions
+    }
+
+    pub fn lu_name(&self) -> Option<&str> {
+        self.lu.as_ref().map(LuLease::name)
+    }
+
+    pub fn term_type(&self) -> Option<&str> {
+        self.term_type.as_deref()
+    }
+
+    pub fn term_types(&self) -> &[String] {
+        self.term_types.as_slice()
+    }
+
+    pub fn terminal_model(&self) -> TerminalModel {
+        self.term_type().and_then(TerminalModel::parse).unwrap_or_default()
+    }
+
+    /// The size of the terminal's screen, which is its alternate size after an
+    /// Erase/Write Alternate
+    pub fn screen_size(&self) -> BufferAddressCalculator {
+        let model = self.terminal_model();
+        if self.alternate_screen && self.nvt.is_none() {
+            model.alternate_size
+        } else {
+            model.default_size
+        }
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    fn tn3270e_subnegotiation(&mut self, sub: Subnegotiation) -> Option<TelnetEvents> {
+        debug!(?sub, "sending TN3270E subnegotiation");
+        self.parser.subnegotiation(OPT_TN3270E, sub.serialize())
+    }
+
+    fn handle_tn3270e(&mut self, buffer: &[u8]) -> Result<Vec<TelnetEvents>, Error> {
+        let sub = Subnegotiation::parse(buffer)
+            .map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+        debug!(?sub, "received TN3270E subnegotiation");
+        let reply = match sub {
+            Subnegotiation::DeviceTypeRequest { device_type, connect, associate } => {
+                // Give back whatever we were assigned before, in case the client is
+                // renegotiating
+                self.lu = None;
+                if !self.term_types.contains(&device_type) && self.term_types.len() < MAX_TERM_TYPES {
+                    self.term_types.push(device_type.clone());
+                }
+                let accepted = if TerminalModel::parse(device_type.as_str()).is_some_and(|model| model.printer) {
+                    self.policy.terminal_types.accepts_printer(device_type.as_str())
+                } else {
+                    self.policy.terminal_types.rank(device_type.as_str()).is_some()
+                };
+                if !accepted {
+                    let reply = Subnegotiation::DeviceTypeReject(ReasonCode::InvDeviceType);
+                    return Ok(self.tn3270e_subnegotiation(reply).into_iter().collect());
+                }
+                let assigned = match associate {
+                    Some(terminal) => self.lu_pool.associate(device_type.as_str(), terminal.as_str()),
+                    None => self.lu_pool.assign(device_type.as_str(), connect.as_deref()),
+                };
+                match assigned {
+                    Ok(device_name) => {
+                        self.term_type = Some(device_type.clone());
+                        self.lu = Some(LuLease::new(self.lu_pool.clone(), device_name.clone()));
+                        Subnegotiation::DeviceTypeIs { device_type, device_name }
+                    }
+                    Err(reason) => Subnegotiation::DeviceTypeReject(reason),
+                }
+            }
+            // Functions are only negotiated once a device type has been agreed on
+            Subnegotiation::FunctionsRequest(_) | Subnegotiation::FunctionsIs(_) if self.lu.is_none() => {
+                debug!("ignoring FUNCTIONS before DEVICE-TYPE IS");
+                return Ok(vec![]);
+            }
+            Subnegotiation::FunctionsRequest(requested) => {
+                let agreed = requested & self.supported_functions;
+                if agreed == requested {
+                    self.functions = agreed;
+                    self.tn3270e = Tn3270eState::Active;
+                    Subnegotiation::FunctionsIs(agreed)
+                } else {
+                    Subnegotiation::FunctionsRequest(agreed)
+                }
+            }
+            Subnegotiation::FunctionsIs(functions) => {
+                self.functions = functions & self.supported_functions;
+                self.tn3270e = Tn3270eState::Active;
+                return Ok(vec![]);
+            }
+            // These only ever go from server to client
+            Subnegotiation::SendDeviceType
+            | Subnegotiation::DeviceTypeIs { .. }
+            | Subnegotiation::DeviceTypeReject(_) => return Ok(vec![]),
+        };
+        Ok(self.tn3270e_subnegotiation(reply).into_iter().collect())
+    }
+
+    fn start_classic_negotiation(&mut self) -> Vec<TelnetEvents> {
+        if self.policy.offers(TelnetOption::TerminalType) {
+            self.parser._do(tn_opt::TTYPE).into_iter().collect()
+        } else {
+            self.ttype = TtypeState::Done;
+            self.start_binary_negotiation()
+        }
+    }
+
+    /// Once the terminal type is settled, we also need to negotiate EOR and BINARY
+    fn start_binary_negotiation(&mut self) -> Vec<TelnetEvents> {
+        [
+            self.parser._will(tn_opt::EOR),
+            self.parser._do(tn_opt::EOR),
+            self.parser._will(tn_opt::BINARY),
+            self.parser._do(tn_opt::BINARY),
+        ].iter_mut()
+            .flat_map(Option::take)
+            .collect()
+    }
+
+    fn send_ttype(&mut self) -> Vec<TelnetEvents> {
+        self.parser.subnegotiation(tn_opt::TTYPE, vec![tn_cmd::SEND]).into_iter().collect()
+    }
+
+    /// Switch to serving the client as an NVT terminal. We echo, and the client sends each
+    /// key as it's typed and tells us its window size.
+    fn start_nvt(&mut self) -> Vec<TelnetEvents> {
+        debug!("serving the client as an NVT terminal");
+        self.ttype = TtypeState::Done;
+        self.nvt = Some(NvtTerminal::new(TerminalModel::default().default_size));
+        // Clients send their window size along with WILL NAWS, before the parser would
+        // otherwise let it through
+        self.enable_subnegotiation(tn_opt::NAWS);
+        [
+            self.parser._will(tn_opt::ECHO),
+            self.parser._will(tn_opt::SGA),
+            self.parser._do(tn_opt::SGA),
+            self.parser._do(tn_opt::NAWS),
+        ].iter_mut()
+            .flat_map(Option::take)
+            .collect()
+    }
+
+    /// The client's most preferred type, with ties going to whichever it listed first
+    fn choose_term_type(&self) -> Option<String> {
+        let mut best: Option<(u32, &String)> = None;
+        for term_type in self.term_types.iter() {
+            if let Some(rank) = self.policy.terminal_types.rank(term_type.as_str()) {
+                if best.map_or(true, |(best_rank, _)| rank > best_rank) {
+                    best = Some((rank, term_type));
+                }
+            }
+        }
+        best.map(|(_, term_type)| term_type.clone())
+    }
+
+    /// Handle TTYPE IS. The client steps through its list of types each time we send
+    /// TTYPE SEND, and signals the end of the list by repeating itself.
+    fn handle_ttype(&mut self, current: String) -> Result<Vec<TelnetEvents>, Error> {
+        if self.ttype == TtypeState::Cycling {
+            let repeated = self.term_types.last() == Some(&current)
+                || self.term_types.first() == Some(&current)
+                || self.term_types.len() >= MAX_TERM_TYPES;
+            if !repeated {
+                self.term_types.push(current);
+                return Ok(self.send_ttype());
+            }
+            let chosen = match self.choose_term_type() {
+                Some(chosen) => chosen,
+                // Nothing the policy accepts, but the client can still be served as an NVT
+                None if self.policy.nvt_fallback && TerminalModel::parse(current.as_str()).is_none() => current.clone(),
+                None => return Err(self.fail(NegotiationError::UnsupportedTerminalType { offered: self.term_types.clone() })),
+            };
+            debug!(offered = ?self.term_types, chosen = %chosen, "chose a terminal type");
+            self.ttype = TtypeState::Settling { chosen, attempts: 0 };
+        }
+
+        let keep_asking = match self.ttype {
+            TtypeState::Settling { ref chosen, ref mut attempts } => {
+                *attempts += 1;
+                *chosen != current && *attempts <= self.term_types.len()
+            }
+            TtypeState::Cycling | TtypeState::Done => return Ok(vec![]),
+        };
+        if keep_asking {
+            return Ok(self.send_ttype());
+        }
+
+        // Either the client switched to the type we wanted, or it won't move off of the
+        // one it's on; either way, the type it last reported is the one it's using. The
+        // terminal type policy only covers 3270s, so an NVT doesn't need to be ranked.
+        let is_3270 = TerminalModel::parse(current.as_str()).is_some();
+        if !is_3270 && self.policy.nvt_fallback {
+            self.term_type = Some(current);
+            return Ok(self.start_nvt());
+        }
+        if self.policy.terminal_types.rank(current.as_str()).is_none() {
+            return Err(self.fail(NegotiationError::UnsupportedTerminalType { offered: self.term_types.clone() }));
+        }
+        self.term_type = Some(current);
+        self.ttype = TtypeState::Done;
+        Ok(self.start_binary_negotiation())
+    }
+
+    pub fn set_use_alternate_size(&mut self, enabled: bool) {
+        self.use_alternate_size = enabled;
+    }
+
+    pub fn uses_alternate_size(&self) -> bool {
+        self.use_alternate_size
+    }
+
+    pub fn set_max_record_size(&mut self, max_record_size: usize) {
+        self.max_record_size = max_record_size;
+    }
+
+    fn record_too_long(&self) -> Error {
+        Error::new(std::io::ErrorKind::InvalidData, StreamFormatError::RecordTooLong { limit: self.max_record_size })
+    }
+
+    fn finish_record(&mut self) -> Result<(), Error> {
+        let data = std::mem::take(&mut self.cur_record);
+        let record = if self.is_tn3270e() {
+            let (header, body) = Header::parse(data.as_slice())
+                .map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+            if header.data_type == DataType::Response {
+                let response = DeviceResponse::parse(&header, body)
+                    .map_err(|err| Error::new(std::io::ErrorKind::InvalidData, err))?;
+                // Responses nobody is waiting for (unsolicited, or too late for a caller
+                // that gave up) would otherwise pile up
+                if !self.awaiting_responses.contains(&header.seq_number) {
+                    debug!(seq = header.seq_number, ?response, "ignoring unexpected response");
+                    return Ok(());
+                }
+                debug!(seq = header.seq_number, ?response, "received response");
+                self.responses.insert(header.seq_number, response);
+                return Ok(());
+            }
+            Record { header, data: body.to_vec() }
+        } else {
+            Record { header: Header::new(DataType::Data3270), data }
+        };
+        log_inbound_record(&record.header, record.data.as_slice());
+        self.incoming_records.push_back(record);
+        Ok(())
+    }
+
+    fn process_events(&mut self, mut events: Vec<TelnetEvents>) -> Result<(), Error> {
+        let mut extra_events = Vec::new();
+        while !events.is_empty() || !extra_events.is_empty() {
+            events.append(&mut extra_events);
+            extra_events.truncate(0);
+            for mut event in events.drain(..) {
+                match event {
+                    TelnetEvents::Negotiation(TelnetNegotiation { command, option }) => {
+                        debug!(command = %CommandName(command), option = %OptionName(option), "received negotiation");
+                    }
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option, ref buffer }) => {
+                        trace!(option = %OptionName(option), payload = %hex::encode(buffer), "received subnegotiation");
+                    }
+                    _ => {}
+                }
+                match event {
+                    // libtelnet-rs doesn't remember having asked, so it answers the client's
+                    // WILL TIMING-MARK with another DO, which the client would answer in turn
+                    TelnetEvents::DataSend(ref data) if self.timing_mark_sent && data.as_slice() == [tn_cmd::IAC, tn_cmd::DO, tn_opt::TM] => {}
+                    TelnetEvents::DataSend(ref mut data) => {
+                        log_telnet_output(data.as_slice());
+                        self.output.append(data)
+                    }
+                    TelnetEvents::DataReceive(ref mut data) => match self.nvt.as_mut() {
+                        Some(nvt) => {
+                            let records = nvt.receive(data.as_slice(), &mut self.output);
+                            self.queue_nvt_records(records);
+                        }
+                        None => {
+                            if self.cur_record.len() + data.len() > self.max_record_size {
+                                return Err(self.record_too_long());
+                            }
+                            self.cur_record.append(data)
+                        }
+                    },
+                    TelnetEvents::IAC(TelnetIAC { command: tn_cmd::EOR }) => self.finish_record()?,
+                    TelnetEvents::IAC(iac) => debug!(command = %CommandName(iac.command), "ignoring telnet command"),
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL, option: tn_opt::TTYPE }) => {
+                        self.enable_subnegotiation(tn_opt::TTYPE);
+                        extra_events.extend(self.send_ttype());
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT, option: tn_opt::NAWS }) => {
+                        self.naws_settled = true;
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT, option: tn_opt::TTYPE }) => {
+                        if self.tn3270e == Tn3270eState::Refused && self.ttype != TtypeState::Done {
+                            if self.policy.nvt_fallback {
+                                extra_events.extend(self.start_nvt());
+                                continue;
+                            }
+                            self.refuse(TelnetOption::TerminalType)?;
+                            self.ttype = TtypeState::Done;
+                            extra_events.extend(self.start_binary_negotiation());
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT | tn_cmd::DONT, option: option @ (tn_opt::BINARY | tn_opt::EOR) }) => {
+                        self.is_eor = self.option_state(tn_opt::EOR);
+                        self.is_bin = self.option_state(tn_opt::BINARY);
+                        if self.tn3270e == Tn3270eState::Refused && self.nvt.is_none() {
+                            self.refuse(if option == tn_opt::BINARY { TelnetOption::Binary } else { TelnetOption::EndOfRecord })?;
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL, option: OPT_START_TLS }) => {
+                        if self.start_tls == StartTlsState::Offered {
+                            self.start_tls = StartTlsState::Follows;
+                            self.enable_subnegotiation(OPT_START_TLS);
+                            extra_events.extend(self.parser.subnegotiation(OPT_START_TLS, vec![START_TLS_FOLLOWS]));
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT, option: OPT_START_TLS }) => {
+                        if self.start_tls == StartTlsState::Offered {
+                            if self.start_tls_policy == StartTlsPolicy::Require {
+                                return Err(self.fail(NegotiationError::RefusedOption { option: TelnetOption::StartTls }));
+                            }
+                            self.start_tls = StartTlsState::Off;
+                            extra_events.extend(self.start_3270_negotiation());
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL, option: OPT_NEW_ENVIRON }) => {
+                        self.environ = EnvironState::Requested;
+                        self.enable_subnegotiation(OPT_NEW_ENVIRON);
+                        extra_events.extend(self.parser.subnegotiation(OPT_NEW_ENVIRON, environ::send_all()));
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT, option: OPT_NEW_ENVIRON }) => {
+                        if matches!(self.environ, EnvironState::Offered | EnvironState::Requested) {
+                            self.refuse(TelnetOption::NewEnviron)?;
+                            self.environ = EnvironState::Done;
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL, option: OPT_TN3270E }) => {
+                        self.tn3270e = Tn3270eState::Negotiating;
+                        self.enable_subnegotiation(OPT_TN3270E);
+                        extra_events.extend(self.tn3270e_subnegotiation(Subnegotiation::SendDeviceType));
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WONT, option: OPT_TN3270E }) => {
+                        // Either the client never wanted TN3270E or it gave up on it partway
+                        // through; either way, fall back to plain TN3270.
+                        if self.tn3270e == Tn3270eState::Refused {
+                            continue;
+                        }
+                        self.refuse(TelnetOption::Tn3270e)?;
+                        self.tn3270e = Tn3270eState::Refused;
+                        self.functions = Functions::empty();
+                        self.lu = None;
+                        self.term_type = None;
+                        self.term_types.clear();
+                        self.ttype = TtypeState::Cycling;
+                        extra_events.extend(self.start_classic_negotiation());
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::WILL | tn_cmd::WONT, option: tn_opt::TM }) => {
+                        if self.timing_mark_sent {
+                            self.timing_mark_sent = false;
+                            self.timing_mark_answered = true;
+                            // Each probe is a fresh request, so forget this one's answer
+                            let mut entry = self.parser.options.get_option(tn_opt::TM);
+                            entry.remote = false;
+                            entry.remote_state = false;
+                            self.parser.options.set_option(tn_opt::TM, entry);
+                        }
+                    }
+                    TelnetEvents::Negotiation(TelnetNegotiation { .. }) => {
+                        self.is_eor = self.option_state(tn_opt::EOR);
+                        self.is_bin = self.option_state(tn_opt::BINARY);
+                    }
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: tn_opt::TTYPE, buffer }) => {
+                        if buffer.first() == Some(&tn_cmd::IS) {
+                            let term_type = String::from_utf8_lossy(&buffer[1..]).into_owned();
+                            debug!(term_type = %term_type, "client terminal type");
+                            extra_events.extend(self.handle_ttype(term_type)?);
+                        }
+                    }
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: OPT_TN3270E, buffer }) => {
+                        extra_events.extend(self.handle_tn3270e(buffer.as_slice())?);
+                    }
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: OPT_NEW_ENVIRON, buffer }) => {
+                        match buffer.first() {
+                            Some(&environ::cmd::IS) => {
+                                self.environment.update(&buffer[1..]);
+                                self.environ = EnvironState::Done;
+                            }
+                            Some(&environ::cmd::INFO) => self.environment.update(&buffer[1..]),
+                            _ => continue,
+                        }
+                        // Only the names, since values can be sensitive
+                        debug!(
+                            vars = ?self.environment.vars.keys().collect::<Vec<_>>(),
+                            user_vars = ?self.environment.user_vars.keys().collect::<Vec<_>>(),
+                            "client environment",
+                        );
+                    }
+                    TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: tn_opt::NAWS, buffer }) => {
+                        let size = unescape_iac(buffer.as_slice());
+                        if let (Some(nvt), [w1, w0, h1, h0]) = (self.nvt.as_mut(), size.as_slice()) {
+                            let width = u16::from_be_bytes([*w1, *w0]);
+                            let height = u16::from_be_bytes([*h1, *h0]);
+                            debug!(width, height, "client window size");
+                            nvt.set_window(width, height, &mut self.output);
+                            self.naws_settled = true;
+                        }
+                    }
+                    TelnetEvents::Subnegotiation(_) => {},
+                    // MCCP is never agreed to, and the parser is given each subnegotiation on
+                    // its own, so there's never anything to decompress
+                    TelnetEvents::DecompressImmediate(ref data) if data.is_empty() => {}
+                    TelnetEvents::DecompressImmediate(_) => {
+                        return Err(Error::new(std::io::ErrorKind::InvalidData, "the client started MCCP compression, which was never agreed to"));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Feed raw bytes from the client through the telnet parser
+    pub fn receive_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.start_tls != StartTlsState::Follows {
+            return self.receive_telnet(data);
+        }
+
+        // Everything after the client's FOLLOWS is TLS, which mustn't reach the parser
+        let mut buffered = std::mem::take(&mut self.held);
+        buffered.extend_from_slice(data);
+        if let Some(pos) = buffered.windows(CLIENT_FOLLOWS.len()).position(|window| window == CLIENT_FOLLOWS) {
+            self.handshake = buffered.split_off(pos + CLIENT_FOLLOWS.len());
+            self.start_tls = StartTlsState::Handshake;
+            buffered.truncate(pos);
+            return self.receive_telnet(buffered.as_slice());
+        }
+
+        let partial = (1..CLIENT_FOLLOWS.len()).rev()
+            .find(|&len| buffered.ends_with(&CLIENT_FOLLOWS[..len]))
+            .unwrap_or(0);
+        self.held = buffered.split_off(buffered.len() - partial);
+        self.receive_telnet(buffered.as_slice())
+    }
+
+    fn receive_telnet(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut commands = Vec::new();
+        let pieces = self.commands.extract(data, &mut commands);
+        for command in commands {
+            let signal = match command {
+                telnet::IP | telnet::BRK => Signal::Attention,
+                telnet::AO if self.functions.contains(Functions::SYSREQ) => Signal::SysReq,
+                _ => {
+                    debug!(command = %CommandName(command), "ignoring telnet command");
+                    continue;
+                }
+            };
+            debug!(command = %CommandName(command), ?signal, "received signal");
+            self.signals.push_back(signal);
+        }
+
+        if self.commands.subnegotiation_len() > self.max_record_size {
+            return Err(self.record_too_long());
+        }
+
+        for piece in pieces {
+            let events = match piece {
+                Piece::Telnet(data) => self.parser.receive(data.as_slice()),
+                Piece::DataIac => vec![TelnetEvents::DataReceive(vec![tn_cmd::IAC])],
+            };
+            self.process_events(events)?;
+        }
+        Ok(())
+    }
+
+    /// Ask the client for a TIMING-MARK, which it answers with WILL or WONT whether or not
+    /// it supports the option
+    pub fn send_timing_mark(&mut self) -> Result<(), Error> {
+        if self.timing_mark_sent {
+            return Ok(());
+        }
+        let mut entry = self.parser.options.get_option(tn_opt::TM);
+        entry.remote = true;
+        entry.remote_state = false;
+        self.parser.options.set_option(tn_opt::TM, entry);
+        let events = self.parser._do(tn_opt::TM).into_iter().collect();
+        self.process_events(events)?;
+        self.timing_mark_sent = true;
+        Ok(())
+    }
+
+    /// Whether the client has answered a TIMING-MARK since this was last called
+    pub fn take_timing_mark_answer(&mut self) -> bool {
+        std::mem::take(&mut self.timing_mark_answered)
+    }
+
+    /// Whether an NVT client's ESC is being held in case it starts an escape sequence
+    pub fn has_pending_escape(&self) -> bool {
+        self.nvt.as_ref().is_some_and(NvtTerminal::has_pending_escape)
+    }
+
+    /// Take an NVT client's held ESC to be the Esc key, since nothing followed it in time
+    pub fn flush_escape(&mut self) {
+        if let Some(nvt) = self.nvt.as_mut() {
+            let records = nvt.flush_escape(&mut self.output);
+            self.queue_nvt_records(records);
+        }
+    }
+
+    /// Queue the inbound records an NVT client's keystrokes produced
+    fn queue_nvt_records(&mut self, records: Vec<Vec<u8>>) {
+        for data in records {
+            let header = Header::new(DataType::Data3270);
+            log_inbound_record(&header, data.as_slice());
+            self.incoming_records.push_back(Record { header, data });
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        // Clients that never answer DO NEW-ENVIRON are treated as not supporting it, but
+        // once one has agreed, its variables are worth waiting for
+        if self.environ == EnvironState::Requested {
+            return false;
+        }
+        if self.nvt.is_some() {
+            // The window size is worth waiting for, so that the first screen fits
+            return self.naws_settled;
+        }
+        match self.tn3270e {
+            Tn3270eState::Active => true,
+            Tn3270eState::Offered | Tn3270eState::Negotiating => false,
+            Tn3270eState::Refused => {
+                self.ttype == TtypeState::Done
+                    && self.is_settled(TelnetOption::Binary, self.is_bin)
+                    && self.is_settled(TelnetOption::EndOfRecord, self.is_eor)
+            }
+        }
+    }
+
+    /// Queue a record of an arbitrary TN3270E data type. Outside of TN3270E mode, only
+    /// 3270 data can be sent.
+    pub fn queue_record(&mut self, data_type: DataType, record: Vec<u8>) -> Result<(), Error> {
+        if data_type == DataType::Data3270 {
+            self.note_screen_size(record.as_slice())?;
+        }
+        if let Some(nvt) = self.nvt.as_mut() {
+            if data_type != DataType::Data3270 {
+                return Err(Error::new(std::io::ErrorKind::InvalidInput, "NVT terminals only accept 3270 data"));
+            }
+            let command = WriteCommand::parse(record.as_slice())
+                .map_err(|err| Error::new(std::io::ErrorKind::InvalidInput, err))?;
+            log_outbound_record(&Header::new(data_type), record.as_slice());
+            nvt.write(&command, &mut self.output);
+            Ok(())
+        } else if self.is_tn3270e() {
+            self.queue_framed(Header::new(data_type), record);
+            Ok(())
+        } else if data_type == DataType::Data3270 {
+            log_outbound_record(&Header::new(data_type), record.as_slice());
+            self.queue_raw(record);
+            Ok(())
+        } else {
+            Err(Error::new(std::io::ErrorKind::InvalidInput, "TN3270E is not active on this session"))
+        }
+    }
+
+    /// Queue a 3270 data stream record that asks the terminal for a response, returning
+    /// the sequence number the response will carry
+    pub fn queue_record_with_response(&mut self, record: Vec<u8>) -> Result<u16, Error> {
+        if !self.functions.contains(Functions::RESPONSES) {
+            return Err(Error::new(std::io::ErrorKind::InvalidInput, "the client has not agreed to send responses"));
+        }
+
+        self.note_screen_size(record.as_slice())?;
+        let mut header = Header::new(DataType::Data3270);
+        header.response_flag = response_flag::ALWAYS_RESPONSE;
+        let seq_number = self.queue_framed(header, record);
+        self.awaiting_responses.insert(seq_number);
+        Ok(seq_number)
+    }
+
+    /// Keep track of which size an outgoing 3270 record leaves the screen at, refusing a
+    /// write command with a buffer address past the end of it. Records that don't parse as
+    /// write commands, such as structured fields, aren't checked.
+    fn note_screen_size(&mut self, record: &[u8]) -> Result<(), Error> {
+        let previous = self.alternate_screen;
+        match record.first().map(|&code| WriteCommandCode::try_from(code)) {
+            Some(Ok(WriteCommandCode::EraseWrite)) => self.alternate_screen = false,
+            Some(Ok(WriteCommandCode::EraseWriteAlternate)) => self.alternate_screen = true,
+            _ => {}
+        }
+        if let Ok(command) = WriteCommand::parse(record) {
+            if let Err(err) = command.check_addresses(self.screen_size()) {
+                // The record isn't sent, so the screen stays as it was
+                self.alternate_screen = previous;
+                return Err(Error::new(std::io::ErrorKind::InvalidInput, err));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue a record with a TN3270E header, filling in the next sequence number
+    fn queue_framed(&mut self, mut header: Header, mut record: Vec<u8>) -> u16 {
+        header.seq_number = self.send_seq;
+        self.send_seq = self.send_seq.wrapping_add(1);
+        log_outbound_record(&header, record.as_slice());
+
+        let mut framed = Vec::with_capacity(record.len() + tn3270e::HEADER_LEN);
+        header.serialize(&mut framed);
+        framed.append(&mut record);
+        self.queue_raw(framed);
+        header.seq_number
+    }
+
+    fn queue_raw(&mut self, record: Vec<u8>) {
+        self.output.append(&mut Parser::escape_iac(record));
+        self.output.extend_from_slice(&[tn_cmd::IAC, tn_cmd::EOR]);
+    }
+
+    pub fn has_response(&self, seq_number: u16) -> bool {
+        self.responses.contains_key(&seq_number)
+    }
+
+    /// Collect the response to a record, and stop waiting for it if it hasn't arrived
+    pub fn take_response(&mut self, seq_number: u16) -> Option<DeviceResponse> {
+        self.awaiting_responses.remove(&seq_number);
+        self.responses.remove(&seq_number)
+    }
+
+    /// Whether a record or signal is waiting to be picked up
+    pub fn has_event(&self) -> bool {
+        !self.signals.is_empty() || !self.incoming_records.is_empty()
+    }
+
+    /// How many records and signals are waiting to be picked up
+    pub fn pending_events(&self) -> usize {
+        self.signals.len() + self.incoming_records.len()
+    }
+
+    pub fn has_record(&self) -> bool {
+        !self.incoming_records.is_empty()
+    }
+
+    pub fn has_signal(&self) -> bool {
+        !self.signals.is_empty()
+    }
+
+    /// The next queued event. Signals are returned ahead of any records that are already
+    /// queued.
+    pub fn next_event(&mut self) -> Option<Event> {
+        match self.signals.pop_front() {
+            Some(signal) => Some(Event::Signal(signal)),
+            None => self.incoming_records.pop_front().map(Event::Record),
+        }
+    }
+
+    pub fn next_signal(&mut self) -> Option<Signal> {
+        self.signals.pop_front()
+    }
+
+    pub fn next_record(&mut self) -> Option<Record> {
+        self.incoming_records.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tn3270::lu::AdHocLuPool;
+
+    /// A protocol that has finished negotiating TN3270E with the RESPONSES function
+    fn tn3270e_protocol() -> Protocol {
+        let mut protocol = Protocol::new(Arc::new(AdHocLuPool), NegotiationPolicy::default(), StartTlsPolicy::Refuse);
+        protocol.tn3270e = Tn3270eState::Active;
+        protocol.functions = Functions::RESPONSES;
+        protocol
+    }
+
+    /// A positive response to record `seq_number`, as the client sends it
+    fn positive_response(seq_number: u16) -> Vec<u8> {
+        let mut header = Header::new(DataType::Response);
+        header.response_flag = response_flag::POSITIVE_RESPONSE;
+        header.seq_number = seq_number;
+        let mut record = Vec::new();
+        header.serialize(&mut record);
+        record.push(0x00);
+        let mut data = Parser::escape_iac(record);
+        data.extend_from_slice(&[tn_cmd::IAC, tn_cmd::EOR]);
+        data
+    }
+
+    fn contains(output: &[u8], sequence: &[u8]) -> bool {
+        output.windows(sequence.len()).any(|window| window == sequence)
+    }
+
+    #[test]
+    fn classic_negotiation_only_asks_for_ttype() {
+        let mut protocol = Protocol::new(Arc::new(AdHocLuPool), NegotiationPolicy::default(), StartTlsPolicy::Refuse);
+        protocol.start_negotiation().unwrap();
+        protocol.take_output();
+        protocol.receive_bytes(&[tn_cmd::IAC, tn_cmd::WONT, OPT_TN3270E]).unwrap();
+        let output = protocol.take_output();
+        assert!(contains(&output, &[tn_cmd::IAC, tn_cmd::DO, tn_opt::TTYPE]));
+        // We have no terminal type of our own to report
+        assert!(!contains(&output, &[tn_cmd::IAC, tn_cmd::WILL, tn_opt::TTYPE]));
+
+        protocol.receive_bytes(&[tn_cmd::IAC, tn_cmd::WILL, tn_opt::TTYPE]).unwrap();
+        let output = protocol.take_output();
+        assert!(contains(&output, &[tn_cmd::IAC, tn_cmd::SB, tn_opt::TTYPE, tn_cmd::SEND, tn_cmd::IAC, tn_cmd::SE]));
+
+        // The answer still gets through, without the option enabled on our side
+        let mut is = vec![tn_cmd::IAC, tn_cmd::SB, tn_opt::TTYPE, tn_cmd::IS];
+        is.extend_from_slice(b"IBM-3278-2");
+        is.extend_from_slice(&[tn_cmd::IAC, tn_cmd::SE]);
+        protocol.receive_bytes(is.as_slice()).unwrap();
+        assert_eq!(protocol.term_types(), ["IBM-3278-2".to_owned()]);
+    }
+
+    #[test]
+    fn functions_need_a_device_type() {
+        let mut protocol = Protocol::new(Arc::new(AdHocLuPool), NegotiationPolicy::default(), StartTlsPolicy::Refuse);
+        protocol.start_negotiation().unwrap();
+        protocol.receive_bytes(&[tn_cmd::IAC, tn_cmd::WILL, OPT_TN3270E]).unwrap();
+        protocol.take_output();
+
+        let mut request = vec![tn_cmd::IAC, tn_cmd::SB, OPT_TN3270E];
+        request.extend(Subnegotiation::FunctionsRequest(Functions::RESPONSES).serialize());
+        request.extend_from_slice(&[tn_cmd::IAC, tn_cmd::SE]);
+        protocol.receive_bytes(request.as_slice()).unwrap();
+        assert!(!protocol.is_tn3270e());
+        assert_eq!(protocol.lu_name(), None);
+        assert!(protocol.take_output().is_empty());
+    }
+
+    #[test]
+    fn escaped_iac_is_data_between_signals() {
+        let mut protocol = Protocol::new(Arc::new(AdHocLuPool), NegotiationPolicy::default(), StartTlsPolicy::Refuse);
+        protocol.receive_bytes(&[
+            0x7D, tn_cmd::IAC, tn_cmd::IAC, tn_cmd::IAC, telnet::IP, 0x01, tn_cmd::IAC, tn_cmd::IAC, 0xC1,
+            tn_cmd::IAC, tn_cmd::EOR,
+        ]).unwrap();
+        assert_eq!(protocol.next_signal(), Some(Signal::Attention));
+        let record = protocol.next_record().expect("no record");
+        assert_eq!(record.data, [0x7D, 0xFF, 0x01, 0xFF, 0xC1]);
+    }
+
+    #[test]
+    fn timing_marks_are_answered_once() {
+        let mut protocol = tn3270e_protocol();
+        let probe = [tn_cmd::IAC, tn_cmd::DO, tn_opt::TM];
+        for answer in [tn_cmd::WILL, tn_cmd::WONT, tn_cmd::WILL] {
+            protocol.send_timing_mark().unwrap();
+            assert_eq!(protocol.take_output(), probe);
+            // Other traffic isn't an answer
+            protocol.receive_bytes(&[tn_cmd::IAC, tn_cmd::NOP]).unwrap();
+            assert!(!protocol.take_timing_mark_answer());
+
+            protocol.receive_bytes(&[tn_cmd::IAC, answer, tn_opt::TM]).unwrap();
+            assert!(protocol.take_output().is_empty());
+            assert!(protocol.take_timing_mark_answer());
+            assert!(!protocol.take_timing_mark_answer());
+        }
+    }
+
+    #[test]
+    fn keeps_awaited_responses() {
+        let mut protocol = tn3270e_protocol();
+        let seq_number = protocol.queue_record_with_response(vec![0xF1, 0xC2]).unwrap();
+        protocol.receive_bytes(positive_response(seq_number).as_slice()).unwrap();
+        assert!(protocol.has_response(seq_number));
+        assert_eq!(protocol.take_response(seq_number), Some(DeviceResponse::DeviceEnd));
+        assert!(protocol.responses.is_empty());
+    }
+
+    #[test]
+    fn drops_unexpected_responses() {
+        let mut protocol = tn3270e_protocol();
+        // Unsolicited
+        protocol.receive_bytes(positive_response(42).as_slice()).unwrap();
+        assert!(!protocol.has_response(42));
+
+        // Arriving after the caller gave up waiting
+        let seq_number = protocol.queue_record_with_response(vec![0xF1, 0xC2]).unwrap();
+        assert_eq!(protocol.take_response(seq_number), None);
+        protocol.receive_bytes(positive_response(seq_number).as_slice()).unwrap();
+        assert!(protocol.responses.is_empty());
+    }
+
+    #[test]
+    fn device_type_requests_are_capped() {
+        let mut protocol = tn3270e_protocol();
+        for n in 0..MAX_TERM_TYPES * 2 {
+            let request = Subnegotiation::DeviceTypeRequest {
+                device_type: format!("IBM-3278-2-{}", n),
+                connect: None,
+                associate: None,
+            };
+            protocol.handle_tn3270e(request.serialize().as_slice()).unwrap();
+        }
+        assert_eq!(protocol.term_types.len(), MAX_TERM_TYPES);
+    }
+
+    #[test]
+    fn data_after_mccp_subnegotiation_is_kept() {
+        let mut protocol = tn3270e_protocol();
+        let mut data = vec![tn_cmd::IAC, tn_cmd::SB, tn_opt::MCCP2, tn_cmd::IAC, tn_cmd::SE];
+        Header::new(DataType::Data3270).serialize(&mut data);
+        data.extend_from_slice(&[0x7D, 0x40, 0x40, tn_cmd::IAC, tn_cmd::EOR]);
+        protocol.receive_bytes(data.as_slice()).unwrap();
+        let record = protocol.next_record().expect("record was dropped");
+        assert_eq!(record.data, [0x7D, 0x40, 0x40]);
+    }
+}
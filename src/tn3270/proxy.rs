@@ -0,0 +1,329 @@
+//! The PROXY protocol (versions 1 and 2), which load balancers such as HAProxy use to pass
+//! on the address of the client they're forwarding, ahead of anything the client sends.
+//!
+//! Sessions read the header themselves when
+//! [`NegotiationPolicy::proxy_protocol`](crate::tn3270::negotiation::NegotiationPolicy::proxy_protocol)
+//! is set. For TLS from the first byte, the header comes before the handshake, so it has
+//! to be read with [`ProxyHeader::read_until`] before the connection is handed to the
+//! acceptor, and given to the session in
+//! [`NegotiationPolicy::proxy_header`](crate::tn3270::negotiation::NegotiationPolicy::proxy_header),
+//! or with [`ProxyHeader::read_async`] for a tokio stream.
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use snafu::{ResultExt, Snafu};
+
+use crate::tn3270::transport::Transport;
+
+/// How a version 2 header starts
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a version 1 header can be, line ending included
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum ProxyError {
+    #[snafu(display("I/O error reading the PROXY header: {}", source))]
+    Io { source: std::io::Error },
+    #[snafu(display("The connection didn't start with a PROXY header"))]
+    MissingHeader,
+    #[snafu(display("Unsupported PROXY protocol version {}", version))]
+    UnsupportedVersion { version: u8 },
+    #[snafu(display("Malformed PROXY header: {}", reason))]
+    Malformed { reason: &'static str },
+}
+
+/// What the proxy said about the connection
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ProxyHeader {
+    pub version: u8,
+    /// Where the client connected from. `None` if the proxy didn't say, as for health
+    /// checks from the proxy itself.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to
+    pub destination: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Read a header from the start of a connection. Nothing past the header is read.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, ProxyError> {
+        let mut start = [0; 6];
+        reader.read_exact(&mut start).context(Io)?;
+        if start == *b"PROXY " {
+            Self::read_v1(reader)
+        } else if start == V2_SIGNATURE[..6] {
+            Self::read_v2(reader)
+        } else {
+            Err(ProxyError::MissingHeader)
+        }
+    }
+
+    /// Like [`ProxyHeader::read_from`], but failing with
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut) once `deadline` passes, however slowly
+    /// the header arrives. The transport is left blocking, with a read timeout set.
+    pub fn read_until(stream: &mut impl Transport, deadline: Instant) -> Result<Self, ProxyError> {
+        stream.set_nonblocking(false).context(Io)?;
+        Self::read_from(&mut UntilDeadline { stream, deadline })
+    }
+
+    /// Like [`ProxyHeader::read_from`], for a tokio stream. Nothing past the header is
+    /// read.
+    #[cfg(feature = "async")]
+    pub async fn read_async(reader: &mut (impl tokio::io::AsyncRead + Unpin)) -> Result<Self, ProxyError> {
+        use tokio::io::AsyncReadExt;
+        // Collect exactly the bytes of the header, then parse them as read_from would
+        let mut header = vec![0; 6];
+        reader.read_exact(&mut header).await.context(Io)?;
+        if header == b"PROXY " {
+            while !header.ends_with(b"\r\n") && header.len() < V1_MAX_LEN {
+                header.push(reader.read_u8().await.context(Io)?);
+            }
+        } else if header == V2_SIGNATURE[..6] {
+            header.resize(16, 0);
+            reader.read_exact(&mut header[6..]).await.context(Io)?;
+            if header[6..12] != V2_SIGNATURE[6..] {
+                return Err(ProxyError::MissingHeader);
+            }
+            let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+            header.resize(16 + len, 0);
+            reader.read_exact(&mut header[16..]).await.context(Io)?;
+        }
+        Self::read_from(&mut header.as_slice())
+    }
+
+    /// The text version: `PROXY TCP4 <source> <destination> <source port> <destination
+    /// port>\r\n`, or `PROXY UNKNOWN` followed by anything
+    fn read_v1(reader: &mut impl Read) -> Result<Self, ProxyError> {
+        let mut line = b"PROXY ".to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(ProxyError::Malformed { reason: "header line too long" });
+            }
+            let mut byte = [0];
+            reader.read_exact(&mut byte).context(Io)?;
+            line.push(byte[0]);
+        }
+
+        let line = std::str::from_utf8(&line[..line.len() - 2])
+            .map_err(|_| ProxyError::Malformed { reason: "header isn't ASCII" })?;
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (family, source, destination, source_port, destination_port) = match fields[..] {
+            [_, "UNKNOWN", ..] => return Ok(ProxyHeader { version: 1, source: None, destination: None }),
+            [_, family, source, destination, source_port, destination_port] => {
+                (family, source, destination, source_port, destination_port)
+            }
+            _ => return Err(ProxyError::Malformed { reason: "wrong number of fields" }),
+        };
+
+        let parse_ip = |text: &str| -> Result<IpAddr, ProxyError> {
+            let ip = text.parse()
+                .map_err(|_| ProxyError::Malformed { reason: "invalid address" })?;
+            match (family, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(ip),
+                ("TCP4", _) | ("TCP6", _) => Err(ProxyError::Malformed { reason: "address doesn't match the protocol" }),
+                _ => Err(ProxyError::Malformed { reason: "unknown protocol" }),
+            }
+        };
+        let parse_port = |text: &str| -> Result<u16, ProxyError> {
+            text.parse().map_err(|_| ProxyError::Malformed { reason: "invalid port" })
+        };
+        Ok(ProxyHeader {
+            version: 1,
+            source: Some(SocketAddr::new(parse_ip(source)?, parse_port(source_port)?)),
+            destination: Some(SocketAddr::new(parse_ip(destination)?, parse_port(destination_port)?)),
+        })
+    }
+
+    /// The binary version: the rest of the signature, version and command, address family
+    /// and protocol, then the length of the addresses and anything after them
+    fn read_v2(reader: &mut impl Read) -> Result<Self, ProxyError> {
+        let mut rest = [0; 10];
+        reader.read_exact(&mut rest).context(Io)?;
+        if rest[..6] != V2_SIGNATURE[6..] {
+            return Err(ProxyError::MissingHeader);
+        }
+        let (version_command, family_protocol) = (rest[6], rest[7]);
+        let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body).context(Io)?;
+
+        let version = version_command >> 4;
+        if version != 2 {
+            return Err(ProxyError::UnsupportedVersion { version });
+        }
+        match version_command & 0x0F {
+            // LOCAL: the proxy's own connection, so the real addresses are the right ones
+            0x0 => return Ok(ProxyHeader { version, source: None, destination: None }),
+            0x1 => {}
+            _ => return Err(ProxyError::Malformed { reason: "unknown command" }),
+        }
+
+        let (source, destination) = match family_protocol >> 4 {
+            // IPv4
+            0x1 => {
+                let addresses = body.get(..12)
+                    .ok_or(ProxyError::Malformed { reason: "IPv4 addresses cut short" })?;
+                let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(addresses[at], addresses[at + 1], addresses[at + 2], addresses[at + 3]));
+                let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+                (SocketAddr::new(ip(0), port(8)), SocketAddr::new(ip(4), port(10)))
+            }
+            // IPv6
+            0x2 => {
+                let addresses = body.get(..36)
+                    .ok_or(ProxyError::Malformed { reason: "IPv6 addresses cut short" })?;
+                let ip = |at: usize| {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&addresses[at..at + 16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+                (SocketAddr::new(ip(0), port(32)), SocketAddr::new(ip(16), port(34)))
+            }
+            // Unspecified or Unix sockets, neither of which has an address worth reporting
+            0x0 | 0x3 => return Ok(ProxyHeader { version, source: None, destination: None }),
+            _ => return Err(ProxyError::Malformed { reason: "unknown address family" }),
+        };
+        Ok(ProxyHeader { version, source: Some(source), destination: Some(destination) })
+    }
+}
+
+/// Shrinks the transport's read timeout before each read, so that a header sent a byte at
+/// a time can't take longer than the deadline
+struct UntilDeadline<'a, T> {
+    stream: &'a mut T,
+    deadline: Instant,
+}
+
+impl<'a, T: Transport> Read for UntilDeadline<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "PROXY header timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::tn3270::memory::duplex;
+
+    fn parse(header: &[u8]) -> Result<ProxyHeader, ProxyError> {
+        ProxyHeader::read_from(&mut &header[..])
+    }
+
+    /// A version 2 header with `command` and the given address family and addresses
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20 | command, family << 4 | 0x1]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let header = parse(b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 23\r\n").unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.source, Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(header.destination, Some("198.51.100.2:23".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let header = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 992\r\n").unwrap();
+        assert_eq!(header.source, Some("[2001:db8::1]:51234".parse().unwrap()));
+        assert_eq!(header.destination, Some("[2001:db8::2]:992".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let header = parse(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n").unwrap();
+        assert_eq!(header, ProxyHeader { version: 1, source: None, destination: None });
+    }
+
+    #[test]
+    fn v1_stops_at_the_header() {
+        let mut input = &b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 23\r\n\xff\xfb\x18"[..];
+        ProxyHeader::read_from(&mut input).unwrap();
+        assert_eq!(input, b"\xff\xfb\x18");
+    }
+
+    #[test]
+    fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 51234\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 198.51.100.2 51234 23\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51234 99999\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 51234 23\r\n",
+        ] {
+            assert!(matches!(parse(header), Err(ProxyError::Malformed { .. })), "{:?}", String::from_utf8_lossy(header));
+        }
+        let too_long = [&b"PROXY "[..], &[b'x'; V1_MAX_LEN]].concat();
+        assert!(matches!(parse(too_long.as_slice()), Err(ProxyError::Malformed { .. })));
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let header = parse(v2(0x1, 0x1, &[192, 0, 2, 1, 198, 51, 100, 2, 0xC8, 0x22, 0, 23]).as_slice()).unwrap();
+        assert_eq!(header.version, 2);
+        assert_eq!(header.source, Some("192.0.2.1:51234".parse().unwrap()));
+        assert_eq!(header.destination, Some("198.51.100.2:23".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local() {
+        let header = parse(v2(0x0, 0x0, &[]).as_slice()).unwrap();
+        assert_eq!(header, ProxyHeader { version: 2, source: None, destination: None });
+    }
+
+    #[test]
+    fn v2_malformed() {
+        assert!(matches!(parse(v2(0x1, 0x1, &[192, 0, 2, 1]).as_slice()), Err(ProxyError::Malformed { .. })));
+        assert!(matches!(parse(v2(0x5, 0x1, &[0; 12]).as_slice()), Err(ProxyError::Malformed { .. })));
+        let mut version_3 = v2(0x1, 0x1, &[0; 12]);
+        version_3[12] = 0x31;
+        assert!(matches!(parse(version_3.as_slice()), Err(ProxyError::UnsupportedVersion { version: 3 })));
+    }
+
+    #[test]
+    fn missing_header() {
+        assert!(matches!(parse(b"\xff\xfd\x18\xff\xfb\x18"), Err(ProxyError::MissingHeader)));
+        assert!(matches!(parse(b"PROX"), Err(ProxyError::Io { .. })));
+    }
+
+    #[test]
+    fn read_until_has_one_deadline() {
+        let (mut server, mut client) = duplex();
+        // A header line that never ends, a byte at a time, each well within the deadline
+        let trickle = std::thread::spawn(move || {
+            if client.write_all(b"PROXY ").is_err() {
+                return;
+            }
+            for _ in 0..100 {
+                std::thread::sleep(Duration::from_millis(20));
+                if client.write_all(b" ").is_err() {
+                    return;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        let result = ProxyHeader::read_until(&mut server, started + Duration::from_millis(200));
+        match result {
+            // Sockets report a read timeout as WouldBlock
+            Err(ProxyError::Io { source }) => {
+                assert!(matches!(source.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock));
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        drop(server);
+        trickle.join().unwrap();
+    }
+}
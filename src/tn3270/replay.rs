@@ -0,0 +1,438 @@
+//! Replaying a recorded session against the application, to catch screens that have
+//! changed.
+//!
+//! A [`Recording`] is read from a trace written by
+//! [`TraceRecorder`](crate::tn3270::trace::TraceRecorder) (or by x3270's `-trace`, which
+//! uses the same format). [`Replay::run`] then hands the application a session connected
+//! to a [`FakeClient`] that plays the terminal's half of the recording back: each recorded
+//! inbound record (and ATTN) is sent once everything the application sent before it in
+//! the recording has been seen. The first outbound record that doesn't match is reported
+//! as a [`Divergence`], with both versions decoded as write commands.
+//!
+//! The fake terminal always negotiates plain TN3270 with the recorded terminal type, so
+//! TN3270E headers are stripped from the recording, and only 3270 data records are
+//! replayed and compared.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use libtelnet_rs::telnet::{op_command as tn_cmd, op_option as tn_opt};
+use snafu::{ResultExt, Snafu};
+
+use crate::tn3270::Session;
+use crate::tn3270::lu::{AdHocLuPool, LuPool};
+use crate::tn3270::memory::{duplex, MemoryStream};
+use crate::tn3270::negotiation::{NegotiationError, NegotiationPolicy};
+use crate::tn3270::stream::{StreamFormatError, WriteCommand};
+use crate::tn3270::telnet;
+use crate::tn3270::testing::FakeClient;
+use crate::tn3270::tn3270e::{self, DataType, Subnegotiation, OPT_TN3270E};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum ReplayError {
+    #[snafu(display("Failed to open {}: {}", path.display(), source))]
+    OpenTrace { path: PathBuf, source: std::io::Error },
+    #[snafu(display("Failed to read the trace: {}", source))]
+    ReadTrace { source: std::io::Error },
+    #[snafu(display("Invalid hex data on line {} of the trace", line))]
+    InvalidTrace { line: usize },
+    #[snafu(display("The application's session failed to negotiate: {}", source))]
+    Negotiation { source: NegotiationError },
+    #[snafu(display("{}", divergence))]
+    Diverged { divergence: Divergence },
+}
+
+/// One thing that happened in a recorded session
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// A 3270 data record from the terminal
+    Inbound(Vec<u8>),
+    /// The terminal's ATTN key
+    Attention,
+    /// A 3270 data record from the application
+    Outbound(Vec<u8>),
+}
+
+/// The 3270 traffic of a recorded session
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    /// The terminal type the client settled on; `IBM-3278-2` if the trace doesn't say
+    pub term_type: String,
+    pub steps: Vec<Step>,
+}
+
+impl Recording {
+    /// Read a trace file
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let path = path.as_ref();
+        let file = File::open(path).context(OpenTrace { path })?;
+        Self::parse(BufReader::new(file))
+    }
+
+    /// Read a trace. Lines other than hex dumps are skipped.
+    pub fn parse(trace: impl BufRead) -> Result<Self, ReplayError> {
+        let mut inbound = TelnetReader::default();
+        let mut outbound = TelnetReader::default();
+        let mut items = Vec::new();
+        let mut recording = Recording::default();
+        let mut tn3270e = false;
+
+        for (number, line) in trace.lines().enumerate() {
+            let line = line.context(ReadTrace)?;
+            let mut words = line.split_whitespace();
+            let (direction, offset, data) = match (words.next(), words.next(), words.next()) {
+                (Some(direction), Some(offset), Some(data)) => (direction, offset, data),
+                _ => continue,
+            };
+            if !offset.starts_with("0x") {
+                continue;
+            }
+            let data = hex::decode(data).map_err(|_| ReplayError::InvalidTrace { line: number + 1 })?;
+            // The trace is written from the terminal's side
+            match direction {
+                ">" => inbound.feed(data.as_slice(), &mut items),
+                "<" => outbound.feed(data.as_slice(), &mut items),
+                _ => continue,
+            }
+
+            for item in items.drain(..) {
+                let is_inbound = direction == ">";
+                match item {
+                    Item::Record(record) => {
+                        let record = if tn3270e {
+                            match tn3270e::Header::parse(record.as_slice()) {
+                                Ok((header, body)) if header.data_type == DataType::Data3270 => body.to_vec(),
+                                _ => continue,
+                            }
+                        } else {
+                            record
+                        };
+                        recording.steps.push(if is_inbound { Step::Inbound(record) } else { Step::Outbound(record) });
+                    }
+                    Item::Command(telnet::IP | telnet::BRK) if is_inbound => recording.steps.push(Step::Attention),
+                    Item::Command(_) => {}
+                    Item::Negotiation(command, OPT_TN3270E) if is_inbound => tn3270e = command == tn_cmd::WILL,
+                    Item::Negotiation(..) => {}
+                    Item::Subnegotiation(option, body) if is_inbound => match option {
+                        tn_opt::TTYPE if body.first() == Some(&tn_cmd::IS) => {
+                            recording.term_type = String::from_utf8_lossy(&body[1..]).into_owned();
+                        }
+                        OPT_TN3270E => {
+                            if let Ok(Subnegotiation::DeviceTypeRequest { device_type, .. }) = Subnegotiation::parse(body.as_slice()) {
+                                recording.term_type = device_type;
+                            }
+                        }
+                        _ => {}
+                    },
+                    Item::Subnegotiation(..) => {}
+                }
+            }
+        }
+
+        if recording.term_type.is_empty() {
+            recording.term_type = "IBM-3278-2".to_owned();
+        }
+        Ok(recording)
+    }
+}
+
+/// What a [`TelnetReader`] found
+enum Item {
+    Record(Vec<u8>),
+    Command(u8),
+    Negotiation(u8, u8),
+    Subnegotiation(u8, Vec<u8>),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ReaderState {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Splits one direction of a recorded telnet stream into records and commands
+struct TelnetReader {
+    state: ReaderState,
+    record: Vec<u8>,
+    subnegotiation: Vec<u8>,
+}
+
+impl Default for TelnetReader {
+    fn default() -> Self {
+        TelnetReader { state: ReaderState::Data, record: Vec::new(), subnegotiation: Vec::new() }
+    }
+}
+
+impl TelnetReader {
+    fn feed(&mut self, data: &[u8], items: &mut Vec<Item>) {
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (ReaderState::Data, tn_cmd::IAC) => ReaderState::Iac,
+                (ReaderState::Data, _) => {
+                    self.record.push(byte);
+                    ReaderState::Data
+                }
+                (ReaderState::Iac, tn_cmd::IAC) => {
+                    self.record.push(byte);
+                    ReaderState::Data
+                }
+                (ReaderState::Iac, tn_cmd::EOR) => {
+                    items.push(Item::Record(std::mem::take(&mut self.record)));
+                    ReaderState::Data
+                }
+                (ReaderState::Iac, tn_cmd::SB) => {
+                    self.subnegotiation.clear();
+                    ReaderState::Subnegotiation
+                }
+                (ReaderState::Iac, tn_cmd::WILL..=tn_cmd::DONT) => ReaderState::Negotiation(byte),
+                (ReaderState::Iac, _) => {
+                    items.push(Item::Command(byte));
+                    ReaderState::Data
+                }
+                (ReaderState::Negotiation(command), _) => {
+                    items.push(Item::Negotiation(command, byte));
+                    ReaderState::Data
+                }
+                (ReaderState::Subnegotiation, tn_cmd::IAC) => ReaderState::SubnegotiationIac,
+                (ReaderState::Subnegotiation, _) => {
+                    self.subnegotiation.push(byte);
+                    ReaderState::Subnegotiation
+                }
+                (ReaderState::SubnegotiationIac, tn_cmd::SE) => {
+                    if let Some((&option, body)) = self.subnegotiation.split_first() {
+                        items.push(Item::Subnegotiation(option, body.to_vec()));
+                    }
+                    ReaderState::Data
+                }
+                (ReaderState::SubnegotiationIac, _) => {
+                    self.subnegotiation.push(byte);
+                    ReaderState::Subnegotiation
+                }
+            };
+        }
+    }
+}
+
+/// The first outbound record that didn't match the recording
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Divergence {
+    /// Which outbound record it was, counting from zero
+    pub index: usize,
+    /// The recorded record, or `None` if the application sent more than was recorded
+    pub expected: Option<Vec<u8>>,
+    /// What the application sent, or `None` if it stopped short
+    pub actual: Option<Vec<u8>>,
+}
+
+impl Divergence {
+    pub fn expected_write(&self) -> Option<Result<WriteCommand, StreamFormatError>> {
+        self.expected.as_deref().map(WriteCommand::parse)
+    }
+
+    pub fn actual_write(&self) -> Option<Result<WriteCommand, StreamFormatError>> {
+        self.actual.as_deref().map(WriteCommand::parse)
+    }
+}
+
+/// Decoded if possible, and in hex if not
+fn describe(f: &mut fmt::Formatter<'_>, record: Option<&[u8]>) -> fmt::Result {
+    match record {
+        None => write!(f, "nothing"),
+        Some(record) => match WriteCommand::parse(record) {
+            Ok(command) => write!(f, "{:#?}", command),
+            Err(err) => write!(f, "{} ({})", hex::encode(record), err),
+        },
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Outbound record {} differs from the recording.\nExpected: ", self.index)?;
+        describe(f, self.expected.as_deref())?;
+        write!(f, "\nActual: ")?;
+        describe(f, self.actual.as_deref())
+    }
+}
+
+/// Records match if they decode to the same write command, even if they were encoded
+/// differently (say, with 12- rather than 14-bit addresses)
+fn same_record(expected: &[u8], actual: &[u8]) -> bool {
+    match (WriteCommand::parse(expected), WriteCommand::parse(actual)) {
+        (Ok(expected), Ok(actual)) => expected == actual,
+        _ => expected == actual,
+    }
+}
+
+/// Plays a [`Recording`] back against the application
+pub struct Replay {
+    recording: Recording,
+    timeout: Duration,
+    lu_pool: Arc<dyn LuPool>,
+    policy: NegotiationPolicy,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Self {
+        Replay {
+            recording,
+            timeout: Duration::from_secs(5),
+            lu_pool: Arc::new(AdHocLuPool),
+            policy: NegotiationPolicy::default(),
+        }
+    }
+
+    /// The LU pool the application's session is created with, as it would be in
+    /// production
+    pub fn with_lu_pool(mut self, lu_pool: Arc<dyn LuPool>) -> Self {
+        self.lu_pool = lu_pool;
+        self
+    }
+
+    /// The policy the application's session negotiates with. The fake terminal refuses
+    /// TN3270E and sends no PROXY header, so a policy that requires either fails.
+    pub fn with_policy(mut self, policy: NegotiationPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// How long to wait for each outbound record before deciding the application isn't
+    /// going to send it
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run `app` on a session driven by the recording, returning the first divergence.
+    /// Once the recording runs out (or the application diverges), the fake terminal hangs
+    /// up, so `app` should return once its session reports the disconnect.
+    pub fn run<F, R>(&self, app: F) -> Result<(), ReplayError>
+        where F: FnOnce(Session<MemoryStream>) -> R + Send + 'static
+    {
+        let (server, client) = duplex();
+        let mut client = FakeClient::start(client, self.recording.term_type.as_str());
+        let lu_pool = self.lu_pool.clone();
+        let policy = self.policy.clone();
+        let app = std::thread::spawn(move || {
+            let session = Session::with_policy(server, lu_pool, policy)?;
+            app(session);
+            Ok(())
+        });
+
+        let mut index = 0;
+        let mut divergence = None;
+        for step in self.recording.steps.iter() {
+            match step {
+                Step::Inbound(record) => {
+                    if client.send_record(record.as_slice()).is_err() {
+                        break;
+                    }
+                }
+                Step::Attention => {
+                    if client.send_attention().is_err() {
+                        break;
+                    }
+                }
+                Step::Outbound(expected) => {
+                    let actual = client.next_record(self.timeout);
+                    if actual.as_deref().map_or(true, |actual| !same_record(expected, actual)) {
+                        divergence = Some(Divergence { index, expected: Some(expected.clone()), actual });
+                        break;
+                    }
+                    index += 1;
+                }
+            }
+        }
+        // Anything the application sends after the recording ends is a divergence too
+        if divergence.is_none() {
+            if let Some(actual) = client.next_record(self.timeout) {
+                divergence = Some(Divergence { index, expected: None, actual: Some(actual) });
+            }
+        }
+
+        client.disconnect();
+        let negotiated: Result<(), NegotiationError> = app.join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        negotiated.context(Negotiation)?;
+        match divergence {
+            Some(divergence) => Err(ReplayError::Diverged { divergence }),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tn3270::{Event, Signal};
+
+    /// A TN3270E session: a screen, Enter, then ATTN and a second write
+    const TRACE: &str = include_str!("testdata/tn3270e.trc");
+
+    const SCREEN: [u8; 12] = [0xF5, 0xC3, 0x11, 0x40, 0x40, 0x1D, 0x60, 0xC8, 0xC5, 0xD3, 0xD3, 0xD6];
+    const RESTORE: [u8; 2] = [0xF1, 0xC2];
+
+    fn recording() -> Recording {
+        Recording::parse(TRACE.as_bytes()).unwrap()
+    }
+
+    /// Plays the recorded application's part, sending `screen` first
+    fn app(screen: Vec<u8>) -> impl FnOnce(Session<MemoryStream>) + Send + 'static {
+        move |mut session| {
+            session.send_record(screen).unwrap();
+            if session.receive_record(None).is_err() {
+                return;
+            }
+            if let Ok(Some(Event::Signal(Signal::Attention))) = session.receive_event(None) {
+                session.send_record(RESTORE.to_vec()).unwrap();
+            }
+            while session.receive_event(None).is_ok() {}
+        }
+    }
+
+    #[test]
+    fn parses_trace() {
+        let recording = recording();
+        assert_eq!(recording.term_type, "IBM-3278-4-E");
+        // TN3270E headers are stripped, and records other than 3270 data are left out
+        assert_eq!(recording.steps, vec![
+            Step::Outbound(SCREEN.to_vec()),
+            Step::Inbound(vec![0x7D, 0x40, 0x40]),
+            Step::Attention,
+            Step::Outbound(RESTORE.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn matching_app_replays_cleanly() {
+        Replay::new(recording())
+            .with_timeout(Duration::from_millis(500))
+            .run(app(SCREEN.to_vec()))
+            .unwrap();
+    }
+
+    #[test]
+    fn diverging_app_is_reported() {
+        let mut changed = SCREEN.to_vec();
+        // HELLO becomes HELLA
+        *changed.last_mut().unwrap() = 0xC1;
+        let result = Replay::new(recording())
+            .with_timeout(Duration::from_millis(500))
+            .run(app(changed.clone()));
+        match result {
+            Err(ReplayError::Diverged { divergence }) => {
+                assert_eq!(divergence.index, 0);
+                assert_eq!(divergence.expected, Some(SCREEN.to_vec()));
+                assert_eq!(divergence.actual, Some(changed));
+            }
+            other => panic!("expected a divergence, got {:?}", other),
+        }
+    }
+}
@@ -1,6 +1,14 @@
 use crate::tn3270::stream::{ExtendedFieldAttribute, AID, WriteCommand, WriteCommandCode, WCC, WriteOrder, BufferAddressCalculator, FieldAttribute, StreamFormatError, IncomingRecord};
-use crate::tn3270::Session;
-use snafu::{Snafu, ResultExt};
+use crate::tn3270::{Event, Session, Signal};
+use crate::tn3270::transport::Transport;
+use crate::tn3270::keepalive::KeepaliveError;
+use crate::tn3270::idle::IdleError;
+use crate::tn3270::terminal::TerminalModel;
+use crate::tn3270::tn3270e::DataType;
+#[cfg(feature = "async")]
+use crate::tn3270::async_session::AsyncSession;
+use snafu::{Snafu, ResultExt, ensure};
+use tracing::debug;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Address {
@@ -16,8 +24,8 @@ pub enum FieldData<'a> {
 impl<'a> AsRef<str> for FieldData<'a> {
     fn as_ref(&self) -> &str {
         match self {
-            FieldData::RO(data) => *data,
-            FieldData::RW(data) => &**data,
+            FieldData::RO(data) => data,
+            FieldData::RW(data) => data,
         }
     }
 }
@@ -75,64 +83,152 @@ pub struct Response {
 pub enum ScreenError {
     IoError { context: &'static str, source: std::io::Error },
     StreamError { source: StreamFormatError },
+    #[snafu(display("Interrupted by {:?}", signal))]
+    Interrupted { signal: Signal },
+    #[snafu(display("The terminal disconnected"))]
+    Disconnected,
+    #[snafu(display("The terminal stopped responding"))]
+    DeadPeer,
+    #[snafu(display("The terminal was idle for {:?}", idle_for))]
+    IdleTimeout { idle_for: std::time::Duration },
+    #[snafu(display("Field at row {}, column {} is off the screen", row, col))]
+    FieldOutOfBounds { row: u16, col: u16 },
+}
+
+/// Pick out a terminal that has gone away, stopped responding or been left idle from other
+/// I/O errors
+fn session_failed(context: &'static str, err: std::io::Error, closed: bool) -> ScreenError {
+    use std::io::ErrorKind;
+    let hung_up = matches!(err.kind(), ErrorKind::UnexpectedEof | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted);
+    if closed || hung_up {
+        ScreenError::Disconnected
+    } else if let Some(KeepaliveError::DeadPeer { .. }) = KeepaliveError::from_io_error(&err) {
+        ScreenError::DeadPeer
+    } else if let Some(IdleError::TimedOut { idle_for }) = IdleError::from_io_error(&err) {
+        ScreenError::IdleTimeout { idle_for: *idle_for }
+    } else {
+        ScreenError::IoError { context, source: err }
+    }
+}
+
+/// The erase that gives a screen the most room on `model`, and the size it leaves it at.
+/// Without `use_alternate`, that's always the default size.
+fn erase_for(model: TerminalModel, use_alternate: bool) -> (WriteCommandCode, BufferAddressCalculator) {
+    if use_alternate && model.alternate_size != model.default_size {
+        (WriteCommandCode::EraseWriteAlternate, model.alternate_size)
+    } else {
+        (WriteCommandCode::EraseWrite, model.default_size)
+    }
 }
 
 impl<'a> Screen<'a> {
-    pub fn present(&mut self, session: &mut Session) -> Result<Response, ScreenError> {
-        let acalc = BufferAddressCalculator {
-            width: 80,
-            height: 24,
+    /// Present the screen, sized for the session's terminal model. Models with a larger
+    /// alternate size are switched to it with an Erase/Write Alternate, unless the session
+    /// has been told not to with [`Session::set_use_alternate_size`].
+    pub fn present<T: Transport>(&mut self, session: &mut Session<T>) -> Result<Response, ScreenError> {
+        let span = session.span().clone();
+        let _entered = span.enter();
+        debug!(fields = self.fields.len(), "presenting screen");
+        let (code, size) = erase_for(session.terminal_model(), session.uses_alternate_size());
+        let command = self.write_command(code, size)?;
+        session.send_record(&command)
+            .map_err(|err| session_failed("Failed to send screen", err, session.is_closed()))?;
+
+        let response = loop {
+            let event = session.receive_event(None)
+                .map_err(|err| session_failed("Failed to read response", err, session.is_closed()))?
+                // Without a timeout, the only way to get nothing is for the client to leave
+                .ok_or(ScreenError::Disconnected)?;
+            match event {
+                Event::Record(record) if record.header.data_type == DataType::Data3270 => break record.data,
+                // Anything other than 3270 data isn't a response to the screen
+                Event::Record(_) => {}
+                Event::Signal(signal) => return Err(ScreenError::Interrupted { signal }),
+            }
         };
 
-        {
-            let command = WriteCommand {
-                command: WriteCommandCode::EraseWrite,
-                wcc: WCC::RESET_MDT | WCC::KBD_RESTORE,
-                orders: self.fields.iter()
-                    .flat_map(|field| {
-                        use std::iter::*;
-                        let Address { row, col } = field.address;
-                        let bufaddr = acalc.encode_address(row, col);
-
-                        let ro = if let FieldData::RO(_) = field.data { true } else { false };
-
-                        let mut field_attr = field.attrs.clone();
-                        let mut have_fa = false;
-                        for attr in field_attr.iter_mut() {
-                            if let ExtendedFieldAttribute::FieldAttribute(attr) = attr {
-                                attr.set(FieldAttribute::PROTECTED, ro);
-                                have_fa = true;
-                            }
-                        }
-                        if !have_fa {
-                            field_attr.insert(0, ExtendedFieldAttribute::FieldAttribute(if ro {
-                                FieldAttribute::PROTECTED
-                            } else {
-                                FieldAttribute::NONE
-                            }));
-                        }
+        self.read_response(response.as_slice(), size)
+    }
+
+    /// Like [`Screen::present`], for an [`AsyncSession`]
+    #[cfg(feature = "async")]
+    pub async fn present_async<S>(&mut self, session: &mut AsyncSession<S>) -> Result<Response, ScreenError>
+        where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+    {
+        session.span().in_scope(|| debug!(fields = self.fields.len(), "presenting screen"));
+        let (code, size) = erase_for(session.terminal_model(), session.uses_alternate_size());
+        let command = self.write_command(code, size)?;
+        session.send_record(&command).await
+            .map_err(|err| session_failed("Failed to send screen", err, session.is_closed()))?;
 
-                        vec![
-                            WriteOrder::SetBufferAddress(bufaddr),
-                            WriteOrder::StartFieldExtended(field_attr),
-                            WriteOrder::SendText(field.data.as_ref().to_owned()) ,
-                            WriteOrder::StartField(FieldAttribute::PROTECTED),
-                        ].into_iter()
-                    })
-                    .collect()
-            };
-            // eprintln!("Sending command: {:#?}", &command);
-            session.send_record(&command).context(IoError { context: "Failed to send screen" })?;
+        let response = loop {
+            let event = session.receive_event(None).await
+                .map_err(|err| session_failed("Failed to read response", err, session.is_closed()))?
+                // Without a timeout, the only way to get nothing is for the client to leave
+                .ok_or(ScreenError::Disconnected)?;
+            match event {
+                Event::Record(record) if record.header.data_type == DataType::Data3270 => break record.data,
+                // Anything other than 3270 data isn't a response to the screen
+                Event::Record(_) => {}
+                Event::Signal(signal) => return Err(ScreenError::Interrupted { signal }),
+            }
+        };
+
+        session.span().in_scope(|| self.read_response(response.as_slice(), size))
+    }
+
+    fn write_command(&self, code: WriteCommandCode, acalc: BufferAddressCalculator) -> Result<WriteCommand, ScreenError> {
+        for field in self.fields.iter() {
+            let Address { row, col } = field.address;
+            ensure!(acalc.contains(row, col), FieldOutOfBounds { row, col });
         }
 
-        let response = session.receive_record(None)
-            .context(IoError { context: "Failed to read response" })?
-            .unwrap(); // We can't get a None if we don't have a timeout
+        let command = WriteCommand {
+            command: code,
+            wcc: WCC::RESET_MDT | WCC::KBD_RESTORE,
+            orders: self.fields.iter()
+                .flat_map(|field| {
+                    use std::iter::*;
+                    let Address { row, col } = field.address;
+                    let bufaddr = acalc.encode_address(row, col);
 
-        let incoming = IncomingRecord::parse_record(response.as_slice())
-            .context(StreamError)?;
+                    let ro = matches!(field.data, FieldData::RO(_));
 
-        // eprintln!("Received: {:?}", incoming);
+                    let mut field_attr = field.attrs.clone();
+                    let mut have_fa = false;
+                    for attr in field_attr.iter_mut() {
+                        if let ExtendedFieldAttribute::FieldAttribute(attr) = attr {
+                            attr.set(FieldAttribute::PROTECTED, ro);
+                            have_fa = true;
+                        }
+                    }
+                    if !have_fa {
+                        field_attr.insert(0, ExtendedFieldAttribute::FieldAttribute(if ro {
+                            FieldAttribute::PROTECTED
+                        } else {
+                            FieldAttribute::NONE
+                        }));
+                    }
+
+                    vec![
+                        WriteOrder::SetBufferAddress(bufaddr),
+                        WriteOrder::StartFieldExtended(field_attr),
+                        WriteOrder::SendText(field.data.as_ref().to_owned()) ,
+                        WriteOrder::StartField(FieldAttribute::PROTECTED),
+                    ].into_iter()
+                })
+                .collect(),
+        };
+        // The fields fit, but make sure nothing they were turned into addresses past the end
+        command.check_addresses(acalc).context(StreamError)?;
+        Ok(command)
+    }
+
+    /// Copy the modified fields in the terminal's response back into the screen
+    fn read_response(&mut self, response: &[u8], acalc: BufferAddressCalculator) -> Result<Response, ScreenError> {
+        let incoming = IncomingRecord::parse_record(response)
+            .context(StreamError)?;
+        debug!(aid = ?incoming.aid, "screen answered");
 
         let mut incoming_addr = !0;
         for order in incoming.orders {
@@ -143,7 +239,7 @@ impl<'a> Screen<'a> {
                 WriteOrder::SendText(text) => {
                     // TODO: Handle text that comes as multiple orders
                     for field in self.fields.iter_mut() {
-                        if acalc.encode_address(field.address.row, field.address.col) == incoming_addr - 1 {
+                        if acalc.encode_address(field.address.row, field.address.col) == incoming_addr.wrapping_sub(1) {
                             if let FieldData::RW(ref mut data) = field.data {
                                 **data = text.clone();
                             }
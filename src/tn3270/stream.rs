@@ -4,6 +4,7 @@ use std::convert::{TryFrom, TryInto};
 use snafu::{Snafu, ensure};
 
 #[derive(Clone, Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
 pub enum StreamFormatError {
     #[snafu(display("Invalid AID: {:02x}", aid))]
     InvalidAID { aid: u8, },
@@ -11,6 +12,10 @@ pub enum StreamFormatError {
     UnexpectedEOR,
     #[snafu(display("Invalid data"))]
     InvalidData,
+    #[snafu(display("Buffer address {} is off the screen", addr))]
+    AddressOutOfRange { addr: u16 },
+    #[snafu(display("Record is longer than {} bytes", limit))]
+    RecordTooLong { limit: usize },
 }
 
 const WCC_TRANS: [u8; 64] = [
@@ -61,7 +66,7 @@ impl WCC {
     }
 
     pub fn from_ascii_compat(value: u8) -> Self {
-        Self::from_bits(value & 0x3F).unwrap()
+        Self::from_bits_truncate(value & 0x3F)
     }
 }
 
@@ -71,14 +76,14 @@ pub trait OutputRecord {
     fn write_to(&self, writer: &mut dyn Write) -> std::io::Result<()>;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct WriteCommand {
     pub command: WriteCommandCode,
     pub wcc: WCC,
     pub orders: Vec<WriteOrder>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum WriteCommandCode {
     Write,
     EraseWrite,
@@ -87,6 +92,21 @@ pub enum WriteCommandCode {
     WriteStructuredField,
 }
 
+impl TryFrom<u8> for WriteCommandCode {
+    type Error = StreamFormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0xF1 => WriteCommandCode::Write,
+            0xF5 => WriteCommandCode::EraseWrite,
+            0x7E => WriteCommandCode::EraseWriteAlternate,
+            0x6F => WriteCommandCode::EraseAllUnprotected,
+            0xF3 => WriteCommandCode::WriteStructuredField,
+            _ => return Err(StreamFormatError::InvalidData),
+        })
+    }
+}
+
 impl WriteCommandCode {
     pub fn to_command_code(self) -> u8 {
         match self {
@@ -99,7 +119,7 @@ impl WriteCommandCode {
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Color {
     Default,
     /// Black on displays, white on printers
@@ -122,9 +142,9 @@ pub enum Color {
     White,
 }
 
-impl Into<u8> for Color {
-    fn into(self) -> u8 {
-        match self {
+impl From<Color> for u8 {
+    fn from(color: Color) -> u8 {
+        match color {
             Color::Default => 0x00,
             Color::NeutralBG => 0xF0,
             Color::Blue => 0xF1,
@@ -173,7 +193,7 @@ impl TryFrom<u8> for Color {
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Highlighting {
     Default = 0x00,
     Normal = 0xF0,
@@ -197,9 +217,9 @@ impl TryFrom<u8> for Highlighting {
     }
 }
 
-impl Into<u8> for Highlighting {
-    fn into(self) -> u8 {
-        self as u8
+impl From<Highlighting> for u8 {
+    fn from(v: Highlighting) -> u8 {
+        v as u8
     }
 }
 
@@ -213,7 +233,7 @@ bitflags! {
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum Transparency {
     Default,
     Or,
@@ -254,7 +274,7 @@ bitflags! {
     }
 }
 
-#[derive(Copy, Clone, Debug, Hash)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum ExtendedFieldAttribute {
     AllAttributes,
     ExtendedHighlighting(Highlighting),
@@ -296,7 +316,7 @@ impl ExtendedFieldAttribute {
             ExtendedFieldAttribute::ExtendedHighlighting(fa) => (0x41, fa.into()),
             ExtendedFieldAttribute::BackgroundColor(c) => (0x45, c.into()),
             ExtendedFieldAttribute::ForegroundColor(c) => (0x42, c.into()),
-            ExtendedFieldAttribute::CharacterSet(cs) => (0x43, cs.into()),
+            ExtendedFieldAttribute::CharacterSet(cs) => (0x43, cs),
             ExtendedFieldAttribute::FieldOutlining(fo) => (0xC2, fo.bits()),
             ExtendedFieldAttribute::Transparency(v) => (0x46, v.into()),
             ExtendedFieldAttribute::FieldValidation(v) => (0xC1, v.bits()),
@@ -310,13 +330,13 @@ impl ExtendedFieldAttribute {
 
 }
 
-impl Into<ExtendedFieldAttribute> for &ExtendedFieldAttribute {
-    fn into(self) -> ExtendedFieldAttribute {
-        *self
+impl From<&ExtendedFieldAttribute> for ExtendedFieldAttribute {
+    fn from(attr: &ExtendedFieldAttribute) -> ExtendedFieldAttribute {
+        *attr
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct BufferAddressCalculator {
     pub width: u16,
     pub height: u16,
@@ -334,9 +354,13 @@ impl BufferAddressCalculator {
     pub fn decode_address(self, addr: u16) -> (u16, u16) {
         (addr / self.width, addr % self.width)
     }
+
+    pub fn contains(self, y: u16, x: u16) -> bool {
+        y < self.height && x < self.width
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum WriteOrder {
     StartField(FieldAttribute),
     /// The list of attributes MUST include a FieldAttribute
@@ -392,6 +416,35 @@ impl WriteOrder {
 }
 
 impl WriteCommand {
+    /// Decode an outbound write command, as a terminal would see it. Structured fields
+    /// aren't supported.
+    pub fn parse(record: &[u8]) -> Result<Self, StreamFormatError> {
+        ensure!(record.len() >= 2, UnexpectedEOR);
+        let command = WriteCommandCode::try_from(record[0])?;
+        ensure!(!matches!(command, WriteCommandCode::WriteStructuredField), InvalidData);
+        Ok(WriteCommand {
+            command,
+            wcc: WCC::from_ascii_compat(record[1]),
+            orders: parse_orders(&record[2..])?,
+        })
+    }
+
+    /// Check that every buffer address in the command is on a screen of the given size
+    pub fn check_addresses(&self, size: BufferAddressCalculator) -> Result<(), StreamFormatError> {
+        for order in self.orders.iter() {
+            match *order {
+                WriteOrder::SetBufferAddress(addr)
+                | WriteOrder::InsertCursor(addr)
+                | WriteOrder::RepeatToAddress(addr, _)
+                | WriteOrder::EraseUnprotectedToAddress(addr) => {
+                    ensure!(addr <= size.last_address(), AddressOutOfRange { addr });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     pub fn serialize(&self, output: &mut Vec<u8>) {
         output.push(self.command.to_command_code());
         output.push(self.wcc.to_ascii_compat());
@@ -402,10 +455,10 @@ impl WriteCommand {
 }
 
 
-impl Into<Vec<u8>> for &WriteCommand {
-    fn into(self) -> Vec<u8> {
+impl From<&WriteCommand> for Vec<u8> {
+    fn from(command: &WriteCommand) -> Vec<u8> {
         let mut result = vec![];
-        self.serialize(&mut result);
+        command.serialize(&mut result);
         result
     }
 }
@@ -552,7 +605,7 @@ impl TryFrom<u8> for AID {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct IncomingRecord {
     pub aid: AID,
     pub addr: u16,
@@ -560,17 +613,19 @@ pub struct IncomingRecord {
 }
 
 fn parse_addr(encoded: &[u8]) -> Result<u16, StreamFormatError> {
-    match encoded[0] >> 6 {
-        0b00 => Ok(((encoded[0] as u16) << 8) + encoded[1] as u16),
-        0b01 | 0b11 => {
-            Ok((encoded[0] as u16 & 0x3F) << 6 | (encoded[1] as u16 & 0x3F))
-        }
+    let (hi, lo) = match *encoded {
+        [hi, lo] => (hi as u16, lo as u16),
+        _ => return Err(StreamFormatError::UnexpectedEOR),
+    };
+    match hi >> 6 {
+        0b00 => Ok((hi << 8) + lo),
+        0b01 | 0b11 => Ok((hi & 0x3F) << 6 | (lo & 0x3F)),
         _ => Err(StreamFormatError::InvalidData),
     }
 }
 
 impl IncomingRecord {
-    pub fn parse_record(mut record: &[u8]) -> Result<Self, StreamFormatError> {
+    pub fn parse_record(record: &[u8]) -> Result<Self, StreamFormatError> {
         if record.len() < 3 {
             return Err(StreamFormatError::UnexpectedEOR);
         }
@@ -579,106 +634,110 @@ impl IncomingRecord {
         // TODO: Handle AID 88 structured fields
         let addr = parse_addr(&record[1..3])?;
 
-        let mut result = Self {
+        Ok(Self {
             aid,
             addr,
-            orders: vec![]
-        };
-
-        record = &record[3..];
-
-        while record.len() > 0 {
-            match record[0] {
-                0x1D => {
-                    ensure!(record.len() >= 2, UnexpectedEOR);
-                    result.orders.push(
-                        WriteOrder::StartField(FieldAttribute::from_bits(record[1] & 0x3F)
-                            .ok_or(StreamFormatError::InvalidData)?));
-                    record = &record[2..];
-
-                },
-                0x29 => {
-                    ensure!(record.len() >= 2, UnexpectedEOR);
-                    let (header, body) = record.split_at(2);
-                    let count = header[1] as usize;
-                    ensure!(body.len() >= count * 2, UnexpectedEOR);
-                    let (attrs, rest) = body.split_at(2 * count);
-                    record = rest;
-
-                    result.orders.push(
-                        WriteOrder::StartFieldExtended(
-                            attrs.chunks(2)
-                            .map(ExtendedFieldAttribute::try_from)
-                                .collect::<Result<Vec<ExtendedFieldAttribute>, StreamFormatError>>()?
-                        )
+            orders: parse_orders(&record[3..])?,
+        })
+    }
+}
+
+fn parse_orders(mut record: &[u8]) -> Result<Vec<WriteOrder>, StreamFormatError> {
+    let mut orders = vec![];
+    while !record.is_empty() {
+        match record[0] {
+            0x1D => {
+                ensure!(record.len() >= 2, UnexpectedEOR);
+                orders.push(
+                    WriteOrder::StartField(FieldAttribute::from_bits(record[1] & 0x3F)
+                        .ok_or(StreamFormatError::InvalidData)?));
+                record = &record[2..];
+
+            },
+            0x29 => {
+                ensure!(record.len() >= 2, UnexpectedEOR);
+                let (header, body) = record.split_at(2);
+                let count = header[1] as usize;
+                ensure!(body.len() >= count * 2, UnexpectedEOR);
+                let (attrs, rest) = body.split_at(2 * count);
+                record = rest;
+
+                orders.push(
+                    WriteOrder::StartFieldExtended(
+                        attrs.chunks(2)
+                        .map(ExtendedFieldAttribute::try_from)
+                            .collect::<Result<Vec<ExtendedFieldAttribute>, StreamFormatError>>()?
                     )
-                }
-                0x11 => {
-                    ensure!(record.len() >= 3, UnexpectedEOR);
-                    result.orders.push(WriteOrder::SetBufferAddress(parse_addr(&record[1..3])?));
-                    record = &record[3..];
-                }
-                0x28 => {
-                    ensure!(record.len() >= 3, UnexpectedEOR);
-                    result.orders.push(WriteOrder::SetAttribute(ExtendedFieldAttribute::try_from(&record[1..3])?));
-                    record = &record[3..];
-                }
-                0x2C => {
-                    ensure!(record.len() >= 2, UnexpectedEOR);
-                    let (header, body) = record.split_at(2);
-                    let count = header[1] as usize;
-                    ensure!(body.len() >= count * 2, UnexpectedEOR);
-                    let (attrs, rest) = body.split_at(2 * count);
-                    record = rest;
-
-                    result.orders.push(
-                        WriteOrder::ModifyField(
-                            attrs.chunks(2)
-                                .map(ExtendedFieldAttribute::try_from)
-                                .collect::<Result<Vec<ExtendedFieldAttribute>, StreamFormatError>>()?
-                        )
+                )
+            }
+            0x11 => {
+                ensure!(record.len() >= 3, UnexpectedEOR);
+                orders.push(WriteOrder::SetBufferAddress(parse_addr(&record[1..3])?));
+                record = &record[3..];
+            }
+            0x28 => {
+                ensure!(record.len() >= 3, UnexpectedEOR);
+                orders.push(WriteOrder::SetAttribute(ExtendedFieldAttribute::try_from(&record[1..3])?));
+                record = &record[3..];
+            }
+            0x2C => {
+                ensure!(record.len() >= 2, UnexpectedEOR);
+                let (header, body) = record.split_at(2);
+                let count = header[1] as usize;
+                ensure!(body.len() >= count * 2, UnexpectedEOR);
+                let (attrs, rest) = body.split_at(2 * count);
+                record = rest;
+
+                orders.push(
+                    WriteOrder::ModifyField(
+                        attrs.chunks(2)
+                            .map(ExtendedFieldAttribute::try_from)
+                            .collect::<Result<Vec<ExtendedFieldAttribute>, StreamFormatError>>()?
                     )
-                }
-                0x13 => {
-                    ensure!(record.len() >= 3, UnexpectedEOR);
-                    result.orders.push(WriteOrder::InsertCursor(parse_addr(&record[1..3])?));
-                    record = &record[3..];
-                }
-                0x05 => {
-                    result.orders.push(WriteOrder::ProgramTab);
-                    record = &record[1..];
-                }
-                0x3C => {
-                    ensure!(record.len() >= 4, UnexpectedEOR);
-                    // TODO: Handle graphic escape properly
-                    result.orders.push(WriteOrder::RepeatToAddress(
-                        parse_addr(&record[1..3])?,
-                        crate::encoding::cp037::DECODE_TBL[record[4] as usize] as char,
-                    ));
-                    record = &record[4..]
-                }
-                0x12 => {
-                    ensure!(record.len() >= 3, UnexpectedEOR);
-                    result.orders.push(WriteOrder::EraseUnprotectedToAddress(parse_addr(&record[1..3])?));
-                    record = &record[3..];
-                }
-                0x08 => {
-                    ensure!(record.len() >= 2, UnexpectedEOR);
-                    result.orders.push(WriteOrder::GraphicEscape(record[2]));
-                    record = &record[2..];
-                }
-                0x40..=0xFF => {
-                    let len = record.iter().position(|&v| v < 0x40).unwrap_or(record.len());
-                    let data = record[..len]
-                        .iter()
-                        .map(|&v| crate::encoding::cp037::DECODE_TBL[v as usize] as char)
-                        .collect();
-                    result.orders.push(WriteOrder::SendText(data));
-                    record = &record[len..];
-                },
-                _ => return Err(StreamFormatError::InvalidData)
+                )
+            }
+            0x13 => {
+                ensure!(record.len() >= 3, UnexpectedEOR);
+                orders.push(WriteOrder::InsertCursor(parse_addr(&record[1..3])?));
+                record = &record[3..];
             }
+            0x05 => {
+                orders.push(WriteOrder::ProgramTab);
+                record = &record[1..];
+            }
+            0x3C => {
+                ensure!(record.len() >= 4, UnexpectedEOR);
+                // TODO: Handle graphic escape properly. For now, the escaped character is
+                // decoded as if it were an ordinary one.
+                let len = if record[3] == 0x08 { 5 } else { 4 };
+                ensure!(record.len() >= len, UnexpectedEOR);
+                orders.push(WriteOrder::RepeatToAddress(
+                    parse_addr(&record[1..3])?,
+                    crate::encoding::cp037::DECODE_TBL[record[len - 1] as usize] as char,
+                ));
+                record = &record[len..]
+            }
+            0x12 => {
+                ensure!(record.len() >= 3, UnexpectedEOR);
+                orders.push(WriteOrder::EraseUnprotectedToAddress(parse_addr(&record[1..3])?));
+                record = &record[3..];
+            }
+            0x08 => {
+                ensure!(record.len() >= 2, UnexpectedEOR);
+                orders.push(WriteOrder::GraphicEscape(record[1]));
+                record = &record[2..];
+            }
+            0x40..=0xFF => {
+                let len = record.iter().position(|&v| v < 0x40).unwrap_or(record.len());
+                let data = record[..len]
+                    .iter()
+                    .map(|&v| crate::encoding::cp037::DECODE_TBL[v as usize] as char)
+                    .collect();
+                orders.push(WriteOrder::SendText(data));
+                record = &record[len..];
+            },
+            _ => return Err(StreamFormatError::InvalidData)
         }
-        Ok(result)
     }
+    Ok(orders)
 }
\ No newline at end of file
@@ -0,0 +1,196 @@
+//! Pre-processing of the raw telnet stream before it reaches libtelnet-rs
+
+use std::fmt;
+
+use libtelnet_rs::telnet::{op_command as tn_cmd, op_option as tn_opt};
+
+use crate::tn3270::environ::OPT_NEW_ENVIRON;
+use crate::tn3270::tn3270e::OPT_TN3270E;
+
+pub const DM: u8 = 242;
+pub const BRK: u8 = 243;
+pub const IP: u8 = 244;
+pub const AO: u8 = 245;
+pub const AYT: u8 = 246;
+pub const EC: u8 = 247;
+pub const EL: u8 = 248;
+
+/// The START_TLS option, from draft-altman-telnet-starttls
+pub const OPT_START_TLS: u8 = 46;
+pub const START_TLS_FOLLOWS: u8 = 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// A piece of the stream, as split up by [`CommandExtractor`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Piece {
+    /// Bytes for the parser
+    Telnet(Vec<u8>),
+    /// A data byte of 0xFF, which was sent escaped as IAC IAC
+    DataIac,
+}
+
+/// libtelnet-rs only recognizes EOR, GA and NOP as two-byte commands, and treats any other
+/// command as the start of a three-byte negotiation, swallowing the byte that follows it.
+/// This pulls those commands out of the stream so that the parser never sees them. It also
+/// holds back commands that are split across reads, which the parser would otherwise drop.
+/// Escaped 0xFF data bytes are pulled out too, since the parser throws away whatever data
+/// follows them, and each subnegotiation ends a piece, since the parser keeps whatever
+/// follows an MCCP subnegotiation back for decompression.
+pub(crate) struct CommandExtractor {
+    state: State,
+    /// Bytes seen so far in the current subnegotiation
+    subnegotiation_len: usize,
+}
+
+impl CommandExtractor {
+    pub fn new() -> Self {
+        CommandExtractor { state: State::Data, subnegotiation_len: 0 }
+    }
+
+    /// How much of an unfinished subnegotiation has been seen, so that a client can't make
+    /// the parser buffer one forever
+    pub fn subnegotiation_len(&self) -> usize {
+        match self.state {
+            State::Subnegotiation | State::SubnegotiationIac => self.subnegotiation_len,
+            _ => 0,
+        }
+    }
+
+    /// Filter `data`, returning the pieces that should be handed to the parser, with the
+    /// 0xFF data bytes that go between them. Any commands that were removed are appended
+    /// to `commands`.
+    pub fn extract(&mut self, data: &[u8], commands: &mut Vec<u8>) -> Vec<Piece> {
+        let mut pieces = Vec::new();
+        let mut output = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (State::Data, tn_cmd::IAC) => State::Iac,
+                (State::Data, _) => {
+                    output.push(byte);
+                    State::Data
+                }
+                (State::Iac, DM..=EL) => {
+                    commands.push(byte);
+                    State::Data
+                }
+                (State::Iac, tn_cmd::IAC) => {
+                    if !output.is_empty() {
+                        pieces.push(Piece::Telnet(std::mem::take(&mut output)));
+                    }
+                    pieces.push(Piece::DataIac);
+                    State::Data
+                }
+                (State::Iac, tn_cmd::WILL..=tn_cmd::DONT) => State::Negotiation(byte),
+                (State::Iac, _) => {
+                    output.extend_from_slice(&[tn_cmd::IAC, byte]);
+                    self.subnegotiation_len = 0;
+                    if byte == tn_cmd::SB { State::Subnegotiation } else { State::Data }
+                }
+                (State::Negotiation(verb), _) => {
+                    output.extend_from_slice(&[tn_cmd::IAC, verb, byte]);
+                    State::Data
+                }
+                (State::Subnegotiation, _) => {
+                    output.push(byte);
+                    self.subnegotiation_len += 1;
+                    if byte == tn_cmd::IAC { State::SubnegotiationIac } else { State::Subnegotiation }
+                }
+                (State::SubnegotiationIac, tn_cmd::SE) => {
+                    output.push(byte);
+                    pieces.push(Piece::Telnet(std::mem::take(&mut output)));
+                    State::Data
+                }
+                (State::SubnegotiationIac, _) => {
+                    output.push(byte);
+                    State::Subnegotiation
+                }
+            }
+        }
+        if !output.is_empty() {
+            pieces.push(Piece::Telnet(output));
+        }
+        pieces
+    }
+}
+
+/// libtelnet-rs hands subnegotiations over with IACs still doubled
+pub(crate) fn unescape_iac(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut last_iac = false;
+    for &byte in data {
+        if byte == tn_cmd::IAC && last_iac {
+            last_iac = false;
+            continue;
+        }
+        last_iac = byte == tn_cmd::IAC;
+        output.push(byte);
+    }
+    output
+}
+
+/// A telnet command, by name where it has one, for logging
+pub(crate) struct CommandName(pub u8);
+
+impl fmt::Display for CommandName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            tn_cmd::WILL => "WILL",
+            tn_cmd::WONT => "WONT",
+            tn_cmd::DO => "DO",
+            tn_cmd::DONT => "DONT",
+            tn_cmd::NOP => "NOP",
+            tn_cmd::GA => "GA",
+            tn_cmd::EOR => "EOR",
+            DM => "DM",
+            BRK => "BRK",
+            IP => "IP",
+            AO => "AO",
+            AYT => "AYT",
+            EC => "EC",
+            EL => "EL",
+            other => return write!(f, "{}", other),
+        })
+    }
+}
+
+/// A telnet option, by name where it's one we deal with, for logging
+pub(crate) struct OptionName(pub u8);
+
+impl fmt::Display for OptionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.0 {
+            tn_opt::BINARY => "BINARY",
+            tn_opt::ECHO => "ECHO",
+            tn_opt::SGA => "SUPPRESS-GO-AHEAD",
+            tn_opt::TM => "TIMING-MARK",
+            tn_opt::TTYPE => "TERMINAL-TYPE",
+            tn_opt::EOR => "END-OF-RECORD",
+            tn_opt::NAWS => "NAWS",
+            OPT_NEW_ENVIRON => "NEW-ENVIRON",
+            OPT_TN3270E => "TN3270E",
+            OPT_START_TLS => "START-TLS",
+            other => return write!(f, "{}", other),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_iac_keeps_following_data() {
+        let mut commands = Vec::new();
+        let pieces = CommandExtractor::new().extract(&[0x01, tn_cmd::IAC, tn_cmd::IAC, 0x02, 0x03], &mut commands);
+        assert_eq!(pieces, [Piece::Telnet(vec![0x01]), Piece::DataIac, Piece::Telnet(vec![0x02, 0x03])]);
+        assert!(commands.is_empty());
+    }
+}
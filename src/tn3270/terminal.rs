@@ -0,0 +1,205 @@
+//! Terminal types: what they mean, and choosing between the ones a client supports
+
+use crate::tn3270::stream::BufferAddressCalculator;
+
+/// The screen every 3270 display starts out with, and the only one a model 2 has
+const MODEL_2_SIZE: BufferAddressCalculator = BufferAddressCalculator { width: 80, height: 24 };
+
+/// What a terminal type says about the terminal, such as `IBM-3279-4-E` being a color
+/// model 4 that supports the extended data stream
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TerminalModel {
+    pub model: u8,
+    /// The size used after an Erase/Write
+    pub default_size: BufferAddressCalculator,
+    /// The size used after an Erase/Write Alternate
+    pub alternate_size: BufferAddressCalculator,
+    pub color: bool,
+    /// Whether the terminal supports the extended data stream (`-E`)
+    pub extended: bool,
+    /// A 3287 printer rather than a display
+    pub printer: bool,
+}
+
+impl TerminalModel {
+    /// Interpret a terminal type. `IBM-DYNAMIC` is treated as a model 2, since its real
+    /// size is only known once the terminal has been queried.
+    pub fn parse(term_type: &str) -> Option<Self> {
+        let upper = term_type.to_ascii_uppercase();
+        if upper == "IBM-DYNAMIC" {
+            return Some(Self::default());
+        }
+
+        let mut parts = upper.split('-');
+        if parts.next() != Some("IBM") {
+            return None;
+        }
+        let (color, printer) = match parts.next()? {
+            "3278" => (false, false),
+            "3279" => (true, false),
+            "3287" => (false, true),
+            _ => return None,
+        };
+        let model = parts.next()?.parse::<u8>().ok()?;
+        let extended = match parts.next() {
+            Some("E") => true,
+            None => false,
+            Some(_) => return None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let alternate_size = match (printer, model) {
+            (true, 1) => MODEL_2_SIZE,
+            (false, 2) => MODEL_2_SIZE,
+            (false, 3) => BufferAddressCalculator { width: 80, height: 32 },
+            (false, 4) => BufferAddressCalculator { width: 80, height: 43 },
+            (false, 5) => BufferAddressCalculator { width: 132, height: 27 },
+            _ => return None,
+        };
+        Some(TerminalModel {
+            model,
+            default_size: MODEL_2_SIZE,
+            alternate_size,
+            color,
+            extended,
+            printer,
+        })
+    }
+}
+
+impl Default for TerminalModel {
+    /// A monochrome 3278 model 2, which every 3270 display can stand in for
+    fn default() -> Self {
+        TerminalModel {
+            model: 2,
+            default_size: MODEL_2_SIZE,
+            alternate_size: MODEL_2_SIZE,
+            color: false,
+            extended: false,
+            printer: false,
+        }
+    }
+}
+
+/// Decides which of the terminal types a client offers gets used
+pub trait TerminalPreference: Send + Sync {
+    /// How desirable `term_type` is as a display; the client's highest-ranked type is
+    /// used. `None` means the type can't be used at all.
+    fn rank(&self, term_type: &str) -> Option<u32>;
+
+    /// Whether a TN3270E printer session of type `device_type` (e.g., `IBM-3287-1`) is
+    /// allowed. Printers aren't chosen between, so by default this just asks
+    /// [`rank`](Self::rank).
+    fn accepts_printer(&self, device_type: &str) -> bool {
+        self.rank(device_type).is_some()
+    }
+}
+
+/// Prefers the most capable 3270 display: extended data stream first, then the largest
+/// screen, then color. `IBM-DYNAMIC` is accepted, but only if the client has nothing
+/// better, and types that aren't 3270 displays are refused. Printer sessions are allowed.
+pub struct MostCapable;
+
+impl TerminalPreference for MostCapable {
+    fn rank(&self, term_type: &str) -> Option<u32> {
+        match TerminalModel::parse(term_type) {
+            Some(model) if model.printer => None,
+            Some(_) if term_type.eq_ignore_ascii_case("IBM-DYNAMIC") => Some(0),
+            Some(model) => {
+                let area = model.alternate_size.width as u32 * model.alternate_size.height as u32;
+                // The largest screen, 27x132, is well under 100000 / 2
+                Some(1 + model.extended as u32 * 100_000 + area * 2 + model.color as u32)
+            }
+            None => None,
+        }
+    }
+
+    fn accepts_printer(&self, device_type: &str) -> bool {
+        TerminalModel::parse(device_type).is_some_and(|model| model.printer)
+    }
+}
+
+/// Accepts only the listed types, preferring those listed first
+pub struct PreferenceList {
+    types: Vec<String>,
+}
+
+impl PreferenceList {
+    pub fn new<S: Into<String>>(types: impl IntoIterator<Item = S>) -> Self {
+        PreferenceList {
+            types: types.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TerminalPreference for PreferenceList {
+    fn rank(&self, term_type: &str) -> Option<u32> {
+        self.types.iter()
+            .position(|candidate| candidate.eq_ignore_ascii_case(term_type))
+            .map(|pos| (self.types.len() - pos) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_displays() {
+        let model = TerminalModel::parse("IBM-3278-2-E").unwrap();
+        assert_eq!((model.model, model.color, model.extended, model.printer), (2, false, true, false));
+        assert_eq!(model.alternate_size, MODEL_2_SIZE);
+
+        let model = TerminalModel::parse("ibm-3279-5").unwrap();
+        assert_eq!((model.model, model.color, model.extended, model.printer), (5, true, false, false));
+        assert_eq!(model.default_size, MODEL_2_SIZE);
+        assert_eq!(model.alternate_size, BufferAddressCalculator { width: 132, height: 27 });
+    }
+
+    #[test]
+    fn parses_dynamic_and_printers() {
+        assert_eq!(TerminalModel::parse("IBM-DYNAMIC"), Some(TerminalModel::default()));
+
+        let model = TerminalModel::parse("IBM-3287-1").unwrap();
+        assert!(model.printer);
+        assert_eq!(model.model, 1);
+        assert_eq!(TerminalModel::parse("IBM-3287-2"), None);
+    }
+
+    #[test]
+    fn rejects_junk() {
+        for junk in &["", "xterm", "IBM", "IBM-3278", "IBM-3278-6", "IBM-3278-X", "IBM-3278-2-X", "IBM-3278-2-E-E", "IBM-3180-2", "DEC-3278-2"] {
+            assert_eq!(TerminalModel::parse(junk), None, "{}", junk);
+        }
+    }
+
+    #[test]
+    fn most_capable_ranking() {
+        let rank = |term_type| MostCapable.rank(term_type).unwrap();
+        assert!(rank("IBM-3278-2-E") > rank("IBM-3279-5"));
+        assert!(rank("IBM-3279-5-E") > rank("IBM-3278-2-E"));
+        assert!(rank("IBM-3278-5") > rank("IBM-3279-4"));
+        assert!(rank("IBM-3279-2") > rank("IBM-3278-2"));
+        assert!(rank("IBM-3278-4") > rank("IBM-3278-2"));
+        // Accepted, but only as a last resort
+        assert_eq!(rank("IBM-DYNAMIC"), 0);
+        assert!(rank("IBM-3278-2") > rank("IBM-DYNAMIC"));
+        assert_eq!(MostCapable.rank("xterm"), None);
+        // Not a display, though printer sessions are allowed
+        assert_eq!(MostCapable.rank("IBM-3287-1"), None);
+        assert!(MostCapable.accepts_printer("IBM-3287-1"));
+        assert!(!MostCapable.accepts_printer("IBM-3278-2"));
+    }
+
+    #[test]
+    fn preference_list_ranking() {
+        let list = PreferenceList::new(vec!["IBM-3279-5", "IBM-3278-2-E", "IBM-DYNAMIC"]);
+        assert!(list.rank("IBM-3279-5") > list.rank("IBM-3278-2-E"));
+        assert!(list.rank("ibm-3278-2-e") > list.rank("IBM-DYNAMIC"));
+        assert_eq!(list.rank("IBM-DYNAMIC"), Some(1));
+        assert_eq!(list.rank("IBM-3287-1"), None);
+        assert_eq!(list.rank("junk"), None);
+    }
+}
@@ -0,0 +1,769 @@
+//! A scripted stand-in for a 3270 emulator, for testing applications without a real
+//! terminal.
+//!
+//! A [`FakeClient`] takes one end of a [`duplex`](crate::tn3270::memory::duplex) stream
+//! and the `Session` takes the other. Negotiation is answered from a background thread, so
+//! `Session::new` can be called on the test's own thread. Inbound records (such as the
+//! response to a screen) can be queued before the application asks for them, and outbound
+//! records are collected so the test can inspect them afterwards.
+//!
+//! This module, [`memory`](crate::tn3270::memory) and [`replay`](crate::tn3270::replay)
+//! are only built with the `testing` feature.
+
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use libtelnet_rs::{
+    Parser,
+    events::*,
+    telnet::{
+        op_option as tn_opt,
+        op_command as tn_cmd,
+    }
+};
+
+use crate::tn3270::memory::MemoryStream;
+use crate::tn3270::stream::{AID, StreamFormatError, WriteCommand};
+use crate::tn3270::telnet::{self, CommandExtractor, Piece};
+use crate::tn3270::tn3270e::{DataType, DeviceResponse, Functions, Header, Subnegotiation, HEADER_LEN, OPT_TN3270E, response_flag};
+
+pub struct FakeClient {
+    stream: MemoryStream,
+    records: Receiver<(Header, Vec<u8>)>,
+    mode: Mode,
+    send_seq: u16,
+    response: Arc<Mutex<DeviceResponse>>,
+    silent: Arc<AtomicBool>,
+    /// NVT output that has been received but not yet returned by `next_output`
+    output: Vec<u8>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Mode {
+    Tn3270,
+    /// Asking for these functions
+    Tn3270e(Functions),
+    /// A plain terminal with a window of (width, height)
+    Nvt(u16, u16),
+}
+
+/// What the background thread needs to know to answer the server
+struct Script {
+    term_type: String,
+    mode: Mode,
+    response: Arc<Mutex<DeviceResponse>>,
+    /// Set once the client should stop answering anything
+    silent: Arc<AtomicBool>,
+}
+
+impl FakeClient {
+    /// Start a fake terminal that identifies itself as `term_type` (e.g., `IBM-3278-2`).
+    /// It refuses TN3270E and negotiates plain TN3270.
+    pub fn start(stream: MemoryStream, term_type: &str) -> Self {
+        Self::spawn(stream, term_type, Mode::Tn3270)
+    }
+
+    /// Start a fake terminal that negotiates TN3270E as device type `term_type` (e.g.,
+    /// `IBM-3278-2-E`), asking for `functions`. Records that ask for a response are
+    /// answered with a positive one unless [`answer_with`](Self::answer_with) says otherwise.
+    pub fn start_tn3270e(stream: MemoryStream, term_type: &str, functions: Functions) -> Self {
+        Self::spawn(stream, term_type, Mode::Tn3270e(functions))
+    }
+
+    /// Start a fake VT100-style terminal that identifies itself as `term_type` (e.g.,
+    /// `xterm`) and reports a window of `width` by `height` with NAWS. Its screen output is
+    /// read with [`next_output`](Self::next_output), and keys are typed with
+    /// [`send_keys`](Self::send_keys).
+    pub fn start_nvt(stream: MemoryStream, term_type: &str, width: u16, height: u16) -> Self {
+        Self::spawn(stream, term_type, Mode::Nvt(width, height))
+    }
+
+    fn spawn(stream: MemoryStream, term_type: &str, mode: Mode) -> Self {
+        let (sender, records) = channel();
+        let reader = stream.clone();
+        let response = Arc::new(Mutex::new(DeviceResponse::DeviceEnd));
+        let silent = Arc::new(AtomicBool::new(false));
+        let script = Script {
+            term_type: term_type.to_owned(),
+            mode,
+            response: response.clone(),
+            silent: silent.clone(),
+        };
+        let thread = std::thread::spawn(move || run(reader, script, sender));
+        FakeClient {
+            stream,
+            records,
+            mode,
+            send_seq: 0,
+            response,
+            silent,
+            output: Vec::new(),
+            thread: Some(thread),
+        }
+    }
+
+    /// Set the response sent for records that ask for one (TN3270E only)
+    pub fn answer_with(&self, response: DeviceResponse) {
+        *self.response.lock().unwrap() = response;
+    }
+
+    /// Send a raw inbound record. In TN3270E mode, it is sent as 3270 data with the next
+    /// sequence number.
+    pub fn send_record(&mut self, record: impl Into<Vec<u8>>) -> std::io::Result<()> {
+        let record = record.into();
+        if !matches!(self.mode, Mode::Tn3270e(_)) {
+            return self.stream.write_all(frame(record).as_slice());
+        }
+
+        let mut header = Header::new(DataType::Data3270);
+        header.seq_number = self.send_seq;
+        self.send_seq = self.send_seq.wrapping_add(1);
+        self.send_framed(header, record.as_slice())
+    }
+
+    /// Send a record with an explicit TN3270E header
+    pub fn send_framed(&mut self, header: Header, body: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(frame_with_header(header, body).as_slice())
+    }
+
+    /// Send a RESPONSE record for `seq_number`, whether or not the server asked for one
+    pub fn send_response(&mut self, seq_number: u16, response: DeviceResponse) -> std::io::Result<()> {
+        let (header, body) = response.to_record(seq_number);
+        self.send_framed(header, &[body])
+    }
+
+    /// Type `keys` on an NVT terminal, as the bytes the terminal would send for them (e.g.,
+    /// `\x1bOP` for F1)
+    pub fn send_keys(&mut self, keys: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(Parser::escape_iac(keys.to_vec()).as_slice())
+    }
+
+    /// Wait up to `timeout` for an NVT terminal's screen output to include `expected`, and
+    /// return everything received up to the end of it. Output after that is kept for the
+    /// next call.
+    pub fn next_output(&mut self, expected: &str, timeout: Duration) -> Option<String> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            let found = self.output
+                .windows(expected.len().max(1))
+                .position(|window| window == expected.as_bytes());
+            if let Some(pos) = found {
+                let rest = self.output.split_off(pos + expected.len());
+                let output = std::mem::replace(&mut self.output, rest);
+                return Some(String::from_utf8_lossy(output.as_slice()).into_owned());
+            }
+            let left = deadline.checked_duration_since(std::time::Instant::now())?;
+            let (_, data) = self.next_framed(left)?;
+            self.output.extend(data);
+        }
+    }
+
+    /// Press SYSREQ, which TN3270E terminals send as `IAC AO`
+    pub fn send_sysreq(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[tn_cmd::IAC, telnet::AO])
+    }
+
+    /// Send the record a terminal produces when the user presses `aid` with the cursor at
+    /// `cursor`. `fields` lists the modified fields, each as the buffer address of its first
+    /// character and its contents.
+    pub fn send_aid(&mut self, aid: AID, cursor: u16, fields: &[(u16, &str)]) -> std::io::Result<()> {
+        let mut record = vec![aid.into(), (cursor >> 8) as u8, (cursor & 0xff) as u8];
+        for &(addr, text) in fields {
+            record.extend_from_slice(&[0x11, (addr >> 8) as u8, (addr & 0xff) as u8]);
+            record.extend(crate::encoding::to_cp037(text.chars()));
+        }
+        self.send_record(record)
+    }
+
+    /// Press ATTN
+    pub fn send_attention(&mut self) -> std::io::Result<()> {
+        self.stream.write_all(&[tn_cmd::IAC, telnet::IP])
+    }
+
+    /// Wait up to `timeout` for the next outbound record. In TN3270E mode, the header is
+    /// stripped off.
+    pub fn next_record(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.next_framed(timeout).map(|(_, record)| record)
+    }
+
+    /// Wait up to `timeout` for the next outbound record, along with its TN3270E header.
+    /// Outside of TN3270E mode, every record comes with a plain 3270 data header, and an
+    /// NVT terminal's output comes in chunks marked as NVT data.
+    pub fn next_framed(&mut self, timeout: Duration) -> Option<(Header, Vec<u8>)> {
+        match self.records.recv_timeout(timeout) {
+            Ok(record) => Some(record),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Wait up to `timeout` for the next outbound record, and decode it as a write command
+    pub fn next_write(&mut self, timeout: Duration) -> Result<Option<WriteCommand>, StreamFormatError> {
+        self.next_record(timeout)
+            .map(|record| WriteCommand::parse(record.as_slice()))
+            .transpose()
+    }
+
+    /// Stop answering the server, including its keepalive probes, as if the connection had
+    /// been dropped somewhere along the way without either end noticing
+    pub fn go_silent(&self) {
+        self.silent.store(true, Ordering::SeqCst);
+    }
+
+    /// Hang up, as if the user closed the emulator
+    pub fn disconnect(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stream.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FakeClient {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Escape a record and mark the end of it
+fn frame(record: Vec<u8>) -> Vec<u8> {
+    let mut data = Parser::escape_iac(record);
+    data.extend_from_slice(&[tn_cmd::IAC, tn_cmd::EOR]);
+    data
+}
+
+fn frame_with_header(header: Header, body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(body.len() + HEADER_LEN);
+    header.serialize(&mut record);
+    record.extend_from_slice(body);
+    frame(record)
+}
+
+fn run(mut stream: MemoryStream, script: Script, records: Sender<(Header, Vec<u8>)>) {
+    let mut parser = Parser::new();
+    parser.options.support_local(tn_opt::TTYPE);
+    parser.options.support(tn_opt::EOR);
+    parser.options.support(tn_opt::BINARY);
+    match script.mode {
+        Mode::Tn3270 => {}
+        Mode::Tn3270e(_) => parser.options.support_local(OPT_TN3270E),
+        Mode::Nvt(..) => parser.options.support_local(tn_opt::NAWS),
+    }
+
+    let mut extractor = CommandExtractor::new();
+    let mut buf = vec![0; 1024];
+    let mut record = Vec::new();
+    // Whether the records we get carry TN3270E headers; only once we've seen the
+    // server's device type
+    let mut framed = false;
+    loop {
+        let len = match stream.read(buf.as_mut_slice()) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => len,
+        };
+
+        let mut events = Vec::new();
+        for piece in extractor.extract(&buf[..len], &mut Vec::new()) {
+            match piece {
+                Piece::Telnet(data) => events.extend(parser.receive(data.as_slice())),
+                Piece::DataIac => events.push(TelnetEvents::DataReceive(vec![tn_cmd::IAC])),
+            }
+        }
+
+        let mut reply = Vec::new();
+        for event in events {
+            match event {
+                TelnetEvents::DataSend(data) => reply.extend(data),
+                TelnetEvents::DataReceive(data) if matches!(script.mode, Mode::Nvt(..)) => {
+                    let _ = records.send((Header::new(DataType::NvtData), data));
+                }
+                TelnetEvents::DataReceive(data) => record.extend(data),
+                TelnetEvents::Negotiation(TelnetNegotiation { command: tn_cmd::DO, option: tn_opt::NAWS }) => {
+                    if let Mode::Nvt(width, height) = script.mode {
+                        let size = vec![(width >> 8) as u8, width as u8, (height >> 8) as u8, height as u8];
+                        if let Some(TelnetEvents::DataSend(data)) = parser.subnegotiation(tn_opt::NAWS, size) {
+                            reply.extend(data);
+                        }
+                    }
+                }
+                TelnetEvents::IAC(TelnetIAC { command: tn_cmd::EOR }) => {
+                    let data = std::mem::take(&mut record);
+                    let (header, body) = if framed {
+                        match Header::parse(data.as_slice()) {
+                            Ok((header, body)) => (header, body.to_vec()),
+                            Err(_) => return,
+                        }
+                    } else {
+                        (Header::new(DataType::Data3270), data)
+                    };
+                    if framed && header.data_type == DataType::Data3270 && header.response_flag == response_flag::ALWAYS_RESPONSE {
+                        let response = *script.response.lock().unwrap();
+                        let (header, body) = response.to_record(header.seq_number);
+                        reply.extend(frame_with_header(header, &[body]));
+                    }
+                    // The FakeClient shuts the stream down before it goes away, so a failure
+                    // here just means we're about to stop anyway
+                    let _ = records.send((header, body));
+                }
+                TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: tn_opt::TTYPE, buffer }) if buffer == [tn_cmd::SEND] => {
+                    let mut is = vec![tn_cmd::IS];
+                    is.extend_from_slice(script.term_type.as_bytes());
+                    if let Some(TelnetEvents::DataSend(data)) = parser.subnegotiation(tn_opt::TTYPE, is) {
+                        reply.extend(data);
+                    }
+                }
+                TelnetEvents::Subnegotiation(TelnetSubnegotiation { option: OPT_TN3270E, buffer }) => {
+                    let answer = match Subnegotiation::parse(&buffer) {
+                        Ok(Subnegotiation::SendDeviceType) => Some(Subnegotiation::DeviceTypeRequest {
+                            device_type: script.term_type.clone(),
+                            connect: None,
+                            associate: None,
+                        }),
+                        Ok(Subnegotiation::DeviceTypeIs { .. }) => {
+                            framed = true;
+                            match script.mode {
+                                Mode::Tn3270e(functions) => Some(Subnegotiation::FunctionsRequest(functions)),
+                                _ => None,
+                            }
+                        }
+                        // Take whatever subset the server offers
+                        Ok(Subnegotiation::FunctionsRequest(functions)) => Some(Subnegotiation::FunctionsIs(functions)),
+                        _ => None,
+                    };
+                    if let Some(TelnetEvents::DataSend(data)) = answer.and_then(|sub| parser.subnegotiation(OPT_TN3270E, sub.serialize())) {
+                        reply.extend(data);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if script.silent.load(Ordering::SeqCst) {
+            continue;
+        }
+        if stream.write_all(reply.as_slice()).is_err() {
+            return;
+        }
+    }
+}
+
+/// How long tests wait for something that should happen promptly
+#[cfg(test)]
+pub(crate) const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A session with the default policy, negotiated with a [`FakeClient`] that identifies
+/// itself as `term_type`
+#[cfg(test)]
+pub(crate) fn connect(term_type: &str) -> (crate::tn3270::Session<MemoryStream>, FakeClient) {
+    let (server, client) = crate::tn3270::memory::duplex();
+    let client = FakeClient::start(client, term_type);
+    let session = crate::tn3270::Session::new(server).expect("negotiation failed");
+    (session, client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tn3270::{Event, Session, Signal};
+    use std::sync::Arc;
+    use std::time::Instant;
+    use crate::tn3270::idle::{IdleError, IdlePolicy, IdleWarning};
+    use crate::tn3270::keepalive::{Keepalive, KeepaliveError, KeepaliveProbe};
+    use crate::tn3270::lu::AdHocLuPool;
+    use crate::tn3270::memory::duplex;
+    use crate::tn3270::negotiation::{NegotiationError, NegotiationPolicy};
+    use crate::tn3270::proxy::ProxyHeader;
+    use crate::tn3270::screen::{Field, Screen, ScreenError};
+    use crate::tn3270::stream::{IncomingRecord, WriteCommandCode, WriteOrder, WCC};
+    use crate::tn3270::terminal::PreferenceList;
+
+    #[test]
+    fn negotiates_classic_tn3270() {
+        let (session, _client) = connect("IBM-3278-2");
+        assert!(!session.is_tn3270e());
+        assert_eq!(session.term_type(), Some("IBM-3278-2"));
+        assert_eq!(session.offered_term_types(), ["IBM-3278-2".to_owned()]);
+    }
+
+    #[test]
+    fn refuses_non_3270_terminal() {
+        let (server, client) = duplex();
+        let _client = FakeClient::start(client, "xterm");
+        let result = Session::new(server);
+        assert!(matches!(result, Err(NegotiationError::UnsupportedTerminalType { ref offered }) if offered == &["xterm".to_owned()]));
+    }
+
+    fn connect_tn3270e(functions: Functions) -> (Session<MemoryStream>, FakeClient) {
+        let (server, client) = duplex();
+        let client = FakeClient::start_tn3270e(client, "IBM-3278-2-E", functions);
+        let session = Session::new(server).expect("negotiation failed");
+        (session, client)
+    }
+
+    #[test]
+    fn negotiates_tn3270e() {
+        let (mut session, mut client) = connect_tn3270e(Functions::RESPONSES);
+        assert!(session.is_tn3270e());
+        assert_eq!(session.term_type(), Some("IBM-3278-2-E"));
+        assert_eq!(session.functions(), Functions::RESPONSES);
+        assert!(session.lu_name().is_some());
+
+        // Records are framed both ways
+        session.send_record(vec![0xF1, 0xC2]).unwrap();
+        let (header, record) = client.next_framed(TIMEOUT).expect("no record");
+        assert_eq!(header.data_type, DataType::Data3270);
+        assert_eq!(record, [0xF1, 0xC2]);
+
+        client.send_aid(AID::Enter, 86, &[]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        assert_eq!(record.header.data_type, DataType::Data3270);
+        assert_eq!(record.data, [0x7D, 0x00, 0x56]);
+    }
+
+    #[test]
+    fn responses_match_their_records() {
+        let (mut session, mut client) = connect_tn3270e(Functions::RESPONSES);
+        assert_eq!(session.send_record_with_response(vec![0xF1, 0xC2], Some(TIMEOUT)).unwrap(), Some(DeviceResponse::DeviceEnd));
+        let (header, _) = client.next_framed(TIMEOUT).expect("no record");
+        assert_eq!(header.response_flag, response_flag::ALWAYS_RESPONSE);
+
+        // A response to an earlier record doesn't answer this one
+        client.send_response(header.seq_number, DeviceResponse::DeviceEnd).unwrap();
+        client.answer_with(DeviceResponse::CommandReject);
+        assert_eq!(session.send_record_with_response(vec![0xF1, 0xC2], Some(TIMEOUT)).unwrap(), Some(DeviceResponse::CommandReject));
+        let (next, _) = client.next_framed(TIMEOUT).expect("no record");
+        assert_eq!(next.seq_number, header.seq_number.wrapping_add(1));
+    }
+
+    #[test]
+    fn unsolicited_responses_are_dropped() {
+        let (mut session, mut client) = connect_tn3270e(Functions::RESPONSES);
+        client.send_response(42, DeviceResponse::DeviceEnd).unwrap();
+        client.send_aid(AID::Enter, 0, &[]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        assert_eq!(record.header.data_type, DataType::Data3270);
+        assert_eq!(record.data, [0x7D, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn responses_need_the_function() {
+        let (mut session, _client) = connect_tn3270e(Functions::empty());
+        assert!(session.is_tn3270e());
+        let err = session.send_record_with_response(vec![0xF1, 0xC2], Some(TIMEOUT)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn sysreq_is_signalled() {
+        let (mut session, mut client) = connect_tn3270e(Functions::SYSREQ);
+        assert_eq!(session.functions(), Functions::SYSREQ);
+        client.send_sysreq().unwrap();
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Busy")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::Interrupted { signal: Signal::SysReq })));
+    }
+
+    #[test]
+    fn sysreq_needs_the_function() {
+        let (mut session, mut client) = connect_tn3270e(Functions::empty());
+        client.send_sysreq().unwrap();
+        client.send_aid(AID::Enter, 0, &[]).unwrap();
+        assert!(matches!(session.receive_event(Some(TIMEOUT)), Ok(Some(Event::Record(_)))));
+    }
+
+    #[test]
+    fn pre_read_proxy_header() {
+        let (server, client) = duplex();
+        let _client = FakeClient::start(client, "IBM-3278-2");
+        let header = ProxyHeader {
+            version: 1,
+            source: Some("192.0.2.1:51234".parse().unwrap()),
+            destination: Some("198.51.100.2:23".parse().unwrap()),
+        };
+        // Already read, so the session mustn't look for another
+        let policy = NegotiationPolicy { proxy_protocol: true, proxy_header: Some(header), ..NegotiationPolicy::default() };
+        let session = Session::with_policy(server, Arc::new(AdHocLuPool), policy).expect("negotiation failed");
+        assert_eq!(session.peer_addr(), header.source);
+        assert_eq!(session.local_addr(), header.destination);
+        assert_eq!(session.proxy_header(), Some(&header));
+    }
+
+    #[test]
+    fn aid_round_trip() {
+        let (mut session, mut client) = connect("IBM-3278-2");
+        let command = WriteCommand {
+            command: WriteCommandCode::Write,
+            wcc: WCC::KBD_RESTORE,
+            orders: vec![WriteOrder::SetBufferAddress(80), WriteOrder::SendText("Hi".into())],
+        };
+        session.send_record(&command).unwrap();
+        assert_eq!(client.next_write(TIMEOUT).unwrap(), Some(command));
+
+        client.send_aid(AID::Enter, 86, &[(81, "HELLO")]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        let incoming = IncomingRecord::parse_record(record.data.as_slice()).unwrap();
+        assert_eq!(incoming.aid, AID::Enter);
+        assert_eq!(incoming.addr, 86);
+        assert_eq!(incoming.orders, vec![WriteOrder::SetBufferAddress(81), WriteOrder::SendText("HELLO".into())]);
+    }
+
+    #[test]
+    fn escaped_iac_arrives_intact() {
+        let (mut session, mut client) = connect("IBM-3278-2");
+        // A buffer address of 511 contains a 0xFF byte, which is sent as IAC IAC
+        client.send_record(vec![0x7D, 0x01, 0xFF, 0x11, 0x01, 0xFF, 0xC1]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        assert_eq!(record.data, [0x7D, 0x01, 0xFF, 0x11, 0x01, 0xFF, 0xC1]);
+    }
+
+    #[test]
+    fn present_screen() {
+        let (mut session, mut client) = connect("IBM-3278-2");
+        // Queued ahead of time; the session picks it up once the screen is sent
+        client.send_aid(AID::PF3, 262, &[(261, "ALICE")]).unwrap();
+
+        let mut name = "        ".to_owned();
+        let response = Screen {
+            fields: vec![
+                Field::at(1, 32).ro_text("Sign on"),
+                Field::at(3, 20).rw_text(&mut name),
+            ],
+        }.present(&mut session).unwrap();
+
+        assert_eq!(response.aid, AID::PF3);
+        assert_eq!((response.address.row, response.address.col), (3, 22));
+        assert_eq!(name, "ALICE");
+
+        let screen = client.next_write(TIMEOUT).unwrap().expect("no screen");
+        assert_eq!(screen.command, WriteCommandCode::EraseWrite);
+        assert!(screen.orders.contains(&WriteOrder::SendText("Sign on".into())));
+        assert!(screen.orders.contains(&WriteOrder::SetBufferAddress(260)));
+    }
+
+    #[test]
+    fn present_uses_alternate_size() {
+        let (mut session, mut client) = connect("IBM-3278-4");
+        client.send_aid(AID::Enter, 2403, &[]).unwrap();
+
+        let response = Screen { fields: vec![Field::at(30, 2).ro_text("Row 30")] }
+            .present(&mut session)
+            .unwrap();
+        assert_eq!((response.address.row, response.address.col), (30, 3));
+        assert_eq!(session.screen_size().height, 43);
+
+        let screen = client.next_write(TIMEOUT).unwrap().expect("no screen");
+        assert_eq!(screen.command, WriteCommandCode::EraseWriteAlternate);
+        assert!(screen.orders.contains(&WriteOrder::SetBufferAddress(2402)));
+    }
+
+    #[test]
+    fn records_must_fit_the_screen() {
+        let (mut session, mut client) = connect("IBM-3278-4");
+        let at = |command, addr| WriteCommand { command, wcc: WCC::empty(), orders: vec![WriteOrder::SetBufferAddress(addr)] };
+        // Row 30 is only there at the alternate size
+        let err = session.send_record(&at(WriteCommandCode::EraseWrite, 2402)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        session.send_record(&at(WriteCommandCode::EraseWriteAlternate, 2402)).unwrap();
+        assert_eq!(session.screen_size().height, 43);
+        // A plain Write keeps the size the last erase left
+        session.send_record(&at(WriteCommandCode::Write, 3439)).unwrap();
+        let err = session.send_record(&at(WriteCommandCode::Write, 3440)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        assert_eq!(client.next_write(TIMEOUT).unwrap().map(|write| write.command), Some(WriteCommandCode::EraseWriteAlternate));
+        assert_eq!(client.next_write(TIMEOUT).unwrap().map(|write| write.command), Some(WriteCommandCode::Write));
+        assert!(client.next_record(Duration::from_millis(50)).is_none());
+    }
+
+    #[test]
+    fn alternate_size_can_be_turned_off() {
+        let (mut session, mut client) = connect("IBM-3278-4");
+        session.set_use_alternate_size(false);
+        client.send_aid(AID::Enter, 0, &[]).unwrap();
+
+        Screen { fields: vec![Field::at(23, 0).ro_text("Last row")] }.present(&mut session).unwrap();
+        assert_eq!(session.screen_size().height, 24);
+        let screen = client.next_write(TIMEOUT).unwrap().expect("no screen");
+        assert_eq!(screen.command, WriteCommandCode::EraseWrite);
+
+        let result = Screen { fields: vec![Field::at(30, 1).ro_text("Row 30")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::FieldOutOfBounds { row: 30, col: 1 })));
+    }
+
+    #[test]
+    fn nvt_session_end_to_end() {
+        let (server, client) = duplex();
+        let mut client = FakeClient::start_nvt(client, "xterm", 80, 24);
+        // Falling back to NVT doesn't depend on the terminal type policy accepting xterm
+        let policy = NegotiationPolicy {
+            terminal_types: Arc::new(PreferenceList::new(vec!["IBM-3278-2"])),
+            nvt_fallback: true,
+            ..NegotiationPolicy::default()
+        };
+        let mut session = Session::with_policy(server, Arc::new(AdHocLuPool), policy).expect("negotiation failed");
+        assert!(session.is_nvt());
+        assert_eq!(session.term_type(), Some("xterm"));
+
+        let typist = std::thread::spawn(move || {
+            client.next_output("Sign on", TIMEOUT).expect("no screen");
+            client.send_keys(b"ALICE\r").unwrap();
+            client
+        });
+        let mut name = "        ".to_owned();
+        let response = Screen {
+            fields: vec![
+                Field::at(1, 32).ro_text("Sign on"),
+                Field::at(3, 20).rw_text(&mut name),
+            ],
+        }.present(&mut session).unwrap();
+        assert_eq!(response.aid, AID::Enter);
+        assert_eq!((response.address.row, response.address.col), (3, 26));
+        // The blanks the field started with are still there after the typing
+        assert_eq!(name, "ALICE   ");
+
+        // F3 comes back as PF3, once the keyboard has been unlocked
+        let mut client = typist.join().unwrap();
+        let restore = WriteCommand { command: WriteCommandCode::Write, wcc: WCC::KBD_RESTORE, orders: vec![] };
+        session.send_record(&restore).unwrap();
+        client.send_keys(b"\x1bOR").unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        assert_eq!(record.data[0], u8::from(AID::PF3));
+    }
+
+    #[test]
+    fn attention_interrupts_screen() {
+        let (mut session, mut client) = connect("IBM-3278-2");
+        client.send_attention().unwrap();
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Busy")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::Interrupted { signal: Signal::Attention })));
+    }
+
+    #[test]
+    fn poll_signal_leaves_blocking_sessions_blocking() {
+        let (mut session, mut client) = connect("IBM-3278-2");
+        assert_eq!(session.poll_signal().unwrap(), None);
+        assert!(!session.get_ref().is_nonblocking());
+
+        client.send_attention().unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        let signal = loop {
+            if let Some(signal) = session.poll_signal().unwrap() {
+                break signal;
+            }
+            assert!(Instant::now() < deadline, "no signal");
+        };
+        assert_eq!(signal, Signal::Attention);
+        assert!(!session.get_ref().is_nonblocking());
+    }
+
+    #[test]
+    fn disconnect_ends_screen() {
+        let (mut session, client) = connect("IBM-3278-2");
+        client.disconnect();
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Bye")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::Disconnected)));
+    }
+
+    #[test]
+    fn silent_peer_is_given_up_on() {
+        let keepalive = Keepalive::new(Duration::from_millis(20), KeepaliveProbe::TimingMark).with_max_missed(2);
+        let (mut session, client) = connect("IBM-3278-2");
+        session.set_keepalive(Some(keepalive));
+        client.go_silent();
+
+        let err = session.receive_record(Some(TIMEOUT)).unwrap_err();
+        assert!(KeepaliveError::from_io_error(&err).is_some(), "{}", err);
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn silent_peer_ends_screen() {
+        let keepalive = Keepalive::new(Duration::from_millis(20), KeepaliveProbe::TimingMark).with_max_missed(2);
+        let (mut session, client) = connect("IBM-3278-2");
+        session.set_keepalive(Some(keepalive));
+        client.go_silent();
+
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Anyone there?")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::DeadPeer)));
+    }
+
+    #[test]
+    fn answered_probes_keep_session_alive() {
+        let keepalive = Keepalive::new(Duration::from_millis(50), KeepaliveProbe::TimingMark).with_max_missed(2);
+        let (mut session, _client) = connect("IBM-3278-2");
+        session.set_keepalive(Some(keepalive));
+        // Several times longer than it takes to give up on a silent client
+        assert!(session.receive_record(Some(Duration::from_millis(500))).unwrap().is_none());
+        assert!(!session.is_closed());
+    }
+
+    fn is_warning(command: &WriteCommand, message: &str) -> bool {
+        command.command == WriteCommandCode::Write
+            && command.wcc.contains(WCC::SOUND_ALARM)
+            && command.orders.contains(&WriteOrder::SendText(message.into()))
+    }
+
+    #[test]
+    fn warning_is_written_over_the_screen() {
+        let idle = IdlePolicy::new(Duration::from_millis(300))
+            .with_warning(IdleWarning::new(Duration::from_millis(200), "Logging off soon"));
+        let (mut session, mut client) = connect("IBM-3278-2");
+        session.set_idle_timeout(Some(idle));
+        let menu = WriteCommand {
+            command: WriteCommandCode::EraseWrite,
+            wcc: WCC::KBD_RESTORE,
+            orders: vec![WriteOrder::SendText("Main menu".into())],
+        };
+        session.send_record(&menu).unwrap();
+
+        let err = session.receive_record(Some(TIMEOUT)).unwrap_err();
+        let Some(IdleError::TimedOut { idle_for }) = IdleError::from_io_error(&err) else {
+            panic!("not an idle timeout: {}", err);
+        };
+        assert!(*idle_for >= Duration::from_millis(300));
+
+        let screen = client.next_write(TIMEOUT).unwrap().expect("no screen");
+        assert_eq!(screen.command, WriteCommandCode::EraseWrite);
+        let warning = client.next_write(TIMEOUT).unwrap().expect("no warning");
+        assert!(is_warning(&warning, "Logging off soon"));
+        // On the bottom line, leaving the rest of the screen alone
+        assert_eq!(warning.orders[0], WriteOrder::SetBufferAddress(23 * 80));
+    }
+
+    #[test]
+    fn activity_resets_the_warning() {
+        let idle = IdlePolicy::new(Duration::from_millis(400))
+            .with_warning(IdleWarning::new(Duration::from_millis(300), "Still there?").at(0, 40));
+        let (mut session, mut client) = connect("IBM-3278-2");
+        session.set_idle_timeout(Some(idle));
+
+        assert!(session.receive_record(Some(Duration::from_millis(150))).unwrap().is_none());
+        let warning = client.next_write(TIMEOUT).unwrap().expect("no warning");
+        assert!(is_warning(&warning, "Still there?"));
+        assert_eq!(warning.orders[0], WriteOrder::SetBufferAddress(40));
+
+        client.send_aid(AID::Enter, 0, &[]).unwrap();
+        assert!(session.receive_record(Some(TIMEOUT)).unwrap().is_some());
+        // Past the original timeout, but not the one the AID started
+        assert!(session.receive_record(Some(Duration::from_millis(250))).unwrap().is_none());
+        let warning = client.next_write(TIMEOUT).unwrap().expect("no second warning");
+        assert!(is_warning(&warning, "Still there?"));
+
+        let err = session.receive_record(None).unwrap_err();
+        assert!(IdleError::from_io_error(&err).is_some(), "{}", err);
+    }
+
+    #[test]
+    fn timeout_ends_screen() {
+        let (mut session, _client) = connect("IBM-3278-2");
+        session.set_idle_timeout(Some(IdlePolicy::new(Duration::from_millis(100))));
+        let result = Screen { fields: vec![Field::at(1, 1).ro_text("Main menu")] }.present(&mut session);
+        assert!(matches!(result, Err(ScreenError::IdleTimeout { idle_for }) if idle_for >= Duration::from_millis(100)));
+    }
+}
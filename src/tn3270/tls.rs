@@ -0,0 +1,647 @@
+//! TLS, either for "secure TN3270", where the handshake happens as soon as the client
+//! connects, or partway through telnet negotiation with the START_TLS option.
+//!
+//! For secure TN3270, the handshake is completed by [`TlsAcceptor::accept`], so a client
+//! that fails it is reported as a [`TlsError`] and never reaches
+//! [`Session::new`](crate::tn3270::Session::new). For START_TLS, wrap the connection in a
+//! [`StartTlsStream`] and the session will run the handshake when the client asks for it.
+//!
+//! Either way, if the acceptor asks for client certificates, the verified chain is
+//! available from [`Session::peer_certificates`](crate::tn3270::Session::peer_certificates)
+//! once the session has been negotiated, along with whatever identity an
+//! [`IdentityMapper`] assigned to it.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use rustls::crypto::CryptoProvider;
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use x509_parser::extensions::GeneralName;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+use crate::tn3270::negotiation::NegotiationError;
+use crate::tn3270::transport::{StartTlsPolicy, Transport};
+
+/// How long a client gets to finish the TLS handshake, unless the acceptor says otherwise
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility = "pub(crate)")]
+pub enum TlsError {
+    #[snafu(display("Failed to read {}: {}", path.display(), source))]
+    ReadPem { path: PathBuf, source: std::io::Error },
+    #[snafu(display("No certificates found in {}", path.display()))]
+    NoCertificates { path: PathBuf },
+    #[snafu(display("No private key found in {}", path.display()))]
+    NoPrivateKey { path: PathBuf },
+    #[snafu(display("Invalid TLS configuration: {}", source))]
+    Config { source: rustls::Error },
+    #[snafu(display("Invalid client certificate settings: {}", source))]
+    ClientVerifier { source: rustls::server::VerifierBuilderError },
+    #[snafu(display("Invalid peer certificate: {}", message))]
+    InvalidCertificate { message: String },
+    #[snafu(display("TLS handshake failed: {}", source))]
+    Handshake { source: std::io::Error },
+}
+
+/// A certificate presented by the client, after it has been verified
+#[derive(Clone, Debug)]
+pub struct PeerCertificate {
+    /// The certificate as sent by the client
+    pub der: Vec<u8>,
+    /// The subject's distinguished name, e.g., `CN=Jane Doe, O=Example Corp`
+    pub subject: String,
+    pub issuer: String,
+    /// The serial number as colon-separated hex bytes
+    pub serial: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    IpAddress(IpAddr),
+    DirectoryName(String),
+    /// Any other kind of name, as described by x509-parser
+    Other(String),
+}
+
+impl std::fmt::Display for SubjectAltName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubjectAltName::Dns(name) => write!(f, "DNS:{}", name),
+            SubjectAltName::Email(address) => write!(f, "email:{}", address),
+            SubjectAltName::Uri(uri) => write!(f, "URI:{}", uri),
+            SubjectAltName::IpAddress(address) => write!(f, "IP:{}", address),
+            SubjectAltName::DirectoryName(name) => write!(f, "DirName:{}", name),
+            SubjectAltName::Other(name) => f.write_str(name),
+        }
+    }
+}
+
+impl<'a> From<&GeneralName<'a>> for SubjectAltName {
+    fn from(name: &GeneralName<'a>) -> Self {
+        match *name {
+            GeneralName::DNSName(name) => SubjectAltName::Dns(name.to_owned()),
+            GeneralName::RFC822Name(address) => SubjectAltName::Email(address.to_owned()),
+            GeneralName::URI(uri) => SubjectAltName::Uri(uri.to_owned()),
+            GeneralName::IPAddress(&[a, b, c, d]) => SubjectAltName::IpAddress([a, b, c, d].into()),
+            GeneralName::IPAddress(address) if address.len() == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(address);
+                SubjectAltName::IpAddress(octets.into())
+            }
+            GeneralName::DirectoryName(ref name) => SubjectAltName::DirectoryName(name.to_string()),
+            ref other => SubjectAltName::Other(other.to_string()),
+        }
+    }
+}
+
+impl PeerCertificate {
+    pub fn parse(der: &[u8]) -> Result<Self, TlsError> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| TlsError::InvalidCertificate { message: err.to_string() })?;
+        let subject_alt_names = cert.subject_alternative_name()
+            .map_err(|err| TlsError::InvalidCertificate { message: err.to_string() })?
+            .map(|ext| ext.value.general_names.iter().map(SubjectAltName::from).collect())
+            .unwrap_or_default();
+        Ok(PeerCertificate {
+            der: der.to_vec(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            serial: cert.raw_serial_as_string(),
+            subject_alt_names,
+        })
+    }
+}
+
+/// Decides which application user a verified client certificate chain belongs to. The
+/// chain starts with the client's own certificate.
+pub trait IdentityMapper: Send + Sync {
+    fn identify(&self, chain: &[PeerCertificate]) -> Option<String>;
+}
+
+impl<F> IdentityMapper for F where F: Fn(&[PeerCertificate]) -> Option<String> + Send + Sync {
+    fn identify(&self, chain: &[PeerCertificate]) -> Option<String> {
+        self(chain)
+    }
+}
+
+/// Wraps accepted connections in TLS
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    identity_mapper: Option<Arc<dyn IdentityMapper>>,
+    handshake_timeout: Duration,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        TlsAcceptor { config, identity_mapper: None, handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT }
+    }
+
+    /// Load a PEM certificate chain and private key. The server certificate must come
+    /// first in `cert_path`, followed by any intermediates.
+    pub fn from_pem_files(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Self::build(cert_path.as_ref(), key_path.as_ref(), default_provider(), WebPkiClientVerifier::no_client_auth())
+    }
+
+    /// Like [`TlsAcceptor::from_pem_files`], but also ask clients for a certificate issued
+    /// by one of the CAs in `ca_path`. Unless `required` is set, clients without one are
+    /// still let in.
+    pub fn from_pem_files_with_client_auth(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>, ca_path: impl AsRef<Path>, required: bool) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certificates(ca_path.as_ref())? {
+            roots.add(cert).context(Config)?;
+        }
+
+        let provider = default_provider();
+        let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+        if !required {
+            verifier = verifier.allow_unauthenticated();
+        }
+        Self::build(cert_path.as_ref(), key_path.as_ref(), provider, verifier.build().context(ClientVerifier)?)
+    }
+
+    fn build(cert_path: &Path, key_path: &Path, provider: Arc<CryptoProvider>, verifier: Arc<dyn ClientCertVerifier>) -> Result<Self, TlsError> {
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .context(Config)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+            .context(Config)?;
+        Ok(Self::new(Arc::new(config)))
+    }
+
+    /// Run `mapper` on every client certificate chain as soon as it has been verified
+    pub fn with_identity_mapper(mut self, mapper: impl IdentityMapper + 'static) -> Self {
+        self.identity_mapper = Some(Arc::new(mapper));
+        self
+    }
+
+    /// How long a client gets to finish the whole handshake; 5 seconds by default
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Run the TLS handshake on a freshly accepted connection
+    pub fn accept<T: Transport>(&self, stream: T) -> Result<TlsStream<T>, TlsError> {
+        self.accept_with(stream, &[])
+    }
+
+    /// Run the TLS handshake, starting with `received`, which has already been read from
+    /// `stream`
+    fn accept_with<T: Transport>(&self, mut stream: T, received: &[u8]) -> Result<TlsStream<T>, TlsError> {
+        let mut conn = ServerConnection::new(self.config.clone()).context(Config)?;
+
+        let deadline = Instant::now() + self.handshake_timeout;
+        let mut rewound = Rewound { received, stream: &mut stream, deadline };
+        while conn.is_handshaking() {
+            let (read, written) = conn.complete_io(&mut rewound).context(Handshake)?;
+            if read == 0 && written == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)).context(Handshake);
+            }
+        }
+        stream.set_read_timeout(None).context(Handshake)?;
+
+        let peer_certificates = conn.peer_certificates()
+            .unwrap_or_default()
+            .iter()
+            .map(|cert| PeerCertificate::parse(cert.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let peer_identity = match self.identity_mapper {
+            Some(ref mapper) if !peer_certificates.is_empty() => mapper.identify(peer_certificates.as_slice()),
+            _ => None,
+        };
+
+        Ok(TlsStream {
+            inner: StreamOwned::new(conn, stream),
+            peer_certificates,
+            peer_identity,
+        })
+    }
+}
+
+fn default_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>, TlsError> {
+    let file = File::open(path).context(ReadPem { path })?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadPem { path })?;
+    if certs.is_empty() {
+        return NoCertificates { path }.fail();
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> Result<rustls::pki_types::PrivateKeyDer<'static>, TlsError> {
+    let file = File::open(path).context(ReadPem { path })?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .context(ReadPem { path })?
+        .context(NoPrivateKey { path })
+}
+
+/// A stream with some bytes that were already read from it put back in front. Reads
+/// from the stream itself fail once `deadline` has passed, however many it takes.
+struct Rewound<'a, T> {
+    received: &'a [u8],
+    stream: &'a mut T,
+    deadline: Instant,
+}
+
+impl<'a, T: Transport> Read for Rewound<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.received.is_empty() {
+            return self.received.read(buf);
+        }
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+impl<'a, T: Write> Write for Rewound<'a, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A connection that has completed the TLS handshake
+pub struct TlsStream<T: Read + Write> {
+    inner: StreamOwned<ServerConnection, T>,
+    peer_certificates: Vec<PeerCertificate>,
+    peer_identity: Option<String>,
+}
+
+impl<T: Read + Write> TlsStream<T> {
+    pub fn connection(&self) -> &ServerConnection {
+        &self.inner.conn
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner.sock
+    }
+}
+
+impl<T: Read + Write> Read for TlsStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Read + Write> Write for TlsStream<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for TlsStream<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.sock.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.sock.set_nonblocking(nonblocking)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.sock.peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.sock.local_addr()
+    }
+
+    fn peer_certificates(&self) -> &[PeerCertificate] {
+        self.peer_certificates.as_slice()
+    }
+
+    fn peer_identity(&self) -> Option<&str> {
+        self.peer_identity.as_deref()
+    }
+}
+
+enum Upgrade<T: Read + Write> {
+    Plain(T),
+    Tls(Box<TlsStream<T>>),
+    /// The handshake failed, taking the connection with it
+    Failed,
+}
+
+/// A plain connection that switches to TLS if the client agrees to START_TLS
+pub struct StartTlsStream<T: Read + Write> {
+    state: Upgrade<T>,
+    acceptor: TlsAcceptor,
+    policy: StartTlsPolicy,
+}
+
+impl<T: Read + Write> StartTlsStream<T> {
+    pub fn new(stream: T, acceptor: TlsAcceptor, policy: StartTlsPolicy) -> Self {
+        StartTlsStream {
+            state: Upgrade::Plain(stream),
+            acceptor,
+            policy,
+        }
+    }
+
+    /// The TLS layer, once the connection has been upgraded
+    pub fn tls(&self) -> Option<&TlsStream<T>> {
+        match self.state {
+            Upgrade::Tls(ref stream) => Some(stream),
+            Upgrade::Plain(_) | Upgrade::Failed => None,
+        }
+    }
+
+    fn stream(&mut self) -> std::io::Result<&mut dyn Transport> where T: Transport {
+        match self.state {
+            Upgrade::Plain(ref mut stream) => Ok(stream),
+            Upgrade::Tls(ref mut stream) => Ok(&mut **stream),
+            Upgrade::Failed => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+impl<T: Transport> Read for StartTlsStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stream()?.read(buf)
+    }
+}
+
+impl<T: Transport> Write for StartTlsStream<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream()?.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream()?.flush()
+    }
+}
+
+impl<T: Transport> Transport for StartTlsStream<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.stream()?.set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        self.stream()?.set_nonblocking(nonblocking)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self.state {
+            Upgrade::Plain(ref stream) => stream.peer_addr(),
+            Upgrade::Tls(ref stream) => stream.peer_addr(),
+            Upgrade::Failed => None,
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self.state {
+            Upgrade::Plain(ref stream) => stream.local_addr(),
+            Upgrade::Tls(ref stream) => stream.local_addr(),
+            Upgrade::Failed => None,
+        }
+    }
+
+    fn peer_certificates(&self) -> &[PeerCertificate] {
+        self.tls().map_or(&[], |tls| tls.peer_certificates())
+    }
+
+    fn peer_identity(&self) -> Option<&str> {
+        self.tls().and_then(|tls| tls.peer_identity())
+    }
+
+    fn start_tls_policy(&self) -> StartTlsPolicy {
+        match self.state {
+            Upgrade::Plain(_) => self.policy,
+            Upgrade::Tls(_) | Upgrade::Failed => StartTlsPolicy::Refuse,
+        }
+    }
+
+    fn start_tls(&mut self, received: &[u8]) -> std::io::Result<()> {
+        match std::mem::replace(&mut self.state, Upgrade::Failed) {
+            Upgrade::Plain(stream) => {
+                // Passed through as a NegotiationError, which the session unwraps again
+                let stream = self.acceptor.accept_with(stream, received)
+                    .map_err(|source| NegotiationError::Tls { source })?;
+                self.state = Upgrade::Tls(Box::new(stream));
+                Ok(())
+            }
+            state => {
+                self.state = state;
+                Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the connection is already encrypted"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tn3270::Session;
+    use crate::tn3270::memory::{duplex, MemoryStream};
+    use crate::tn3270::telnet::{OPT_START_TLS, START_TLS_FOLLOWS};
+    use libtelnet_rs::telnet::op_command as tn_cmd;
+    use std::convert::TryFrom;
+    use rustls::{ClientConfig, ClientConnection};
+    use rustls::pki_types::ServerName;
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+
+    /// A test CA, a server certificate for `localhost` and a client certificate for Jane
+    /// Doe, all issued by the CA with openssl and valid from 2020 to 2120
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/tn3270/testdata").join(name)
+    }
+
+    fn client_certificate() -> PeerCertificate {
+        let chain = load_certificates(&testdata("client.pem")).unwrap();
+        PeerCertificate::parse(chain[0].as_ref()).unwrap()
+    }
+
+    fn email_identity(chain: &[PeerCertificate]) -> Option<String> {
+        chain[0].subject_alt_names.iter().find_map(|name| match name {
+            SubjectAltName::Email(address) => Some(address.clone()),
+            _ => None,
+        })
+    }
+
+    /// Connect to `acceptor` as Jane Doe, returning both ends of the connection. The
+    /// client's end has to stay open, since the server carries on writing (TLS 1.3 session
+    /// tickets) after the client has finished its side of the handshake.
+    fn connect_with_client_certificate(acceptor: &TlsAcceptor) -> (TlsStream<MemoryStream>, MemoryStream) {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certificates(&testdata("ca.pem")).unwrap() {
+            roots.add(cert).unwrap();
+        }
+        let config = ClientConfig::builder_with_provider(default_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(load_certificates(&testdata("client.pem")).unwrap(), load_private_key(&testdata("client.key")).unwrap())
+            .unwrap();
+        let (server, mut client) = duplex();
+        let handshake = std::thread::spawn(move || {
+            let mut conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+            while conn.is_handshaking() {
+                conn.complete_io(&mut client).unwrap();
+            }
+            client
+        });
+        let stream = acceptor.accept(server).unwrap();
+        (stream, handshake.join().unwrap())
+    }
+
+    /// Handshakes that get as far as needing a certificate fail, which is all these tests
+    /// need
+    #[derive(Debug)]
+    struct NoCertificate;
+
+    impl ResolvesServerCert for NoCertificate {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            None
+        }
+    }
+
+    fn acceptor() -> TlsAcceptor {
+        let config = ServerConfig::builder_with_provider(default_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(NoCertificate));
+        TlsAcceptor::new(Arc::new(config))
+    }
+
+    /// Read from `stream` until `expected` has gone by
+    fn expect(stream: &mut impl Read, expected: &[u8]) {
+        let mut seen = Vec::new();
+        let mut buf = [0; 256];
+        while !seen.windows(expected.len()).any(|window| window == expected) {
+            let len = stream.read(&mut buf).unwrap();
+            assert_ne!(len, 0, "connection closed before {:x?}", expected);
+            seen.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn failed_start_tls_is_a_tls_error() {
+        let (server, mut client) = duplex();
+        let session = std::thread::spawn(move || {
+            Session::new(StartTlsStream::new(server, acceptor(), StartTlsPolicy::Require)).map(|_| ())
+        });
+
+        expect(&mut client, &[tn_cmd::IAC, tn_cmd::DO, OPT_START_TLS]);
+        client.write_all(&[tn_cmd::IAC, tn_cmd::WILL, OPT_START_TLS]).unwrap();
+        expect(&mut client, &[tn_cmd::IAC, tn_cmd::SB, OPT_START_TLS, START_TLS_FOLLOWS, tn_cmd::IAC, tn_cmd::SE]);
+        client.write_all(&[tn_cmd::IAC, tn_cmd::SB, OPT_START_TLS, START_TLS_FOLLOWS, tn_cmd::IAC, tn_cmd::SE]).unwrap();
+        client.write_all(b"this is not a TLS handshake").unwrap();
+
+        let result = session.join().unwrap();
+        assert!(matches!(result, Err(NegotiationError::Tls { .. })), "{:?}", result.err());
+    }
+
+    #[test]
+    fn handshake_has_one_deadline() {
+        let (server, mut client) = duplex();
+        // A handshake record that never finishes arriving, a byte at a time, each well
+        // within the timeout
+        let trickle = std::thread::spawn(move || {
+            if client.write_all(&[0x16, 0x03, 0x01, 0x02, 0x00]).is_err() {
+                return;
+            }
+            for _ in 0..100 {
+                std::thread::sleep(Duration::from_millis(20));
+                if client.write_all(&[0x01]).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let started = Instant::now();
+        let result = acceptor().with_handshake_timeout(Duration::from_millis(200)).accept(server);
+        assert!(matches!(result, Err(TlsError::Handshake { .. })));
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+        drop(result);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn parses_peer_certificate() {
+        let cert = client_certificate();
+        assert_eq!(cert.subject, "O=Example Corp, OU=Operations, CN=Jane Doe");
+        assert_eq!(cert.issuer, "O=Example Corp, CN=Example Test CA");
+        assert_eq!(cert.serial, "1a:2b:3c:4d");
+        assert_eq!(cert.subject_alt_names, [
+            SubjectAltName::Email("jane@example.com".into()),
+            SubjectAltName::Dns("jane.example.com".into()),
+            SubjectAltName::IpAddress("192.0.2.7".parse().unwrap()),
+            SubjectAltName::IpAddress("2001:db8::7".parse().unwrap()),
+            SubjectAltName::Uri("https://example.com/users/jane".into()),
+        ]);
+        let names: Vec<_> = cert.subject_alt_names.iter().map(ToString::to_string).collect();
+        assert_eq!(names, [
+            "email:jane@example.com",
+            "DNS:jane.example.com",
+            "IP:192.0.2.7",
+            "IP:2001:db8::7",
+            "URI:https://example.com/users/jane",
+        ]);
+    }
+
+    #[test]
+    fn certificate_without_alt_names() {
+        let chain = load_certificates(&testdata("ca.pem")).unwrap();
+        let cert = PeerCertificate::parse(chain[0].as_ref()).unwrap();
+        assert_eq!(cert.subject, cert.issuer);
+        assert_eq!(cert.serial, "01");
+        assert!(cert.subject_alt_names.is_empty());
+    }
+
+    #[test]
+    fn rejects_garbage_certificate() {
+        assert!(matches!(PeerCertificate::parse(b"not a certificate"), Err(TlsError::InvalidCertificate { .. })));
+    }
+
+    #[test]
+    fn identity_mapper_sees_client_chain() {
+        assert_eq!(email_identity(&[client_certificate()]).as_deref(), Some("jane@example.com"));
+
+        let acceptor = TlsAcceptor::from_pem_files_with_client_auth(testdata("server.pem"), testdata("server.key"), testdata("ca.pem"), true)
+            .unwrap()
+            .with_identity_mapper(email_identity);
+        let (stream, _client) = connect_with_client_certificate(&acceptor);
+        assert_eq!(stream.peer_certificates().len(), 1);
+        assert_eq!(stream.peer_certificates()[0].subject, "O=Example Corp, OU=Operations, CN=Jane Doe");
+        assert_eq!(stream.peer_identity(), Some("jane@example.com"));
+    }
+
+    #[test]
+    fn no_identity_without_mapper() {
+        let acceptor = TlsAcceptor::from_pem_files_with_client_auth(testdata("server.pem"), testdata("server.key"), testdata("ca.pem"), false).unwrap();
+        let (stream, _client) = connect_with_client_certificate(&acceptor);
+        assert_eq!(stream.peer_certificates().len(), 1);
+        assert_eq!(stream.peer_identity(), None);
+    }
+}
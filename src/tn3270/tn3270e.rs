@@ -0,0 +1,437 @@
+//! Framing and subnegotiation for the TN3270E telnet option (RFC 2355)
+
+use bitflags::bitflags;
+use std::convert::TryFrom;
+use snafu::ensure;
+use crate::tn3270::stream::{StreamFormatError, UnexpectedEOR, InvalidData};
+
+pub const OPT_TN3270E: u8 = 40;
+
+/// Length of the header that precedes every TN3270E record
+pub const HEADER_LEN: usize = 5;
+
+pub mod cmd {
+    pub const ASSOCIATE: u8 = 0;
+    pub const CONNECT: u8 = 1;
+    pub const DEVICE_TYPE: u8 = 2;
+    pub const FUNCTIONS: u8 = 3;
+    pub const IS: u8 = 4;
+    pub const REASON: u8 = 5;
+    pub const REJECT: u8 = 6;
+    pub const REQUEST: u8 = 7;
+    pub const SEND: u8 = 8;
+}
+
+/// Values of the response flag in a TN3270E header
+pub mod response_flag {
+    // For 3270-DATA and SCS-DATA records
+    pub const NO_RESPONSE: u8 = 0x00;
+    pub const ERROR_RESPONSE: u8 = 0x01;
+    pub const ALWAYS_RESPONSE: u8 = 0x02;
+
+    // For RESPONSE records
+    pub const POSITIVE_RESPONSE: u8 = 0x00;
+    pub const NEGATIVE_RESPONSE: u8 = 0x01;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum DataType {
+    Data3270,
+    ScsData,
+    Response,
+    BindImage,
+    Unbind,
+    NvtData,
+    Request,
+    SscpLuData,
+    PrintEoj,
+}
+
+impl From<DataType> for u8 {
+    fn from(v: DataType) -> u8 {
+        match v {
+            DataType::Data3270 => 0x00,
+            DataType::ScsData => 0x01,
+            DataType::Response => 0x02,
+            DataType::BindImage => 0x03,
+            DataType::Unbind => 0x04,
+            DataType::NvtData => 0x05,
+            DataType::Request => 0x06,
+            DataType::SscpLuData => 0x07,
+            DataType::PrintEoj => 0x08,
+        }
+    }
+}
+
+impl TryFrom<u8> for DataType {
+    type Error = StreamFormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => DataType::Data3270,
+            0x01 => DataType::ScsData,
+            0x02 => DataType::Response,
+            0x03 => DataType::BindImage,
+            0x04 => DataType::Unbind,
+            0x05 => DataType::NvtData,
+            0x06 => DataType::Request,
+            0x07 => DataType::SscpLuData,
+            0x08 => DataType::PrintEoj,
+            _ => return Err(StreamFormatError::InvalidData),
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ReasonCode {
+    ConnPartner,
+    DeviceInUse,
+    InvAssociate,
+    InvName,
+    InvDeviceType,
+    TypeNameError,
+    UnknownError,
+    UnsupportedReq,
+}
+
+impl From<ReasonCode> for u8 {
+    fn from(v: ReasonCode) -> u8 {
+        match v {
+            ReasonCode::ConnPartner => 0x00,
+            ReasonCode::DeviceInUse => 0x01,
+            ReasonCode::InvAssociate => 0x02,
+            ReasonCode::InvName => 0x03,
+            ReasonCode::InvDeviceType => 0x04,
+            ReasonCode::TypeNameError => 0x05,
+            ReasonCode::UnknownError => 0x06,
+            ReasonCode::UnsupportedReq => 0x07,
+        }
+    }
+}
+
+impl TryFrom<u8> for ReasonCode {
+    type Error = StreamFormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x00 => ReasonCode::ConnPartner,
+            0x01 => ReasonCode::DeviceInUse,
+            0x02 => ReasonCode::InvAssociate,
+            0x03 => ReasonCode::InvName,
+            0x04 => ReasonCode::InvDeviceType,
+            0x05 => ReasonCode::TypeNameError,
+            0x06 => ReasonCode::UnknownError,
+            0x07 => ReasonCode::UnsupportedReq,
+            _ => return Err(StreamFormatError::InvalidData),
+        })
+    }
+}
+
+bitflags! {
+    /// The set of TN3270E functions; each bit is `1 << function code`
+    pub struct Functions: u8 {
+        const BIND_IMAGE = 1 << 0;
+        const DATA_STREAM_CTL = 1 << 1;
+        const RESPONSES = 1 << 2;
+        const SCS_CTL_CODES = 1 << 3;
+        const SYSREQ = 1 << 4;
+    }
+}
+
+impl Functions {
+    /// Decode a function list, silently dropping functions we've never heard of.
+    pub fn from_codes(codes: &[u8]) -> Self {
+        codes.iter()
+            .filter(|&&code| code < 8)
+            .fold(Functions::empty(), |acc, &code| acc | Functions::from_bits_truncate(1 << code))
+    }
+
+    pub fn codes(self) -> Vec<u8> {
+        (0..8).filter(|code| self.bits() & (1 << code) != 0).collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub data_type: DataType,
+    pub request_flag: u8,
+    pub response_flag: u8,
+    pub seq_number: u16,
+}
+
+impl Header {
+    pub fn new(data_type: DataType) -> Self {
+        Header {
+            data_type,
+            request_flag: 0,
+            response_flag: 0,
+            seq_number: 0,
+        }
+    }
+
+    /// Split a TN3270E header off the front of a record
+    pub fn parse(record: &[u8]) -> Result<(Self, &[u8]), StreamFormatError> {
+        ensure!(record.len() >= HEADER_LEN, UnexpectedEOR);
+        let (header, body) = record.split_at(HEADER_LEN);
+        Ok((Header {
+            data_type: DataType::try_from(header[0])?,
+            request_flag: header[1],
+            response_flag: header[2],
+            seq_number: (header[3] as u16) << 8 | header[4] as u16,
+        }, body))
+    }
+
+    pub fn serialize(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&[
+            self.data_type.into(),
+            self.request_flag,
+            self.response_flag,
+            (self.seq_number >> 8) as u8,
+            (self.seq_number & 0xff) as u8,
+        ]);
+    }
+}
+
+/// The outcome a terminal reports in a RESPONSE record
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum DeviceResponse {
+    DeviceEnd,
+    CommandReject,
+    InterventionRequired,
+    OperationCheck,
+    ComponentDisconnected,
+}
+
+impl DeviceResponse {
+    pub fn is_positive(self) -> bool {
+        self == DeviceResponse::DeviceEnd
+    }
+
+    /// Decode the body of a RESPONSE record
+    pub fn parse(header: &Header, body: &[u8]) -> Result<Self, StreamFormatError> {
+        ensure!(!body.is_empty(), UnexpectedEOR);
+        Ok(match (header.response_flag, body[0]) {
+            (response_flag::POSITIVE_RESPONSE, 0x00) => DeviceResponse::DeviceEnd,
+            (response_flag::NEGATIVE_RESPONSE, 0x00) => DeviceResponse::CommandReject,
+            (response_flag::NEGATIVE_RESPONSE, 0x01) => DeviceResponse::InterventionRequired,
+            (response_flag::NEGATIVE_RESPONSE, 0x02) => DeviceResponse::OperationCheck,
+            (response_flag::NEGATIVE_RESPONSE, 0x03) => DeviceResponse::ComponentDisconnected,
+            _ => return Err(StreamFormatError::InvalidData),
+        })
+    }
+
+    /// The header and body byte of a RESPONSE record answering record `seq_number`
+    pub fn to_record(self, seq_number: u16) -> (Header, u8) {
+        let (flag, code) = match self {
+            DeviceResponse::DeviceEnd => (response_flag::POSITIVE_RESPONSE, 0x00),
+            DeviceResponse::CommandReject => (response_flag::NEGATIVE_RESPONSE, 0x00),
+            DeviceResponse::InterventionRequired => (response_flag::NEGATIVE_RESPONSE, 0x01),
+            DeviceResponse::OperationCheck => (response_flag::NEGATIVE_RESPONSE, 0x02),
+            DeviceResponse::ComponentDisconnected => (response_flag::NEGATIVE_RESPONSE, 0x03),
+        };
+        let header = Header {
+            data_type: DataType::Response,
+            request_flag: 0,
+            response_flag: flag,
+            seq_number,
+        };
+        (header, code)
+    }
+}
+
+/// The body of an `IAC SB TN3270E ... IAC SE` sequence
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Subnegotiation {
+    SendDeviceType,
+    DeviceTypeRequest {
+        device_type: String,
+        connect: Option<String>,
+        associate: Option<String>,
+    },
+    DeviceTypeIs {
+        device_type: String,
+        device_name: String,
+    },
+    DeviceTypeReject(ReasonCode),
+    FunctionsRequest(Functions),
+    FunctionsIs(Functions),
+}
+
+fn ascii(data: &[u8]) -> String {
+    data.iter().map(|&b| b as char).collect()
+}
+
+impl Subnegotiation {
+    pub fn parse(buffer: &[u8]) -> Result<Self, StreamFormatError> {
+        ensure!(buffer.len() >= 2, UnexpectedEOR);
+        Ok(match (buffer[0], buffer[1]) {
+            (cmd::SEND, cmd::DEVICE_TYPE) => Subnegotiation::SendDeviceType,
+            (cmd::DEVICE_TYPE, cmd::REQUEST) => {
+                let body = &buffer[2..];
+                let split = body.iter()
+                    .position(|&b| b == cmd::CONNECT || b == cmd::ASSOCIATE)
+                    .unwrap_or(body.len());
+                let (device_type, rest) = body.split_at(split);
+                let (mut connect, mut associate) = (None, None);
+                match rest.split_first() {
+                    Some((&cmd::CONNECT, name)) => connect = Some(ascii(name)),
+                    Some((&cmd::ASSOCIATE, name)) => associate = Some(ascii(name)),
+                    _ => {}
+                }
+                Subnegotiation::DeviceTypeRequest {
+                    device_type: ascii(device_type),
+                    connect,
+                    associate,
+                }
+            }
+            (cmd::DEVICE_TYPE, cmd::IS) => {
+                let body = &buffer[2..];
+                let split = body.iter()
+                    .position(|&b| b == cmd::CONNECT)
+                    .ok_or(StreamFormatError::InvalidData)?;
+                Subnegotiation::DeviceTypeIs {
+                    device_type: ascii(&body[..split]),
+                    device_name: ascii(&body[split + 1..]),
+                }
+            }
+            (cmd::DEVICE_TYPE, cmd::REJECT) => {
+                ensure!(buffer.len() >= 4, UnexpectedEOR);
+                ensure!(buffer[2] == cmd::REASON, InvalidData);
+                Subnegotiation::DeviceTypeReject(ReasonCode::try_from(buffer[3])?)
+            }
+            (cmd::FUNCTIONS, cmd::REQUEST) => Subnegotiation::FunctionsRequest(Functions::from_codes(&buffer[2..])),
+            (cmd::FUNCTIONS, cmd::IS) => Subnegotiation::FunctionsIs(Functions::from_codes(&buffer[2..])),
+            _ => return Err(StreamFormatError::InvalidData),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut output = vec![];
+        match self {
+            Subnegotiation::SendDeviceType => output.extend_from_slice(&[cmd::SEND, cmd::DEVICE_TYPE]),
+            Subnegotiation::DeviceTypeRequest { device_type, connect, associate } => {
+                output.extend_from_slice(&[cmd::DEVICE_TYPE, cmd::REQUEST]);
+                output.extend_from_slice(device_type.as_bytes());
+                if let Some(name) = connect {
+                    output.push(cmd::CONNECT);
+                    output.extend_from_slice(name.as_bytes());
+                } else if let Some(name) = associate {
+                    output.push(cmd::ASSOCIATE);
+                    output.extend_from_slice(name.as_bytes());
+                }
+            }
+            Subnegotiation::DeviceTypeIs { device_type, device_name } => {
+                output.extend_from_slice(&[cmd::DEVICE_TYPE, cmd::IS]);
+                output.extend_from_slice(device_type.as_bytes());
+                output.push(cmd::CONNECT);
+                output.extend_from_slice(device_name.as_bytes());
+            }
+            Subnegotiation::DeviceTypeReject(reason) => {
+                output.extend_from_slice(&[cmd::DEVICE_TYPE, cmd::REJECT, cmd::REASON, (*reason).into()]);
+            }
+            Subnegotiation::FunctionsRequest(functions) => {
+                output.extend_from_slice(&[cmd::FUNCTIONS, cmd::REQUEST]);
+                output.extend(functions.codes());
+            }
+            Subnegotiation::FunctionsIs(functions) => {
+                output.extend_from_slice(&[cmd::FUNCTIONS, cmd::IS]);
+                output.extend(functions.codes());
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(sub: Subnegotiation) {
+        assert_eq!(Subnegotiation::parse(sub.serialize().as_slice()).unwrap(), sub);
+    }
+
+    #[test]
+    fn subnegotiation_round_trips() {
+        round_trip(Subnegotiation::SendDeviceType);
+        round_trip(Subnegotiation::DeviceTypeRequest {
+            device_type: "IBM-3278-2-E".into(),
+            connect: None,
+            associate: None,
+        });
+        round_trip(Subnegotiation::DeviceTypeRequest {
+            device_type: "IBM-3278-2-E".into(),
+            connect: Some("TCP00042".into()),
+            associate: None,
+        });
+        round_trip(Subnegotiation::DeviceTypeRequest {
+            device_type: "IBM-3287-1".into(),
+            connect: None,
+            associate: Some("TCP00042".into()),
+        });
+        round_trip(Subnegotiation::DeviceTypeIs {
+            device_type: "IBM-3278-2-E".into(),
+            device_name: "TCP00042".into(),
+        });
+        round_trip(Subnegotiation::DeviceTypeReject(ReasonCode::DeviceInUse));
+        round_trip(Subnegotiation::FunctionsRequest(Functions::RESPONSES | Functions::SYSREQ));
+        round_trip(Subnegotiation::FunctionsIs(Functions::empty()));
+    }
+
+    #[test]
+    fn parses_wire_subnegotiations() {
+        assert_eq!(Subnegotiation::parse(&[cmd::SEND, cmd::DEVICE_TYPE]).unwrap(), Subnegotiation::SendDeviceType);
+        assert_eq!(
+            Subnegotiation::parse(b"\x02\x07IBM-3279-2-E\x01LU1").unwrap(),
+            Subnegotiation::DeviceTypeRequest {
+                device_type: "IBM-3279-2-E".into(),
+                connect: Some("LU1".into()),
+                associate: None,
+            },
+        );
+        // Unknown function codes are dropped
+        assert_eq!(
+            Subnegotiation::parse(&[cmd::FUNCTIONS, cmd::REQUEST, 2, 4, 7]).unwrap(),
+            Subnegotiation::FunctionsRequest(Functions::RESPONSES | Functions::SYSREQ),
+        );
+    }
+
+    #[test]
+    fn rejects_bad_subnegotiations() {
+        assert!(matches!(Subnegotiation::parse(&[cmd::SEND]), Err(StreamFormatError::UnexpectedEOR)));
+        assert!(matches!(Subnegotiation::parse(&[cmd::IS, cmd::SEND]), Err(StreamFormatError::InvalidData)));
+        // DEVICE-TYPE IS needs a CONNECT
+        assert!(matches!(Subnegotiation::parse(b"\x02\x04IBM-3278-2-E"), Err(StreamFormatError::InvalidData)));
+        assert!(matches!(Subnegotiation::parse(&[cmd::DEVICE_TYPE, cmd::REJECT, cmd::REASON]), Err(StreamFormatError::UnexpectedEOR)));
+        assert!(matches!(Subnegotiation::parse(&[cmd::DEVICE_TYPE, cmd::REJECT, cmd::REASON, 0x42]), Err(StreamFormatError::InvalidData)));
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = Header {
+            data_type: DataType::Data3270,
+            request_flag: 0,
+            response_flag: response_flag::ALWAYS_RESPONSE,
+            seq_number: 0x1234,
+        };
+        let mut record = Vec::new();
+        header.serialize(&mut record);
+        assert_eq!(record, [0x00, 0x00, 0x02, 0x12, 0x34]);
+        record.extend_from_slice(&[0xF5, 0xC3]);
+        assert_eq!(Header::parse(record.as_slice()).unwrap(), (header, &[0xF5, 0xC3][..]));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(Header::parse(&[0x00, 0x00, 0x00, 0x00]), Err(StreamFormatError::UnexpectedEOR)));
+        assert!(matches!(Header::parse(&[0x42, 0x00, 0x00, 0x00, 0x00]), Err(StreamFormatError::InvalidData)));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for &response in &[DeviceResponse::DeviceEnd, DeviceResponse::CommandReject, DeviceResponse::ComponentDisconnected] {
+            let (header, code) = response.to_record(7);
+            assert_eq!(header.seq_number, 7);
+            assert_eq!(DeviceResponse::parse(&header, &[code]).unwrap(), response);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Recording everything a session sends and receives, in the format of x3270's `-trace`
+//! files, so that a capture can be compared line for line with one taken by the emulator.
+//!
+//! The file is written from the terminal's side, as x3270 writes it: lines starting with
+//! `<` are what the session sent, and lines starting with `>` are what it received. Each
+//! chunk of data is preceded by a `< +0.25s` line giving the time since the previous one,
+//! and dumped in hex, 32 bytes to a line, each line starting with the direction and the
+//! offset into the chunk:
+//!
+//! ```text
+//! < +0.000512s
+//! < 0x0   fffd28
+//! RCVD DO TN3270E
+//! ```
+//!
+//! On top of what x3270 writes, the file starts with a `Trace started` line giving the
+//! time the trace started in seconds since the Unix epoch (then a line naming this crate
+//! and its version, as x3270 does for itself), and each chunk is followed by
+//! a line for every telnet command in it (`SENT`/`RCVD`, again from the terminal's side,
+//! then the command). Tools reading x3270 traces skip lines they don't recognize.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use libtelnet_rs::telnet::op_command as tn_cmd;
+
+use crate::tn3270::telnet::{CommandName, OptionName};
+
+/// How many bytes are dumped on each line, as in x3270
+const LINE_LEN: usize = 32;
+
+/// Which way data went, from the session's side
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// From the client to the session
+    Inbound,
+    /// From the session to the client
+    Outbound,
+}
+
+impl Direction {
+    /// x3270 marks data from the terminal's side
+    fn marker(self) -> char {
+        match self {
+            Direction::Inbound => '>',
+            Direction::Outbound => '<',
+        }
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            Direction::Inbound => "SENT",
+            Direction::Outbound => "RCVD",
+        }
+    }
+}
+
+/// Writes a session's traffic to a trace file. Hand one to
+/// [`Session::with_trace`](crate::tn3270::Session::with_trace) to capture the session from
+/// the start, or to [`Session::set_trace`](crate::tn3270::Session::set_trace) to start
+/// partway through.
+pub struct TraceRecorder {
+    out: Box<dyn Write + Send>,
+    last: Instant,
+}
+
+impl TraceRecorder {
+    pub fn new(out: impl Write + Send + 'static) -> std::io::Result<Self> {
+        let mut recorder = TraceRecorder { out: Box::new(out), last: Instant::now() };
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        writeln!(recorder.out, "Trace started {}.{:06}", started.as_secs(), started.subsec_micros())?;
+        writeln!(recorder.out, " Version: tn3270s {}", env!("CARGO_PKG_VERSION"))?;
+        recorder.out.flush()?;
+        Ok(recorder)
+    }
+
+    /// Record to a new file at `path`, replacing anything already there
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Record one chunk of data. The trace is flushed afterwards, so that nothing is lost
+    /// if the process dies.
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        let now = Instant::now();
+        let marker = direction.marker();
+        writeln!(self.out, "{} +{}s", marker, seconds(now - self.last))?;
+        self.last = now;
+
+        for (line, chunk) in data.chunks(LINE_LEN).enumerate() {
+            write!(self.out, "{} 0x{:<3x} ", marker, line * LINE_LEN)?;
+            for byte in chunk {
+                write!(self.out, "{:02x}", byte)?;
+            }
+            writeln!(self.out)?;
+        }
+        self.annotate(direction, data)?;
+        self.out.flush()
+    }
+
+    /// Describe the telnet commands in a chunk. Commands split across chunks are missed,
+    /// which only costs an annotation.
+    fn annotate(&mut self, direction: Direction, data: &[u8]) -> std::io::Result<()> {
+        let verb = direction.verb();
+        let mut rest = data;
+        while let Some(pos) = rest.iter().position(|&byte| byte == tn_cmd::IAC) {
+            rest = &rest[pos + 1..];
+            match *rest {
+                [command @ tn_cmd::WILL..=tn_cmd::DONT, option, ..] => {
+                    writeln!(self.out, "{} {} {}", verb, CommandName(command), OptionName(option))?;
+                    rest = &rest[2..];
+                }
+                [tn_cmd::SB, option, ..] => {
+                    let body = &rest[2..];
+                    let end = body.windows(2)
+                        .position(|window| window == [tn_cmd::IAC, tn_cmd::SE])
+                        .unwrap_or(body.len());
+                    write!(self.out, "{} SB {} ", verb, OptionName(option))?;
+                    for byte in &body[..end] {
+                        write!(self.out, "{:02x}", byte)?;
+                    }
+                    writeln!(self.out, " SE")?;
+                    rest = &body[(end + 2).min(body.len())..];
+                }
+                // A doubled IAC is data
+                [tn_cmd::IAC, ..] => rest = &rest[1..],
+                [command, ..] => {
+                    writeln!(self.out, "{} {}", verb, CommandName(command))?;
+                    rest = &rest[1..];
+                }
+                [] => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Like C's `%g`, which x3270 uses for the time between chunks, but to the microsecond
+fn seconds(elapsed: std::time::Duration) -> String {
+    let formatted = format!("{:.6}", elapsed.as_secs_f64());
+    formatted.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::tn3270::{Event, Session, Signal};
+    use crate::tn3270::lu::AdHocLuPool;
+    use crate::tn3270::memory::duplex;
+    use crate::tn3270::negotiation::NegotiationPolicy;
+    use crate::tn3270::replay::{Recording, Step};
+    use crate::tn3270::testing::{FakeClient, TIMEOUT};
+    use crate::tn3270::tn3270e::Functions;
+
+    /// A trace the test can read back while the session still holds the recorder
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn recorded_session_replays() {
+        let buffer = SharedBuffer::default();
+        let (server, client) = duplex();
+        let mut client = FakeClient::start_tn3270e(client, "IBM-3278-2-E", Functions::empty());
+        let trace = TraceRecorder::new(buffer.clone()).unwrap();
+        let mut session = Session::with_trace(server, Arc::new(AdHocLuPool), NegotiationPolicy::default(), trace)
+            .expect("negotiation failed");
+
+        session.send_record(vec![0xF5, 0xC3]).unwrap();
+        assert_eq!(client.next_record(TIMEOUT).expect("no record"), [0xF5, 0xC3]);
+        client.send_record(vec![0x7D, 0x40, 0x40]).unwrap();
+        let record = session.receive_record(Some(TIMEOUT)).unwrap().expect("no record");
+        assert_eq!(record.data, [0x7D, 0x40, 0x40]);
+        client.send_attention().unwrap();
+        assert!(matches!(session.receive_event(Some(TIMEOUT)), Ok(Some(Event::Signal(Signal::Attention)))));
+        session.send_record(vec![0xF1, 0xC2]).unwrap();
+        assert_eq!(client.next_record(TIMEOUT).expect("no record"), [0xF1, 0xC2]);
+
+        let text = buffer.0.lock().unwrap().clone();
+        assert!(text.starts_with(b"Trace started "));
+        let recording = Recording::parse(text.as_slice()).unwrap();
+        assert_eq!(recording.term_type, "IBM-3278-2-E");
+        assert_eq!(recording.steps, vec![
+            Step::Outbound(vec![0xF5, 0xC3]),
+            Step::Inbound(vec![0x7D, 0x40, 0x40]),
+            Step::Attention,
+            Step::Outbound(vec![0xF1, 0xC2]),
+        ]);
+    }
+}
@@ -0,0 +1,129 @@
+//! The byte streams a [`Session`](crate::tn3270::Session) can run over
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use crate::tn3270::tls::PeerCertificate;
+
+/// What to do about the telnet START_TLS option
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StartTlsPolicy {
+    /// Don't offer it; the session stays in plain text
+    Refuse,
+    /// Offer it, but carry on in plain text if the client declines
+    Allow,
+    /// Offer it, and drop clients that decline
+    Require,
+}
+
+/// A bidirectional byte stream with the blocking controls that `Session` relies on.
+///
+/// `set_read_timeout(None)` must make reads block indefinitely, and while non-blocking
+/// mode is enabled, reads with no data available must fail with `WouldBlock`.
+pub trait Transport: Read + Write {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()>;
+
+    /// Where the client is connecting from, for logging
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The address the client connected to
+    fn local_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Whether the client should be offered START_TLS. Only transports that implement
+    /// [`Transport::start_tls`] should return anything but `Refuse`.
+    fn start_tls_policy(&self) -> StartTlsPolicy {
+        StartTlsPolicy::Refuse
+    }
+
+    /// Run the server side of a TLS handshake once the client has agreed to START_TLS.
+    /// `received` holds the start of the handshake, if it was read along with the
+    /// preceding telnet data.
+    fn start_tls(&mut self, received: &[u8]) -> std::io::Result<()> {
+        let _ = received;
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "this transport can't start TLS"))
+    }
+
+    /// The client's verified certificate chain, starting with its own certificate
+    #[cfg(feature = "tls")]
+    fn peer_certificates(&self) -> &[PeerCertificate] {
+        &[]
+    }
+
+    /// The application user the client's certificate was mapped to
+    #[cfg(feature = "tls")]
+    fn peer_identity(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        TcpStream::local_addr(self).ok()
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        std::os::unix::net::UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> std::io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn start_tls_policy(&self) -> StartTlsPolicy {
+        (**self).start_tls_policy()
+    }
+
+    fn start_tls(&mut self, received: &[u8]) -> std::io::Result<()> {
+        (**self).start_tls(received)
+    }
+
+    #[cfg(feature = "tls")]
+    fn peer_certificates(&self) -> &[PeerCertificate] {
+        (**self).peer_certificates()
+    }
+
+    #[cfg(feature = "tls")]
+    fn peer_identity(&self) -> Option<&str> {
+        (**self).peer_identity()
+    }
+}